@@ -0,0 +1,117 @@
+use crate::cmdline::Opts;
+use crate::config::Config;
+use crate::graphql::backend_query;
+use crate::tls;
+
+use anyhow::{anyhow, Result};
+use backend_query::assign_vulcast_to_relay::AssignVulcastToRelayAssignVulcastToRelay::{
+    AuthenticationError, RelayAssignment, VulcastAssignedToRelayError,
+};
+use backend_query::log_in_as_vulcast::LogInAsVulcastLogInAsVulcast::{
+    AuthenticationError as LoginAuthenticationError, VulcastAuthentication,
+};
+use graphql_client::{GraphQLQuery, Response};
+use ini::Ini;
+
+/// Creates the HTTP client used for backend requests, presenting the device
+/// certificate if one is configured.
+pub fn client(conf: &Config) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder();
+    if let Some(identity) = tls::client_identity(conf)? {
+        log::info!("Using device certificate for backend requests");
+        builder = builder.identity(reqwest::Identity::from_pem(identity.pem())?);
+    }
+    Ok(builder.build()?)
+}
+
+pub async fn login(conf: &Config, client: &reqwest::Client) -> Result<String> {
+    log::info!("Logging in");
+
+    let uri = conf.network.backend_addr.clone() + "/graphql";
+
+    let login_query =
+        backend_query::LogInAsVulcast::build_query(backend_query::log_in_as_vulcast::Variables {
+            vulcast_id: conf.auth.guid.clone(),
+            secret: conf.auth.secret.expose().to_owned(),
+        });
+    let auth = client.post(&uri).json(&login_query).send().await?;
+    let response_body: Response<backend_query::log_in_as_vulcast::ResponseData> =
+        auth.json().await?;
+    if let Some(errors) = response_body.errors {
+        errors.iter().for_each(|error| log::error!("{:?}", error))
+    }
+    let response_data: backend_query::log_in_as_vulcast::ResponseData = response_body
+        .data
+        .ok_or_else(|| anyhow!("Request returned no data"))?;
+    match response_data.log_in_as_vulcast {
+        VulcastAuthentication(auth) => Ok(auth.vulcast_access_token),
+        LoginAuthenticationError(error) => Err(anyhow!("Authentication error: {}", error.message)),
+    }
+}
+
+pub fn write_relay_assignment(hostname: &str, token: &str, opts: &Opts) -> Result<()> {
+    log::info!("Writing relay assignment...");
+    let mut assigned = Ini::new();
+    assigned
+        .with_section(Some("relay"))
+        .set("hostname", hostname)
+        .set("token", token);
+    assigned.write_to_file(opts.config_dir.clone() + "/assigned_relay")?;
+    Ok(())
+}
+
+pub fn read_relay_assignment(opts: &Opts) -> Result<(String, String)> {
+    log::info!("Reading relay assignment...");
+    let relay_file = Ini::load_from_file(opts.config_dir.clone() + "/assigned_relay")?;
+    let host = relay_file
+        .get_from(Some("relay"), "hostname")
+        .ok_or_else(|| anyhow!("Could not load relay hostname from file"))?;
+    let token = relay_file
+        .get_from(Some("relay"), "token")
+        .ok_or_else(|| anyhow!("Could not load relay token from file"))?;
+    Ok((host.to_owned(), token.to_owned()))
+}
+
+pub async fn assign_relay(
+    conf: &Config,
+    opts: &Opts,
+    client: &reqwest::Client,
+    auth_token: &str,
+) -> Result<(String, String)> {
+    log::info!("Requesting relay assignment");
+
+    let uri = conf.network.backend_addr.clone() + "/graphql";
+
+    let register_query = backend_query::AssignVulcastToRelay::build_query(
+        backend_query::assign_vulcast_to_relay::Variables {},
+    );
+    let res = client
+        .post(&uri)
+        .bearer_auth("vulcast_".to_owned() + auth_token)
+        .json(&register_query)
+        .send()
+        .await?;
+
+    let response_body: Response<backend_query::assign_vulcast_to_relay::ResponseData> =
+        res.json().await?;
+    if let Some(errors) = response_body.errors {
+        errors.iter().for_each(|error| log::error!("{:?}", error))
+    }
+    let response_data: backend_query::assign_vulcast_to_relay::ResponseData = response_body
+        .data
+        .ok_or_else(|| anyhow!("Request returned no data"))?;
+    match response_data.assign_vulcast_to_relay {
+        RelayAssignment(assignment) => {
+            let _ = write_relay_assignment(
+                &assignment.relay.host_name,
+                &assignment.relay_access_token,
+                opts,
+            );
+            Ok((assignment.relay.host_name, assignment.relay_access_token))
+        }
+        AuthenticationError(error) => Err(anyhow!("Authentication error: {}", error.message)),
+        VulcastAssignedToRelayError(error) => {
+            Err(anyhow!("Vulcast already assigned error: {}", error.message))
+        }
+    }
+}
@@ -0,0 +1,38 @@
+use rand::Rng;
+use std::time::Duration;
+
+/// Exponential backoff with jitter, used to pace reconnection attempts.
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            min,
+            max,
+            attempt: 0,
+        }
+    }
+
+    /// Returns how long to wait before the next attempt, doubling the
+    /// ceiling on every call up to `max`. The returned delay is picked
+    /// uniformly from the upper half of the current ceiling so that many
+    /// devices dropped at once do not reconnect in lockstep.
+    pub fn next_delay(&mut self) -> Duration {
+        let ceiling = self
+            .min
+            .checked_mul(1 << self.attempt.min(16))
+            .map_or(self.max, |delay| delay.min(self.max));
+        self.attempt = self.attempt.saturating_add(1);
+
+        let half = ceiling / 2;
+        half + half.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
@@ -1,4 +1,5 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use std::net::SocketAddr;
 
 pub mod built_info {
     include!(concat!(env!("OUT_DIR"), "/built.rs"));
@@ -14,4 +15,85 @@ pub struct Opts {
     /// Directory containing vulcast.conf and other config
     #[clap(long, default_value = concat!(env!("HOME"), "/.vulcast"))]
     pub config_dir: String,
+
+    /// Accept any certificate presented by the relay (INSECURE, development only)
+    #[clap(long)]
+    pub insecure_relay_tls: bool,
+
+    /// How media reaches the relay (native or plain), overriding [media]
+    #[clap(long)]
+    pub media_mode: Option<String>,
+
+    /// Video capture device index (-1 for the first available) or path, overriding [media]
+    #[clap(long)]
+    pub video_device: Option<String>,
+
+    /// Capture width in pixels, overriding [media]
+    #[clap(long)]
+    pub video_width: Option<u32>,
+
+    /// Capture height in pixels, overriding [media]
+    #[clap(long)]
+    pub video_height: Option<u32>,
+
+    /// Capture frame rate, overriding [media]
+    #[clap(long)]
+    pub video_framerate: Option<u32>,
+
+    /// ALSA card to capture audio from, overriding [media]
+    #[clap(long)]
+    pub audio_card: Option<String>,
+
+    /// Controller backend (procon, xinput, ds4, keyboard_mouse or mock), overriding [controller]
+    #[clap(long)]
+    pub controller_backend: Option<String>,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Clone)]
+pub enum Command {
+    /// Inspect the configuration
+    #[clap(subcommand)]
+    Config(ConfigCommand),
+    /// Send a request to the running firmware over its control socket
+    Control {
+        /// One of status, end_session, reconnect, release_all_buttons, assign_slot, mock_events
+        method: String,
+        /// Parameters as a JSON object, e.g. {"data_producer": "...", "slot": 1} for assign_slot
+        params: Option<String>,
+    },
+    /// Inspect the external media pipelines
+    #[clap(subcommand)]
+    Pipeline(PipelineCommand),
+    /// Replay a recording of data channel messages into the controllers
+    Replay {
+        /// Recording made with [controller] record_dir set
+        file: String,
+        /// Playback speed multiplier
+        #[clap(long, default_value = "1.0")]
+        speed: f64,
+    },
+}
+
+#[derive(Subcommand, Clone)]
+pub enum PipelineCommand {
+    /// Print the command line of a media pipeline with its placeholders filled in, without running it
+    DryRun {
+        /// Preset or pipelines.conf section, [media] pipeline by default
+        name: Option<String>,
+        /// Address to send audio to, standing in for the relay's transport
+        #[clap(long, default_value = "127.0.0.1:40000")]
+        audio_addr: SocketAddr,
+        /// Address to send video to, standing in for the relay's transport
+        #[clap(long, default_value = "127.0.0.1:40002")]
+        video_addr: SocketAddr,
+    },
+}
+
+#[derive(Subcommand, Clone)]
+pub enum ConfigCommand {
+    /// Validate vulcast.conf and print the effective configuration with secrets redacted
+    Check,
 }
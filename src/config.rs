@@ -0,0 +1,806 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::cmdline::Opts;
+use crate::controllers::{
+    self, ControllerBackend, DeadzoneShape, SlotConflict, MAX_SLOTS, MAX_TURBO_RATE_HZ,
+};
+use crate::media::{self, MediaMode, VideoDevice};
+use crate::tls;
+
+use anyhow::{anyhow, Context, Result};
+use http::Uri;
+use ini::Ini;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+
+/// Prefix of environment variables that override config file values, e.g.
+/// `VULCAST_NETWORK__BACKEND_ADDR` overrides `backend_addr` in `[network]`.
+const ENV_PREFIX: &str = "VULCAST_";
+const ENV_SEPARATOR: &str = "__";
+
+/// Parses a value from its string form, as every value read from the config
+/// file or the environment is a string.
+fn from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let s = String::deserialize(deserializer)?;
+    s.trim().parse().map_err(serde::de::Error::custom)
+}
+
+/// A config value that must never be printed or logged.
+#[derive(Clone, Default, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str("<redacted>")
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// Base URL of the backend; `/graphql` is appended for requests.
+    pub backend_addr: String,
+    /// Port of the relay signalling WebSocket.
+    #[serde(deserialize_with = "from_str")]
+    pub signal_port: u16,
+    /// PEM bundle of additional CAs trusted for the relay connection.
+    pub relay_ca_file: Option<String>,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            backend_addr: "https://app.vulcangames.fun".to_owned(),
+            signal_port: 8443,
+            relay_ca_file: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub guid: String,
+    pub secret: Secret,
+    /// Device certificate chain presented to the backend and relay.
+    pub client_cert: Option<String>,
+    /// Private key for `client_cert`.
+    pub client_key: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MediaConfig {
+    pub mode: MediaMode,
+    /// Preset or `pipelines.conf` section to send media with in plain mode.
+    pub pipeline: String,
+    /// Video capture device index (-1 for the first available) or path.
+    #[serde(deserialize_with = "from_str")]
+    pub video_device: VideoDevice,
+    #[serde(deserialize_with = "from_str")]
+    pub video_width: u32,
+    #[serde(deserialize_with = "from_str")]
+    pub video_height: u32,
+    #[serde(deserialize_with = "from_str")]
+    pub video_framerate: u32,
+    /// ALSA card id or index to capture audio from, instead of the default
+    /// card; `hw:` device names for the card are accepted too.
+    pub audio_card: Option<String>,
+    /// USB `vendor:product` id of the capture device, which selects its
+    /// video device and ALSA card instead of `video_device` and
+    /// `audio_card`.
+    pub capture_usb_id: Option<String>,
+    /// Pattern matched against capture device names, `*` and `?` being
+    /// wildcards; selects the device like `capture_usb_id`.
+    pub capture_name: Option<String>,
+    /// Video bitrate of external pipelines, or the starting one when
+    /// adapting.
+    #[serde(deserialize_with = "from_str")]
+    pub video_bitrate_kbps: u32,
+    /// Whether to adapt the encoding of external pipelines to the loss the
+    /// relay reports.
+    #[serde(deserialize_with = "from_str")]
+    pub adaptive_bitrate: bool,
+    #[serde(deserialize_with = "from_str")]
+    pub min_video_bitrate_kbps: u32,
+    #[serde(deserialize_with = "from_str")]
+    pub max_video_bitrate_kbps: u32,
+    /// Lowest height adaptation may scale video down to.
+    #[serde(deserialize_with = "from_str")]
+    pub min_video_height: u32,
+    /// Lowest frame rate adaptation may drop video to.
+    #[serde(deserialize_with = "from_str")]
+    pub min_video_framerate: u32,
+    /// Local port video from external pipelines is sent to the relay from;
+    /// audio is sent from the next one.
+    #[serde(deserialize_with = "from_str")]
+    pub rtp_bind_port: u16,
+    /// Restart an external pipeline after this long without progress.
+    #[serde(deserialize_with = "from_str")]
+    pub pipeline_stall_timeout_ms: u64,
+}
+
+impl Default for MediaConfig {
+    fn default() -> Self {
+        Self {
+            mode: MediaMode::Native,
+            pipeline: "vp8".to_owned(),
+            video_device: VideoDevice::Index(-1),
+            video_width: 1280,
+            video_height: 720,
+            video_framerate: 30,
+            audio_card: None,
+            capture_usb_id: None,
+            capture_name: None,
+            video_bitrate_kbps: 500,
+            adaptive_bitrate: false,
+            min_video_bitrate_kbps: 150,
+            max_video_bitrate_kbps: 2500,
+            min_video_height: 360,
+            min_video_framerate: 15,
+            rtp_bind_port: 50000,
+            pipeline_stall_timeout_ms: 10000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControllerConfig {
+    /// Whether to emulate controllers at all.
+    #[serde(deserialize_with = "from_str")]
+    pub enabled: bool,
+    /// Kind of device to present the players' input as.
+    pub backend: ControllerBackend,
+    /// Name of the USB gadget to create, named after the backend if unset.
+    pub gadget_name: Option<String>,
+    /// Number of players, up to `MAX_SLOTS`. The keyboard and mouse backend
+    /// always has a single player.
+    #[serde(deserialize_with = "from_str")]
+    pub slots: usize,
+    /// Comma-separated HID device nodes, one per slot, or the keyboard and
+    /// then the mouse. Defaults to `/dev/hidg0` onwards.
+    pub device_paths: Option<String>,
+    /// Comma-separated `RRGGBB` Pro Controller body colors, one per slot.
+    pub body_colors: Option<String>,
+    /// Milliseconds without input after which a slot is reset to neutral,
+    /// 0 to never time out.
+    #[serde(deserialize_with = "from_str")]
+    pub input_timeout_ms: u64,
+    /// Whether the input data channel delivers messages in order. When it
+    /// does not, messages older than the last applied one are discarded.
+    #[serde(deserialize_with = "from_str")]
+    pub ordered_input: bool,
+    /// Whether legacy (version 0) clients advance their sequence numbers.
+    /// Turn off for clients that send the same number every time, whose
+    /// input would otherwise be dropped as duplicates.
+    #[serde(deserialize_with = "from_str")]
+    pub legacy_sequence_numbers: bool,
+    /// Presses per second of buttons with turbo enabled.
+    #[serde(deserialize_with = "from_str")]
+    pub turbo_rate_hz: u32,
+    /// Directory to record every data channel message to, one file per
+    /// run, for `vulcast-firmware replay`.
+    pub record_dir: Option<String>,
+    /// What to do when a data producer sends input for a slot another one
+    /// has claimed.
+    pub slot_conflict: SlotConflict,
+    /// Slots a data producer may claim by sending input for them.
+    #[serde(deserialize_with = "from_str")]
+    pub slots_per_producer: usize,
+}
+
+impl Default for ControllerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            backend: ControllerBackend::Procon,
+            gadget_name: None,
+            slots: 4,
+            device_paths: None,
+            body_colors: None,
+            input_timeout_ms: 5000,
+            ordered_input: false,
+            legacy_sequence_numbers: true,
+            turbo_rate_hz: 10,
+            record_dir: None,
+            slot_conflict: SlotConflict::Remap,
+            slots_per_producer: 1,
+        }
+    }
+}
+
+/// Analog stick processing, applied to every slot. Deflections are fractions
+/// of pushing the stick all the way.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SticksConfig {
+    /// Whether the deadzones are measured radially or per axis.
+    pub deadzone_shape: DeadzoneShape,
+    /// Deflection below which a stick reads as centered.
+    #[serde(deserialize_with = "from_str")]
+    pub inner_deadzone: f32,
+    /// Deflection short of the edge beyond which a stick reads as fully
+    /// pushed.
+    #[serde(deserialize_with = "from_str")]
+    pub outer_deadzone: f32,
+    /// Smallest deflection sent once past the inner deadzone, to cancel out
+    /// a game's own deadzone.
+    #[serde(deserialize_with = "from_str")]
+    pub anti_deadzone: f32,
+    /// Response curve exponent; above 1 gives finer control near the center.
+    #[serde(deserialize_with = "from_str")]
+    pub curve: f32,
+}
+
+impl Default for SticksConfig {
+    fn default() -> Self {
+        Self {
+            deadzone_shape: DeadzoneShape::Radial,
+            inner_deadzone: 0.0,
+            outer_deadzone: 0.0,
+            anti_deadzone: 0.0,
+            curve: 1.0,
+        }
+    }
+}
+
+/// Limits on the input each data producer may send.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Messages per second a data producer may send on average.
+    #[serde(deserialize_with = "from_str")]
+    pub input_rate_hz: u32,
+    /// Messages a data producer may send at once above the average rate.
+    #[serde(deserialize_with = "from_str")]
+    pub input_burst: u32,
+    /// Dropped or invalid messages within 10 seconds after which a data
+    /// producer is disconnected, 0 to never disconnect.
+    #[serde(deserialize_with = "from_str")]
+    pub abuse_threshold: u32,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            input_rate_hz: 250,
+            input_burst: 60,
+            abuse_threshold: 500,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControlConfig {
+    /// Path of the local control socket, `control.sock` in the config
+    /// directory if unset.
+    pub socket_path: Option<String>,
+}
+
+impl ControllerConfig {
+    pub fn gadget_name(&self) -> &str {
+        match &self.gadget_name {
+            Some(name) => name,
+            None => self.backend.default_gadget_name(),
+        }
+    }
+
+    /// Number of players the backend takes input from.
+    pub fn slot_count(&self) -> usize {
+        match self.backend {
+            ControllerBackend::KeyboardMouse => 1,
+            _ => self.slots,
+        }
+    }
+
+    pub fn device_paths(&self) -> Vec<String> {
+        match &self.device_paths {
+            Some(paths) => paths
+                .split(',')
+                .map(|path| path.trim().to_owned())
+                .collect(),
+            None => (0..self.backend.device_count(self.slot_count()))
+                .map(|i| format!("/dev/hidg{}", i))
+                .collect(),
+        }
+    }
+}
+
+/// Contents of `vulcast.conf`, with environment overrides applied.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Config {
+    pub network: NetworkConfig,
+    pub auth: AuthConfig,
+    pub media: MediaConfig,
+    pub controller: ControllerConfig,
+    pub sticks: SticksConfig,
+    pub limits: LimitsConfig,
+    pub control: ControlConfig,
+    /// SHA-256 SPKI pins per relay hostname, see `tls::parse_pins`.
+    pub relay_pins: BTreeMap<String, String>,
+}
+
+type RawSections = BTreeMap<String, BTreeMap<String, String>>;
+
+/// Deserializes one section, reporting every bad key rather than only the
+/// first. Keys that fail to parse fall back to their defaults.
+fn section<T: DeserializeOwned + Default>(
+    name: &str,
+    values: Option<&BTreeMap<String, String>>,
+    problems: &mut Vec<String>,
+) -> T {
+    let mut valid = Map::new();
+    for (key, value) in values.into_iter().flatten() {
+        let mut single = Map::new();
+        single.insert(key.clone(), Value::String(value.clone()));
+        match serde_json::from_value::<T>(Value::Object(single.clone())) {
+            Ok(_) => valid.extend(single),
+            Err(e) => problems.push(format!("[{}] {}: {}", name, key, e)),
+        }
+    }
+    serde_json::from_value(Value::Object(valid)).unwrap_or_default()
+}
+
+/// Applies `VULCAST_<SECTION>__<KEY>` variables over the file's values.
+fn override_from_env(raw: &mut RawSections, vars: impl Iterator<Item = (String, String)>) {
+    for (name, value) in vars {
+        let override_key = name
+            .strip_prefix(ENV_PREFIX)
+            .and_then(|rest| rest.split_once(ENV_SEPARATOR));
+        if let Some((section, key)) = override_key {
+            log::debug!("Config overridden by {}", name);
+            raw.entry(section.to_lowercase())
+                .or_default()
+                .insert(key.to_lowercase(), value);
+        }
+    }
+}
+
+impl Config {
+    /// Loads `vulcast.conf` from the configured directory, applies
+    /// environment and then command line overrides, and validates the result,
+    /// reporting all problems at once.
+    pub fn load(opts: &Opts) -> Result<Self> {
+        let path = Path::new(&opts.config_dir).join("vulcast.conf");
+        log::info!("Loading config from {}", path.display());
+        let ini = Ini::load_from_file(&path)
+            .with_context(|| format!("Couldn't open config file: {}", path.display()))?;
+
+        let mut raw = RawSections::new();
+        for (section, properties) in ini.iter() {
+            let entries = raw
+                .entry(section.unwrap_or_default().to_owned())
+                .or_default();
+            for (key, value) in properties.iter() {
+                entries.insert(key.to_owned(), value.to_owned());
+            }
+        }
+        override_from_env(&mut raw, std::env::vars());
+        let cli_overrides = [
+            ("media", "mode", opts.media_mode.clone()),
+            ("media", "video_device", opts.video_device.clone()),
+            (
+                "media",
+                "video_width",
+                opts.video_width.map(|v| v.to_string()),
+            ),
+            (
+                "media",
+                "video_height",
+                opts.video_height.map(|v| v.to_string()),
+            ),
+            (
+                "media",
+                "video_framerate",
+                opts.video_framerate.map(|v| v.to_string()),
+            ),
+            ("media", "audio_card", opts.audio_card.clone()),
+            ("controller", "backend", opts.controller_backend.clone()),
+        ];
+        for (section, key, value) in cli_overrides.iter() {
+            if let Some(value) = value {
+                raw.entry((*section).to_owned())
+                    .or_default()
+                    .insert((*key).to_owned(), value.clone());
+            }
+        }
+
+        Self::from_sections(raw).map_err(|problems| {
+            anyhow!(
+                "Invalid configuration in {}:\n  {}",
+                path.display(),
+                problems.join("\n  ")
+            )
+        })
+    }
+
+    pub fn control_socket(&self, opts: &Opts) -> PathBuf {
+        match &self.control.socket_path {
+            Some(path) => PathBuf::from(path),
+            None => Path::new(&opts.config_dir).join("control.sock"),
+        }
+    }
+
+    fn from_sections(mut raw: RawSections) -> Result<Self, Vec<String>> {
+        let mut problems = Vec::new();
+        let config = Self {
+            network: section("network", raw.remove("network").as_ref(), &mut problems),
+            auth: section("auth", raw.remove("auth").as_ref(), &mut problems),
+            media: section("media", raw.remove("media").as_ref(), &mut problems),
+            controller: section(
+                "controller",
+                raw.remove("controller").as_ref(),
+                &mut problems,
+            ),
+            sticks: section("sticks", raw.remove("sticks").as_ref(), &mut problems),
+            limits: section("limits", raw.remove("limits").as_ref(), &mut problems),
+            control: section("control", raw.remove("control").as_ref(), &mut problems),
+            relay_pins: raw.remove("relay_pins").unwrap_or_default(),
+        };
+        // properties outside any section are ignored
+        for name in raw.keys().filter(|name| !name.is_empty()) {
+            problems.push(format!("[{}]: unknown section", name));
+        }
+
+        config.validate(&mut problems);
+        if problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(problems)
+        }
+    }
+
+    fn validate(&self, problems: &mut Vec<String>) {
+        match self.network.backend_addr.parse::<Uri>() {
+            Ok(uri) if matches!(uri.scheme_str(), Some("http") | Some("https")) => (),
+            _ => problems.push(format!(
+                "[network] backend_addr: not an http(s) URL: {:?}",
+                self.network.backend_addr
+            )),
+        }
+        if self.network.signal_port == 0 {
+            problems.push("[network] signal_port: must not be 0".to_owned());
+        }
+        if let Some(ca_file) = &self.network.relay_ca_file {
+            if !Path::new(ca_file).is_file() {
+                problems.push(format!(
+                    "[network] relay_ca_file: no such file: {}",
+                    ca_file
+                ));
+            }
+        }
+
+        if self.auth.guid.is_empty() {
+            problems.push("[auth] guid: missing".to_owned());
+        }
+        if self.auth.secret.expose().is_empty() {
+            problems.push("[auth] secret: missing".to_owned());
+        }
+        match (&self.auth.client_cert, &self.auth.client_key) {
+            (Some(cert), Some(key)) => {
+                for (name, file) in &[("client_cert", cert), ("client_key", key)] {
+                    if !Path::new(file).is_file() {
+                        problems.push(format!("[auth] {}: no such file: {}", name, file));
+                    }
+                }
+                if Path::new(key).is_file() {
+                    if let Err(e) = tls::read_client_key(key) {
+                        problems.push(format!("[auth] client_key: {}", e));
+                    }
+                }
+            }
+            (None, None) => (),
+            _ => problems.push("[auth] client_cert and client_key must be set together".to_owned()),
+        }
+
+        if self.media.video_width == 0 || self.media.video_height == 0 {
+            problems.push("[media] video_width and video_height must be non-zero".to_owned());
+        }
+        if self.media.video_framerate == 0 {
+            problems.push("[media] video_framerate: must be non-zero".to_owned());
+        }
+        if let Some(Err(e)) = self.media.audio_card.as_deref().map(media::alsa_card) {
+            problems.push(format!("[media] audio_card: {}", e));
+        }
+        if let Some(usb_id) = &self.media.capture_usb_id {
+            let is_id = |part: &str| part.len() == 4 && part.chars().all(|c| c.is_ascii_hexdigit());
+            if !matches!(usb_id.split_once(':'), Some((vendor, product)) if is_id(vendor) && is_id(product))
+            {
+                problems.push(format!(
+                    "[media] capture_usb_id: expected vendor:product in hex, like 534d:2109, got {:?}",
+                    usb_id
+                ));
+            }
+        }
+        if self.media.video_bitrate_kbps == 0 {
+            problems.push("[media] video_bitrate_kbps: must be non-zero".to_owned());
+        }
+        let bitrates = [
+            self.media.min_video_bitrate_kbps,
+            self.media.video_bitrate_kbps,
+            self.media.max_video_bitrate_kbps,
+        ];
+        if self.media.adaptive_bitrate
+            && !(bitrates[0] <= bitrates[1] && bitrates[1] <= bitrates[2])
+        {
+            problems.push(
+                "[media] video_bitrate_kbps must be between min_video_bitrate_kbps and \
+                 max_video_bitrate_kbps"
+                    .to_owned(),
+            );
+        }
+        if self.media.rtp_bind_port == 0 || self.media.rtp_bind_port == u16::MAX {
+            problems.push("[media] rtp_bind_port: must be between 1 and 65534".to_owned());
+        }
+        if self.media.pipeline_stall_timeout_ms == 0 {
+            problems.push("[media] pipeline_stall_timeout_ms: must be non-zero".to_owned());
+        }
+
+        if self.controller.gadget_name().is_empty() {
+            problems.push("[controller] gadget_name: must not be empty".to_owned());
+        }
+        let slots = self.controller.slot_count();
+        if !(1..=MAX_SLOTS).contains(&slots) {
+            problems.push(format!(
+                "[controller] slots: must be between 1 and {}",
+                MAX_SLOTS
+            ));
+        }
+        if !(1..=slots).contains(&self.controller.slots_per_producer) {
+            problems.push(
+                "[controller] slots_per_producer: must be between 1 and the number of slots"
+                    .to_owned(),
+            );
+        }
+        let devices = self.controller.backend.device_count(slots);
+        if self.controller.device_paths.is_some() && self.controller.device_paths().len() != devices
+        {
+            problems.push(format!(
+                "[controller] device_paths: expected {} paths",
+                devices
+            ));
+        }
+        if !(1..=MAX_TURBO_RATE_HZ).contains(&self.controller.turbo_rate_hz) {
+            problems.push(format!(
+                "[controller] turbo_rate_hz: must be between 1 and {}",
+                MAX_TURBO_RATE_HZ
+            ));
+        }
+        match self
+            .controller
+            .body_colors
+            .as_deref()
+            .map(controllers::parse_colors)
+        {
+            Some(Ok(colors)) if colors.len() != slots => problems.push(format!(
+                "[controller] body_colors: expected {} colors, got {}",
+                slots,
+                colors.len()
+            )),
+            Some(Err(e)) => problems.push(format!("[controller] body_colors: {}", e)),
+            _ => (),
+        }
+
+        let sticks = &self.sticks;
+        let fractions = [
+            ("inner_deadzone", sticks.inner_deadzone),
+            ("outer_deadzone", sticks.outer_deadzone),
+            ("anti_deadzone", sticks.anti_deadzone),
+        ];
+        for (key, value) in fractions.iter() {
+            if !(0.0..1.0).contains(value) {
+                problems.push(format!("[sticks] {}: must be at least 0 and below 1", key));
+            }
+        }
+        if sticks.inner_deadzone + sticks.outer_deadzone >= 1.0 {
+            problems.push("[sticks] inner_deadzone and outer_deadzone leave no range".to_owned());
+        }
+        if sticks.curve.is_nan() || sticks.curve <= 0.0 {
+            problems.push("[sticks] curve: must be positive".to_owned());
+        }
+
+        if self.limits.input_rate_hz == 0 {
+            problems.push("[limits] input_rate_hz: must be non-zero".to_owned());
+        }
+        if self.limits.input_burst == 0 {
+            problems.push("[limits] input_burst: must be non-zero".to_owned());
+        }
+
+        for (host, pins) in &self.relay_pins {
+            if let Err(e) = tls::parse_pins(host, pins) {
+                problems.push(format!("[relay_pins] {}: {}", host, e));
+            }
+        }
+    }
+}
+
+/// Prints the configuration in `vulcast.conf` syntax. Secrets are redacted
+/// and unset optional values are omitted.
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sections = match serde_json::to_value(self) {
+            Ok(Value::Object(sections)) => sections,
+            _ => return Err(fmt::Error),
+        };
+        let mut first = true;
+        for (name, values) in sections {
+            let values = match values {
+                Value::Object(values) if !values.is_empty() => values,
+                _ => continue,
+            };
+            if !first {
+                writeln!(f)?;
+            }
+            first = false;
+            writeln!(f, "[{}]", name)?;
+            for (key, value) in values {
+                match value {
+                    Value::Null => (),
+                    Value::String(s) => writeln!(f, "{} = {}", key, s)?,
+                    other => writeln!(f, "{} = {}", key, other)?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw(entries: &[(&str, &str, &str)]) -> RawSections {
+        let mut raw = RawSections::new();
+        for (section, key, value) in entries {
+            raw.entry((*section).to_owned())
+                .or_default()
+                .insert((*key).to_owned(), (*value).to_owned());
+        }
+        raw
+    }
+
+    fn minimal() -> RawSections {
+        raw(&[("auth", "guid", "cast-1"), ("auth", "secret", "hunter2")])
+    }
+
+    #[test]
+    fn defaults() {
+        let config = Config::from_sections(minimal()).unwrap();
+        let defaults = ControllerConfig::default();
+        assert_eq!(config.network.backend_addr, "https://app.vulcangames.fun");
+        assert_eq!(config.network.signal_port, 8443);
+        assert_eq!(
+            config.controller.slots_per_producer,
+            defaults.slots_per_producer
+        );
+        assert_eq!(
+            config.limits.input_rate_hz,
+            LimitsConfig::default().input_rate_hz
+        );
+        assert_eq!(config.auth.secret.expose(), "hunter2");
+        assert!(config.relay_pins.is_empty());
+    }
+
+    #[test]
+    fn environment_overrides_file() {
+        let mut sections = minimal();
+        sections
+            .entry("network".to_owned())
+            .or_default()
+            .insert("signal_port".to_owned(), "443".to_owned());
+        let vars = vec![
+            ("VULCAST_NETWORK__SIGNAL_PORT", "9443"),
+            ("VULCAST_AUTH__SECRET", "from-env"),
+            ("VULCAST_LIMITS", "ignored, no key"),
+            ("HOME", "/root"),
+        ];
+        override_from_env(
+            &mut sections,
+            vars.into_iter().map(|(k, v)| (k.to_owned(), v.to_owned())),
+        );
+        let config = Config::from_sections(sections).unwrap();
+        assert_eq!(config.network.signal_port, 9443);
+        assert_eq!(config.auth.secret.expose(), "from-env");
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let mut sections = raw(&[
+            ("network", "signal_port", "port"),
+            ("network", "backend_addr", "ftp://example.com"),
+            ("limits", "input_rate_hz", "0"),
+            ("limits", "surprise", "1"),
+            ("media", "audio_card", "hw:CARD=MS2109,DEV=1"),
+            ("bogus", "key", "value"),
+        ]);
+        sections.insert(String::new(), BTreeMap::new());
+        let problems = Config::from_sections(sections).unwrap_err();
+        let expected = [
+            "[network] signal_port: ",
+            "[limits] surprise: ",
+            "[bogus]: unknown section",
+            "[network] backend_addr: not an http(s) URL",
+            "[auth] guid: missing",
+            "[auth] secret: missing",
+            "[media] audio_card: only device 0 of a card is captured",
+            "[limits] input_rate_hz: must be non-zero",
+        ];
+        for prefix in expected.iter() {
+            assert!(
+                problems.iter().any(|problem| problem.starts_with(prefix)),
+                "{:?} not in {:#?}",
+                prefix,
+                problems
+            );
+        }
+        assert_eq!(problems.len(), expected.len(), "{:#?}", problems);
+    }
+
+    #[test]
+    fn bad_key_falls_back_to_default() {
+        let mut problems = Vec::new();
+        let values = raw(&[
+            ("network", "signal_port", "-1"),
+            ("network", "backend_addr", "http://x"),
+        ]);
+        let network: NetworkConfig = section("network", values.get("network"), &mut problems);
+        assert_eq!(network.signal_port, 8443);
+        assert_eq!(network.backend_addr, "http://x");
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("[network] signal_port: "));
+    }
+
+    #[test]
+    fn display_redacts_secrets() {
+        let config = Config::from_sections(minimal()).unwrap();
+        let printed = config.to_string();
+        assert!(!printed.contains("hunter2"), "{}", printed);
+        assert!(printed.contains("[auth]\nguid = cast-1\nsecret = <redacted>\n"));
+        assert!(!format!("{:?}", config).contains("hunter2"));
+        // unset optional values are left out
+        assert!(!printed.contains("client_cert"));
+        assert!(printed.contains("[network]\nbackend_addr = https://app.vulcangames.fun\n"));
+    }
+
+    #[test]
+    fn display_reloads() {
+        let config = Config::from_sections(minimal()).unwrap();
+        let printed = config.to_string();
+        let ini = Ini::load_from_str(&printed).unwrap();
+        let mut sections = RawSections::new();
+        for (name, properties) in ini.iter() {
+            let entries = sections
+                .entry(name.unwrap_or_default().to_owned())
+                .or_default();
+            for (key, value) in properties.iter() {
+                entries.insert(key.to_owned(), value.to_owned());
+            }
+        }
+        let reloaded = Config::from_sections(sections).unwrap();
+        assert_eq!(reloaded.to_string(), printed);
+    }
+}
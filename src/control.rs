@@ -0,0 +1,591 @@
+//! Local control socket. Requests and responses are JSON-RPC 2.0 objects, one
+//! per line. The generated GraphQL control schema (`graphql::control_query`)
+//! is not used here: it describes the relay's control API, which the firmware
+//! only ever talks to as a client, and a local socket for scripts and
+//! `vulcast-firmware control` has no use for a GraphQL server.
+
+use std::fs::{self, Permissions};
+use std::io;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::controllers::{ControllerSlots, Motion, ReleaseReason, SequenceStats};
+use crate::data_streamer::PipelineStatus;
+use crate::limits::ProducerStats;
+use crate::media::CaptureDevice;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{broadcast, watch};
+use vulcast_rtc::types::DataProducerId;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionState {
+    Connecting,
+    Connected,
+    WaitingToReconnect,
+    Stopped,
+}
+
+/// Commands for the session supervisor, received over the control socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlCommand {
+    /// End the session and exit.
+    EndSession,
+    /// Drop the current relay session, if any, and connect again right away.
+    Reconnect,
+}
+
+#[derive(Serialize)]
+struct SlotStatus {
+    slot: usize,
+    /// Data producer the slot is bound to.
+    owner: Option<DataProducerId>,
+    pressed_buttons: Vec<usize>,
+    lh: u16,
+    lv: u16,
+    rh: u16,
+    rv: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    triggers: Option<[u16; 2]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    motion: Option<Motion>,
+    /// Set while the slot is held neutral by the firmware.
+    released: Option<ReleaseReason>,
+    /// Remapping profile selected by the player, `None` for the default.
+    profile: Option<String>,
+    calibrating_sticks: bool,
+    turbo_buttons: Vec<usize>,
+    playing_macro: Option<String>,
+    sequence: SequenceStats,
+}
+
+#[derive(Serialize)]
+struct Status {
+    session: SessionState,
+    relay_host: Option<String>,
+    data_producers: Vec<DataProducerId>,
+    input_stats: Vec<ProducerStatus>,
+    /// `None` while the capture device is unplugged.
+    capture_device: Option<CaptureDevice>,
+    /// External pipeline sending media, if the session uses one.
+    media_pipeline: Option<PipelineStatus>,
+    /// `None` when controller emulation is disabled.
+    controller_slots: Option<Vec<SlotStatus>>,
+}
+
+#[derive(Clone, Serialize)]
+struct ProducerStatus {
+    data_producer: DataProducerId,
+    #[serde(flatten)]
+    stats: ProducerStats,
+}
+
+const JSONRPC_VERSION: &str = "2.0";
+
+/// JSON-RPC 2.0 error codes.
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// Any failure of a known method, e.g. no session to send a command to.
+const SERVER_ERROR: i64 = -32000;
+
+/// A JSON-RPC request on the control socket. Requests without an `id` are
+/// notifications and get no response.
+#[derive(Deserialize)]
+struct Request {
+    jsonrpc: String,
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Deserialize)]
+struct AssignSlotParams {
+    /// As shown in `status`.
+    data_producer: Value,
+    slot: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for RpcError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::new(SERVER_ERROR, e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for RpcError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::new(SERVER_ERROR, e.to_string())
+    }
+}
+
+#[derive(Serialize)]
+struct Response {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+impl Response {
+    fn new(id: Value, outcome: Result<Value, RpcError>) -> Self {
+        let (result, error) = match outcome {
+            Ok(result) => (Some(result), None),
+            Err(error) => (None, Some(error)),
+        };
+        Self {
+            jsonrpc: JSONRPC_VERSION,
+            id,
+            result,
+            error,
+        }
+    }
+}
+
+struct State {
+    session: SessionState,
+    relay_host: Option<String>,
+    data_producers: Vec<ProducerStatus>,
+    pipeline: Option<watch::Receiver<PipelineStatus>>,
+    capture: Option<watch::Receiver<Option<CaptureDevice>>>,
+}
+
+/// Session state shared between the supervisor, the relay session and the
+/// local control socket.
+pub struct Control {
+    state: Mutex<State>,
+    commands: broadcast::Sender<ControlCommand>,
+    controllers: Option<Arc<Mutex<ControllerSlots>>>,
+}
+
+impl Control {
+    pub fn new(controllers: Option<Arc<Mutex<ControllerSlots>>>) -> Arc<Self> {
+        let (commands, _) = broadcast::channel(16);
+        Arc::new(Self {
+            state: Mutex::new(State {
+                session: SessionState::Connecting,
+                relay_host: None,
+                data_producers: Vec::new(),
+                pipeline: None,
+                capture: None,
+            }),
+            commands,
+            controllers,
+        })
+    }
+
+    pub fn commands(&self) -> broadcast::Receiver<ControlCommand> {
+        self.commands.subscribe()
+    }
+
+    pub fn set_session_state(&self, session: SessionState) {
+        let mut state = self.state.lock().unwrap();
+        state.session = session;
+        if session != SessionState::Connected {
+            state.relay_host = None;
+            state.data_producers.clear();
+            state.pipeline = None;
+        }
+    }
+
+    pub fn set_connected(&self, relay_host: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.session = SessionState::Connected;
+        state.relay_host = Some(relay_host.to_owned());
+    }
+
+    pub fn add_data_producer(&self, id: &DataProducerId) {
+        self.state
+            .lock()
+            .unwrap()
+            .data_producers
+            .push(ProducerStatus {
+                data_producer: id.clone(),
+                stats: ProducerStats::default(),
+            });
+    }
+
+    pub fn remove_data_producer(&self, id: &DataProducerId) {
+        self.state
+            .lock()
+            .unwrap()
+            .data_producers
+            .retain(|producer| producer.data_producer != *id);
+    }
+
+    pub fn set_pipeline(&self, pipeline: watch::Receiver<PipelineStatus>) {
+        self.state.lock().unwrap().pipeline = Some(pipeline);
+    }
+
+    pub fn set_capture(&self, capture: watch::Receiver<Option<CaptureDevice>>) {
+        self.state.lock().unwrap().capture = Some(capture);
+    }
+
+    pub fn set_producer_stats(&self, id: &DataProducerId, stats: ProducerStats) {
+        let mut state = self.state.lock().unwrap();
+        let producer = state
+            .data_producers
+            .iter_mut()
+            .find(|producer| producer.data_producer == *id);
+        if let Some(producer) = producer {
+            producer.stats = stats;
+        }
+    }
+
+    fn status(&self) -> Status {
+        let controller_slots = self.controllers.as_ref().map(|controllers| {
+            let controllers = controllers.lock().unwrap();
+            let owners = controllers.owners();
+            let released = controllers.release_reasons();
+            let profiles = controllers.profiles();
+            let calibrating = controllers.calibrating();
+            let turbo_buttons = controllers.turbo_buttons();
+            let playing_macros = controllers.playing_macros();
+            let sequence = controllers.sequence_stats();
+            controllers
+                .states()
+                .iter()
+                .enumerate()
+                .map(|(slot, state)| SlotStatus {
+                    slot,
+                    owner: owners[slot].clone(),
+                    pressed_buttons: state.pressed_buttons(),
+                    lh: state.lh(),
+                    lv: state.lv(),
+                    rh: state.rh(),
+                    rv: state.rv(),
+                    triggers: state.triggers(),
+                    motion: state.motion(),
+                    released: released[slot],
+                    profile: profiles[slot].clone(),
+                    calibrating_sticks: calibrating[slot],
+                    turbo_buttons: turbo_buttons[slot].clone(),
+                    playing_macro: playing_macros[slot].clone(),
+                    sequence: sequence[slot],
+                })
+                .collect()
+        });
+        let state = self.state.lock().unwrap();
+        Status {
+            session: state.session,
+            relay_host: state.relay_host.clone(),
+            data_producers: state
+                .data_producers
+                .iter()
+                .map(|producer| producer.data_producer.clone())
+                .collect(),
+            input_stats: state.data_producers.clone(),
+            capture_device: state
+                .capture
+                .as_ref()
+                .and_then(|capture| capture.borrow().clone()),
+            media_pipeline: state
+                .pipeline
+                .as_ref()
+                .map(|pipeline| pipeline.borrow().clone()),
+            controller_slots,
+        }
+    }
+
+    fn send(&self, command: ControlCommand) -> Result<Value> {
+        self.commands
+            .send(command)
+            .map_err(|_| anyhow!("No session is listening for commands"))?;
+        Ok(Value::Null)
+    }
+
+    fn controllers(&self) -> Result<&Arc<Mutex<ControllerSlots>>> {
+        self.controllers
+            .as_ref()
+            .ok_or_else(|| anyhow!("Controller emulation is disabled"))
+    }
+
+    /// Binds a slot to a connected data producer, for hosts that decide who
+    /// plays where.
+    fn assign_slot(&self, params: Value) -> Result<Value, RpcError> {
+        let params: AssignSlotParams = serde_json::from_value(params).map_err(|e| {
+            RpcError::new(INVALID_PARAMS, format!("Invalid assign_slot params: {}", e))
+        })?;
+        let producer = {
+            let state = self.state.lock().unwrap();
+            state
+                .data_producers
+                .iter()
+                .map(|producer| &producer.data_producer)
+                .find(|id| serde_json::to_value(id).ok().as_ref() == Some(&params.data_producer))
+                .cloned()
+                .ok_or_else(|| {
+                    RpcError::new(
+                        INVALID_PARAMS,
+                        format!("No data producer {}", params.data_producer),
+                    )
+                })?
+        };
+        self.controllers()?
+            .lock()
+            .unwrap()
+            .assign(&producer, params.slot)?;
+        Ok(Value::Null)
+    }
+
+    fn handle(&self, method: &str, params: Value) -> Result<Value, RpcError> {
+        match method {
+            "status" => Ok(serde_json::to_value(self.status())?),
+            "end_session" => Ok(self.send(ControlCommand::EndSession)?),
+            "reconnect" => Ok(self.send(ControlCommand::Reconnect)?),
+            "release_all_buttons" => {
+                self.controllers()?.lock().unwrap().release_all()?;
+                Ok(Value::Null)
+            }
+            "assign_slot" => self.assign_slot(params),
+            "mock_events" => match self.controllers()?.lock().unwrap().recorded_events() {
+                Some(events) => Ok(serde_json::to_value(events)?),
+                None => Err(anyhow!("Controller backend does not record input").into()),
+            },
+            _ => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("Unknown method {:?}", method),
+            )),
+        }
+    }
+
+    /// Answers one line of input, `None` for notifications.
+    fn respond(&self, line: &str) -> Option<Response> {
+        let request = match serde_json::from_str::<Value>(line) {
+            Ok(request) => request,
+            Err(e) => {
+                let error = RpcError::new(PARSE_ERROR, format!("Invalid JSON: {}", e));
+                return Some(Response::new(Value::Null, Err(error)));
+            }
+        };
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let request = match serde_json::from_value::<Request>(request) {
+            Ok(request) if request.jsonrpc == JSONRPC_VERSION => request,
+            Ok(_) => {
+                let error = RpcError::new(INVALID_REQUEST, "Only JSON-RPC 2.0 is supported");
+                return Some(Response::new(id, Err(error)));
+            }
+            Err(e) => {
+                let error = RpcError::new(INVALID_REQUEST, format!("Invalid request: {}", e));
+                return Some(Response::new(id, Err(error)));
+            }
+        };
+        log::debug!("Control request: {}", request.method);
+        let outcome = self.handle(&request.method, request.params);
+        request.id.map(|id| Response::new(id, outcome))
+    }
+
+    async fn handle_client(&self, stream: UnixStream) -> Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        while let Some(line) = lines.next_line().await? {
+            let response = match self.respond(&line) {
+                Some(response) => response,
+                None => continue,
+            };
+            let mut response = serde_json::to_vec(&response)?;
+            response.push(b'\n');
+            writer.write_all(&response).await?;
+        }
+        Ok(())
+    }
+
+    /// Accepts control connections on `listener` until the process exits.
+    pub async fn serve(self: Arc<Self>, listener: UnixListener) -> Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let control = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = control.handle_client(stream).await {
+                    log::warn!("Control connection failed: {:?}", e);
+                }
+            });
+        }
+    }
+}
+
+/// Binds the control socket at `path`, readable and writable by our user
+/// only. A socket left behind by a previous run is replaced, but one that
+/// still answers belongs to a running firmware and is an error.
+pub async fn listen(path: &Path) -> Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.file_type().is_socket() => {
+            return Err(anyhow!(
+                "Control socket path {} exists and is not a socket",
+                path.display()
+            ));
+        }
+        Ok(_) => {
+            if UnixStream::connect(path).await.is_ok() {
+                return Err(anyhow!(
+                    "Another firmware is already listening at {}",
+                    path.display()
+                ));
+            }
+            fs::remove_file(path)?;
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into()),
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, Permissions::from_mode(0o600))?;
+    log::info!("Control socket listening at {}", path.display());
+    Ok(listener)
+}
+
+/// Sends a single request to a running firmware's control socket and prints
+/// the response.
+pub async fn request(path: &Path, method: &str, params: Option<&str>) -> Result<()> {
+    let params: Value = match params {
+        Some(params) => {
+            serde_json::from_str(params).map_err(|e| anyhow!("Params must be JSON: {}", e))?
+        }
+        None => Value::Null,
+    };
+    let stream = UnixStream::connect(path)
+        .await
+        .map_err(|e| anyhow!("Couldn't connect to {}: {}", path.display(), e))?;
+    let (reader, mut writer) = stream.into_split();
+    let request = serde_json::json!({
+        "jsonrpc": JSONRPC_VERSION,
+        "id": 1,
+        "method": method,
+        "params": params,
+    });
+    writer
+        .write_all(format!("{}\n", request).as_bytes())
+        .await?;
+    let line = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or_else(|| anyhow!("Control socket closed without a response"))?;
+    let response: Value = serde_json::from_str(&line)?;
+    if let Some(error) = response.get("error") {
+        return Err(anyhow!(
+            "{} ({})",
+            error["message"].as_str().unwrap_or_default(),
+            error["code"]
+        ));
+    }
+    if !response["result"].is_null() {
+        println!("{}", serde_json::to_string_pretty(&response["result"])?);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn respond(control: &Control, line: &str) -> Value {
+        serde_json::to_value(control.respond(line).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn json_rpc_responses() {
+        let control = Control::new(None);
+        let status = respond(
+            &control,
+            r#"{"jsonrpc": "2.0", "id": "a", "method": "status"}"#,
+        );
+        assert_eq!(status["jsonrpc"], "2.0");
+        assert_eq!(status["id"], "a");
+        assert_eq!(status["result"]["session"], "connecting");
+        assert!(status.get("error").is_none());
+
+        let cases = [
+            ("{", Value::Null, PARSE_ERROR),
+            (
+                r#"{"id": 1, "method": "status"}"#,
+                Value::from(1),
+                INVALID_REQUEST,
+            ),
+            (
+                r#"{"jsonrpc": "1.0", "id": 2, "method": "status"}"#,
+                Value::from(2),
+                INVALID_REQUEST,
+            ),
+            (
+                r#"{"jsonrpc": "2.0", "id": 3, "method": "dance"}"#,
+                Value::from(3),
+                METHOD_NOT_FOUND,
+            ),
+            (
+                r#"{"jsonrpc": "2.0", "id": 4, "method": "assign_slot", "params": {"slot": 1}}"#,
+                Value::from(4),
+                INVALID_PARAMS,
+            ),
+            (
+                r#"{"jsonrpc": "2.0", "id": 5, "method": "reconnect"}"#,
+                Value::from(5),
+                SERVER_ERROR,
+            ),
+        ];
+        for (line, id, code) in cases.iter() {
+            let response = respond(&control, line);
+            assert_eq!(response["id"], *id, "{}", line);
+            assert_eq!(response["error"]["code"], *code, "{}", line);
+            assert!(response.get("result").is_none(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn notifications_get_no_response() {
+        let control = Control::new(None);
+        let mut commands = control.commands();
+        assert!(control
+            .respond(r#"{"jsonrpc": "2.0", "method": "reconnect"}"#)
+            .is_none());
+        assert_eq!(commands.try_recv().unwrap(), ControlCommand::Reconnect);
+    }
+
+    #[tokio::test]
+    async fn listen_only_replaces_stale_sockets() {
+        let dir = std::env::temp_dir().join(format!("vulcast-control-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("control.sock");
+        let _ = fs::remove_file(&path);
+
+        let listener = listen(&path).await.unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let running = listen(&path).await.unwrap_err();
+        assert!(running.to_string().contains("already listening"));
+
+        // the socket file outlives its listener
+        drop(listener);
+        assert!(path.exists());
+        drop(listen(&path).await.unwrap());
+
+        fs::remove_file(&path).unwrap();
+        fs::write(&path, "not a socket").unwrap();
+        let file = listen(&path).await.unwrap_err();
+        assert!(file.to_string().contains("is not a socket"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
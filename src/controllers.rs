@@ -1,141 +1,956 @@
-use anyhow::{anyhow, Result};
-use controller_emulator::controller::ns_procon;
-use controller_emulator::controller::Controller;
-use controller_emulator::usb_gadget;
-use std::thread::sleep;
-use std::time::Duration;
+mod ds4;
+mod gadget;
+mod hid;
+mod keyboard_mouse;
+mod macros;
+mod mock;
+mod ownership;
+mod procon;
+mod remap;
+mod sticks;
+mod xinput;
 
+use crate::config::{Config, ControllerConfig, SticksConfig};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use vulcast_rtc::types::DataProducerId;
+
+pub use ds4::Ds4Gamepads;
+pub use keyboard_mouse::KeyboardMouse;
+use macros::{Macros, SlotMacros};
+pub use mock::{MockControllers, MockEvent};
+pub use ownership::SlotConflict;
+use ownership::SlotOwners;
+pub use procon::NsProcons;
+use remap::RemapProfiles;
+pub use sticks::DeadzoneShape;
+use sticks::SlotSticks;
+pub use xinput::XInputGamepads;
+
+/// Accelerometer and gyroscope readings, in the sender's units.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub struct Motion {
+    pub accel: [i16; 3],
+    pub gyro: [i16; 3],
+}
+
+/// One player's inputs, decoded from any version of the wire format in
+/// `protocol`.
 #[derive(Debug, Copy, Clone)]
-pub struct NetworkControllerState(pub [u8; 13]);
+pub struct NetworkControllerState {
+    pub(crate) player_id: u8,
+    pub(crate) sequence_no: u8,
+    /// Decoded from a legacy (version 0) message.
+    pub(crate) legacy: bool,
+    /// Button bitfield, indexed by the constants in `buttons`.
+    pub(crate) buttons: u32,
+    pub(crate) axes: [u16; 4],
+    /// Analog left and right triggers, if the sender has them.
+    pub(crate) triggers: Option<[u16; 2]>,
+    pub(crate) motion: Option<Motion>,
+}
+
+/// Axis value of a centered stick.
+pub const AXIS_CENTER: u16 = 0x8000;
+
+/// Indices of buttons in `NetworkControllerState`, named after the Switch
+/// Pro Controller buttons they were first defined for.
+pub mod buttons {
+    pub const A: usize = 0;
+    pub const B: usize = 1;
+    pub const X: usize = 2;
+    pub const Y: usize = 3;
+    pub const L: usize = 4;
+    pub const R: usize = 5;
+    pub const ZL: usize = 6;
+    pub const ZR: usize = 7;
+    pub const MINUS: usize = 8;
+    pub const PLUS: usize = 9;
+    pub const L_STICK: usize = 10;
+    pub const R_STICK: usize = 11;
+    pub const UP: usize = 12;
+    pub const DOWN: usize = 13;
+    pub const LEFT: usize = 14;
+    pub const RIGHT: usize = 15;
+    pub const HOME: usize = 16;
+    pub const CAPTURE: usize = 17;
+    pub const SL: usize = 18;
+    pub const SR: usize = 19;
+}
+
+/// Number of buttons in the legacy wire format; Capture, SL and SR follow.
+pub const LEGACY_BUTTONS: usize = 17;
+pub const NUM_BUTTONS: usize = 20;
+
+/// Analog trigger value above which a digital trigger counts as pressed.
+const TRIGGER_THRESHOLD: u16 = 0x8000;
 
 impl NetworkControllerState {
-    fn get_u16(&self, offset: usize) -> u16 {
-        ((self.0[offset] as u16) << 8) | (self.0[offset + 1] as u16)
+    /// State with no buttons pressed and both sticks centered.
+    pub fn neutral(player_id: usize) -> Self {
+        NetworkControllerState {
+            player_id: player_id as u8,
+            sequence_no: 0,
+            legacy: false,
+            buttons: 0,
+            axes: [AXIS_CENTER; 4],
+            triggers: None,
+            motion: None,
+        }
     }
 
     pub fn player_id(&self) -> usize {
-        self.0[0] as usize
+        self.player_id as usize
     }
 
-    pub fn _sequence_no(&self) -> u8 {
-        self.0[1]
+    pub fn sequence_no(&self) -> u8 {
+        self.sequence_no
     }
 
     pub fn num_buttons(&self) -> usize {
-        17
+        NUM_BUTTONS
     }
 
     pub fn get_button(&self, index: usize) -> bool {
-        let byte = 2 + (index >> 3);
-        let byte_ind = index & 7;
+        (self.buttons >> index) & 1 == 1
+    }
 
-        ((self.0[byte] >> byte_ind) & 1) == 1
+    /// Whether a button is pressed, counting ZL and ZR as pressed once their
+    /// analog trigger passes the halfway point.
+    pub fn is_pressed(&self, index: usize) -> bool {
+        let analog = match (self.triggers, index) {
+            (Some([left, _]), buttons::ZL) => left > TRIGGER_THRESHOLD,
+            (Some([_, right]), buttons::ZR) => right > TRIGGER_THRESHOLD,
+            _ => false,
+        };
+        analog || self.get_button(index)
     }
+
     pub fn lh(&self) -> u16 {
-        self.get_u16(5)
+        self.axes[0]
     }
     pub fn lv(&self) -> u16 {
-        self.get_u16(7)
+        self.axes[1]
     }
     pub fn rh(&self) -> u16 {
-        self.get_u16(9)
+        self.axes[2]
     }
     pub fn rv(&self) -> u16 {
-        self.get_u16(11)
+        self.axes[3]
+    }
+
+    pub fn triggers(&self) -> Option<[u16; 2]> {
+        self.triggers
+    }
+
+    /// Left and right trigger positions, fully pressed while ZL or ZR is.
+    pub fn analog_triggers(&self) -> [u16; 2] {
+        let digital = |index| {
+            if self.get_button(index) {
+                u16::MAX
+            } else {
+                0
+            }
+        };
+        let [left, right] = self.triggers.unwrap_or_default();
+        [
+            left.max(digital(buttons::ZL)),
+            right.max(digital(buttons::ZR)),
+        ]
+    }
+
+    pub fn motion(&self) -> Option<Motion> {
+        self.motion
+    }
+
+    /// Indices of the buttons currently pressed.
+    pub fn pressed_buttons(&self) -> Vec<usize> {
+        (0..self.num_buttons())
+            .filter(|&button| self.get_button(button))
+            .collect()
     }
 
+    /// Whether any input differs, ignoring the sequence number.
     pub fn diff(&self, other: &NetworkControllerState) -> bool {
-        self.0[2..13]
-            .iter()
-            .zip(&other.0[2..13])
-            .fold(0u32, |acc, (x, y)| acc + (x ^ y) as u32)
-            != 0
+        self.buttons != other.buttons
+            || self.axes != other.axes
+            || self.triggers != other.triggers
+            || self.motion != other.motion
+    }
+}
+
+/// Why the firmware reset a slot to neutral on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReleaseReason {
+    /// No input arrived for the slot within the configured silence window.
+    InputTimeout,
+    /// The data consumer that was driving the slot ended.
+    ConsumerEnded,
+    /// Released through the control socket or on shutdown.
+    Requested,
+    /// The slot was assigned to a different data producer.
+    Reassigned,
+}
+
+impl fmt::Display for ReleaseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ReleaseReason::InputTimeout => "no input received",
+            ReleaseReason::ConsumerEnded => "data consumer ended",
+            ReleaseReason::Requested => "release requested",
+            ReleaseReason::Reassigned => "slot reassigned",
+        })
+    }
+}
+
+/// Input watchdog and sequence bookkeeping for one slot, reset whenever the
+/// slot is released so the next sender starts afresh.
+#[derive(Debug, Clone, Default)]
+struct SlotActivity {
+    last_input: Option<Instant>,
+    last_sequence: Option<u8>,
+    released: Option<ReleaseReason>,
+    /// Remapping profile the player selected, kept until their data
+    /// consumer ends.
+    profile: Option<String>,
+}
+
+/// Per-slot counters of sequence number anomalies.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct SequenceStats {
+    pub received: u64,
+    /// Messages never received, inferred from gaps in the sequence.
+    pub dropped: u64,
+    /// Messages repeating the previous sequence number, which are ignored.
+    pub duplicated: u64,
+    /// Messages older than one already applied, which are ignored when the
+    /// data channel is unordered.
+    pub out_of_order: u64,
+}
+
+/// Upper bound on `[controller] slots`.
+pub const MAX_SLOTS: usize = 8;
+/// Upper bound on `[controller] turbo_rate_hz`, leaving a few ticks of
+/// `MACRO_TICK` for each press and release.
+pub const MAX_TURBO_RATE_HZ: u32 = 20;
+/// How often turbo and macro playback advance.
+pub const MACRO_TICK: Duration = Duration::from_millis(8);
+
+/// Parses a comma-separated list of `RRGGBB` hex colors.
+pub fn parse_colors(colors: &str) -> Result<Vec<[u8; 3]>> {
+    colors
+        .split(',')
+        .map(str::trim)
+        .map(|color| {
+            let hex = color.trim_start_matches('#');
+            let value = u32::from_str_radix(hex, 16)
+                .ok()
+                .filter(|_| hex.len() == 6)
+                .with_context(|| format!("Invalid color {:?}, expected RRGGBB", color))?;
+            let [_, r, g, b] = value.to_be_bytes();
+            Ok([r, g, b])
+        })
+        .collect()
+}
+
+/// Kind of USB device the players' inputs are presented as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ControllerBackend {
+    /// Nintendo Switch Pro Controllers.
+    Procon,
+    /// Generic Xbox-style HID gamepads.
+    Xinput,
+    /// DualShock 4-style gamepads.
+    Ds4,
+    /// A keyboard and mouse driven by the first player.
+    KeyboardMouse,
+    /// Records input in memory instead of using a USB gadget.
+    Mock,
+}
+
+impl ControllerBackend {
+    /// Name of the USB gadget used when `[controller] gadget_name` is unset.
+    pub fn default_gadget_name(&self) -> &'static str {
+        match self {
+            ControllerBackend::Procon => "procons",
+            ControllerBackend::Xinput => "xinput",
+            ControllerBackend::Ds4 => "ds4",
+            ControllerBackend::KeyboardMouse => "keyboard_mouse",
+            ControllerBackend::Mock => "mock",
+        }
+    }
+
+    /// Number of HID devices the backend uses for `slots` players.
+    pub fn device_count(&self, slots: usize) -> usize {
+        match self {
+            ControllerBackend::KeyboardMouse => 2,
+            ControllerBackend::Mock => 0,
+            _ => slots,
+        }
     }
 }
 
-pub trait Controllers {
-    fn new(gadget_name: &str) -> Self;
+/// A set of emulated USB devices, one per player slot.
+pub trait Controllers: Send {
     fn initialize(&mut self) -> Result<()>;
-    fn set_state(&mut self, state: NetworkControllerState) -> Result<()>;
-}
-pub struct NsProcons {
-    gadget_name: String,
-    controllers: [ns_procon::NsProcon; 4],
-    last_state: [NetworkControllerState; 4],
-}
-
-static PROCON_BUTTON_MAP: &[usize] = &[
-    ns_procon::inputs::BUTTON_A,
-    ns_procon::inputs::BUTTON_B,
-    ns_procon::inputs::BUTTON_X,
-    ns_procon::inputs::BUTTON_Y,
-    ns_procon::inputs::BUTTON_L,
-    ns_procon::inputs::BUTTON_R,
-    ns_procon::inputs::BUTTON_ZL,
-    ns_procon::inputs::BUTTON_ZR,
-    ns_procon::inputs::BUTTON_MINUS,
-    ns_procon::inputs::BUTTON_PLUS,
-    ns_procon::inputs::BUTTON_L_STICK,
-    ns_procon::inputs::BUTTON_R_STICK,
-    ns_procon::inputs::BUTTON_UP,
-    ns_procon::inputs::BUTTON_DOWN,
-    ns_procon::inputs::BUTTON_LEFT,
-    ns_procon::inputs::BUTTON_RIGHT,
-    ns_procon::inputs::BUTTON_HOME,
-];
-
-impl Controllers for NsProcons {
-    fn new(gadget_name: &str) -> Self {
-        let procon_1 = ns_procon::NsProcon::create("/dev/hidg0", [255, 0, 0]);
-        let procon_2 = ns_procon::NsProcon::create("/dev/hidg1", [0, 192, 0]);
-        let procon_3 = ns_procon::NsProcon::create("/dev/hidg2", [255, 255, 0]);
-        let procon_4 = ns_procon::NsProcon::create("/dev/hidg3", [64, 64, 255]);
-
-        Self {
-            gadget_name: gadget_name.to_string(),
-            controllers: [procon_1, procon_2, procon_3, procon_4],
-            last_state: [NetworkControllerState([0u8; 13]); 4],
-        }
-    }
-
-    fn initialize(&mut self) -> Result<()> {
-        usb_gadget::activate(&self.gadget_name).expect("Could not activate procon gadget");
-
-        sleep(Duration::from_secs(1));
-
-        self.controllers[0].start_comms()?;
-        self.controllers[1].start_comms()?;
-        self.controllers[2].start_comms()?;
-        self.controllers[3].start_comms()?;
+    /// Deactivates the USB gadget.
+    fn deinitialize(&mut self) -> Result<()>;
+    /// Number of players the devices can take input from.
+    fn slots(&self) -> usize;
+    /// Presents a player's input on their device.
+    fn write_state(&mut self, state: &NetworkControllerState) -> Result<()>;
+    /// Every input change so far, for backends that record them.
+    fn recorded_events(&self) -> Option<Vec<MockEvent>> {
+        None
+    }
+}
+
+/// Creates the devices for the configured backend.
+pub fn create(conf: &ControllerConfig) -> Result<Box<dyn Controllers>> {
+    Ok(match conf.backend {
+        ControllerBackend::Procon => Box::new(NsProcons::new(conf)?),
+        ControllerBackend::Xinput => Box::new(XInputGamepads::new(conf)),
+        ControllerBackend::Ds4 => Box::new(Ds4Gamepads::new(conf)),
+        ControllerBackend::KeyboardMouse => Box::new(KeyboardMouse::new(conf)),
+        ControllerBackend::Mock => Box::new(MockControllers::new(conf)),
+    })
+}
+
+/// Tracks what each player slot is doing and filters their input before it
+/// reaches the devices.
+pub struct ControllerSlots {
+    controllers: Box<dyn Controllers>,
+    /// Each player's input after remapping and stick processing.
+    input: Vec<NetworkControllerState>,
+    /// What was last written to each device, after turbo and macros.
+    last_state: Vec<NetworkControllerState>,
+    activity: Vec<SlotActivity>,
+    sequence_stats: Vec<SequenceStats>,
+    /// Whether messages arrive in order, so that a sequence number going
+    /// backwards means the sender restarted rather than a late message.
+    ordered_input: bool,
+    /// Whether legacy clients advance their sequence numbers; when they
+    /// don't, their messages are applied regardless.
+    legacy_sequence_numbers: bool,
+    profiles: RemapProfiles,
+    stick_settings: SticksConfig,
+    /// Kept across releases until the slot's data consumer ends, like the
+    /// remapping profile.
+    sticks: Vec<SlotSticks>,
+    macros: Macros,
+    /// Turbo settings are kept until the slot's data consumer ends; macros
+    /// stop when the slot is released.
+    slot_macros: Vec<SlotMacros>,
+    turbo_period: Duration,
+    owners: SlotOwners,
+}
+
+impl ControllerSlots {
+    /// Sets up the slots for `controllers`, with the remapping profiles and
+    /// macros in `config_dir`.
+    pub fn new(
+        controllers: Box<dyn Controllers>,
+        conf: &Config,
+        config_dir: &Path,
+    ) -> Result<Self> {
+        let profiles = RemapProfiles::load(&config_dir.join("profiles.conf"))?;
+        let macros = Macros::load(&config_dir.join("macros.conf"))?;
+        let slots = controllers.slots();
+        let neutral: Vec<_> = (0..slots).map(NetworkControllerState::neutral).collect();
+        Ok(Self {
+            controllers,
+            input: neutral.clone(),
+            last_state: neutral,
+            activity: vec![SlotActivity::default(); slots],
+            sequence_stats: vec![SequenceStats::default(); slots],
+            ordered_input: conf.controller.ordered_input,
+            legacy_sequence_numbers: conf.controller.legacy_sequence_numbers,
+            profiles,
+            stick_settings: conf.sticks.clone(),
+            sticks: vec![SlotSticks::default(); slots],
+            macros,
+            slot_macros: vec![SlotMacros::default(); slots],
+            turbo_period: Duration::from_secs(1) / conf.controller.turbo_rate_hz,
+            owners: SlotOwners::new(
+                slots,
+                conf.controller.slot_conflict,
+                conf.controller.slots_per_producer,
+            ),
+        })
+    }
+
+    pub fn initialize(&mut self) -> Result<()> {
+        self.controllers.initialize()
+    }
+
+    /// Releases all inputs and deactivates the USB gadget.
+    pub fn deinitialize(&mut self) -> Result<()> {
+        let released = self.release_all();
+        self.controllers.deinitialize()?;
+        released
+    }
+
+    fn check_slot(&self, player_id: usize) -> Result<()> {
+        if player_id >= self.last_state.len() {
+            return Err(anyhow!("Invalid controller number: {}", player_id));
+        }
         Ok(())
     }
 
-    fn set_state(&mut self, state: NetworkControllerState) -> Result<()> {
-        if state.player_id() >= 4 {
-            return Err(anyhow!("Invalid controller number: {}", state.player_id()));
+    pub fn set_state(&mut self, mut state: NetworkControllerState) -> Result<()> {
+        self.check_slot(state.player_id())?;
+
+        let sequence_no = if state.legacy && !self.legacy_sequence_numbers {
+            None
+        } else {
+            Some(state.sequence_no())
+        };
+        if !self.accept_sequence(state.player_id(), sequence_no) {
+            return Ok(());
         }
+        let now = Instant::now();
+        let activity = &mut self.activity[state.player_id()];
+        activity.last_input = Some(now);
+        activity.released = None;
 
-        if !self.last_state[state.player_id()].diff(&state) {
+        let profiles = &self.profiles;
+        if let Some(profile) = activity
+            .profile
+            .as_ref()
+            .and_then(|name| profiles.get(name))
+        {
+            state.buttons = profile.apply(state.buttons);
+        }
+        state.axes = self.sticks[state.player_id()].apply(&self.stick_settings, state.axes, now);
+
+        self.input[state.player_id()] = state;
+        self.render(state.player_id(), now)
+    }
+
+    /// Writes a slot's input with turbo and macros applied at `now`, if that
+    /// changes what the device shows.
+    fn render(&mut self, player_id: usize, now: Instant) -> Result<()> {
+        let output =
+            self.slot_macros[player_id].render(&self.input[player_id], self.turbo_period, now);
+        if !self.last_state[player_id].diff(&output) {
             return Ok(());
         }
+        self.write_state(output)
+    }
+
+    /// Advances turbo and macro playback on every slot using them.
+    pub fn tick(&mut self, now: Instant) -> Result<()> {
+        for player_id in 0..self.slot_macros.len() {
+            if self.slot_macros[player_id].is_active() {
+                self.render(player_id, now)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Turns turbo on or off for one of a slot's buttons.
+    pub fn set_turbo(&mut self, player_id: usize, button: usize, enabled: bool) -> Result<()> {
+        self.check_slot(player_id)?;
+        if button >= NUM_BUTTONS {
+            return Err(anyhow!("Invalid button number: {}", button));
+        }
+        log::info!(
+            "Controller {} turbo {} for button {}",
+            player_id,
+            if enabled { "on" } else { "off" },
+            button
+        );
+        self.slot_macros[player_id].set_turbo(button, enabled);
+        self.render(player_id, Instant::now())
+    }
+
+    /// Starts playing a macro on a slot, replacing any already playing, or
+    /// stops playback with `None`.
+    pub fn play_macro(&mut self, player_id: usize, name: Option<&str>) -> Result<()> {
+        self.check_slot(player_id)?;
+        let slot_macros = &mut self.slot_macros[player_id];
+        match name {
+            Some(name) => {
+                let playing = self
+                    .macros
+                    .get(name)
+                    .ok_or_else(|| anyhow!("Unknown macro {:?}", name))?;
+                log::info!("Controller {} playing macro {}", player_id, name);
+                slot_macros.play(playing.clone(), Instant::now());
+            }
+            None => slot_macros.stop(),
+        }
+        self.render(player_id, Instant::now())
+    }
+
+    /// The slot `owner` may write to for `player_id`, claiming a free one on
+    /// first use.
+    pub fn claim(&mut self, owner: &DataProducerId, player_id: u8) -> Result<usize> {
+        self.owners.resolve(owner, player_id)
+    }
+
+    /// Binds a slot to `owner` for all of its input, releasing the slot from
+    /// any previous owner and any slots `owner` had before.
+    pub fn assign(&mut self, owner: &DataProducerId, slot: usize) -> Result<()> {
+        self.check_slot(slot)?;
+        log::info!("Controller {} assigned to data producer {:?}", slot, owner);
+        for changed in self.owners.assign(owner, slot) {
+            self.release(changed, ReleaseReason::Reassigned)?;
+        }
+        Ok(())
+    }
+
+    /// Releases and unbinds every slot `owner` drove, once it has gone.
+    pub fn disown(&mut self, owner: &DataProducerId) -> Result<()> {
+        for slot in self.owners.remove(owner) {
+            self.release(slot, ReleaseReason::ConsumerEnded)?;
+        }
+        Ok(())
+    }
+
+    /// Switches a slot to a remapping profile, or back to the default layout
+    /// with `None`. Takes effect from the slot's next input.
+    pub fn select_profile(&mut self, player_id: usize, profile: Option<&str>) -> Result<()> {
+        self.check_slot(player_id)?;
+        if let Some(name) = profile {
+            if self.profiles.get(name).is_none() {
+                return Err(anyhow!("Unknown remapping profile {:?}", name));
+            }
+        }
+        log::info!(
+            "Controller {} using remapping profile {}",
+            player_id,
+            profile.unwrap_or("default")
+        );
+        self.activity[player_id].profile = profile.map(str::to_owned);
+        Ok(())
+    }
+
+    /// Finds the resting position of a slot's sticks from its next inputs,
+    /// which must be sent with the sticks untouched.
+    pub fn calibrate_sticks(&mut self, player_id: usize) -> Result<()> {
+        self.check_slot(player_id)?;
+        log::info!("Calibrating controller {} sticks", player_id);
+        self.sticks[player_id].calibrate(Instant::now());
+        Ok(())
+    }
+
+    /// Releases every button and centers every stick on all slots.
+    pub fn release_all(&mut self) -> Result<()> {
+        self.release_each(0..self.last_state.len(), ReleaseReason::Requested)
+    }
+
+    /// Releases each of `slots`, going on past those that fail so that one
+    /// bad device does not leave the others held.
+    fn release_each(
+        &mut self,
+        slots: impl IntoIterator<Item = usize>,
+        reason: ReleaseReason,
+    ) -> Result<()> {
+        let failures: Vec<String> = slots
+            .into_iter()
+            .filter_map(|player_id| {
+                self.release(player_id, reason)
+                    .err()
+                    .map(|e| format!("controller {}: {}", player_id, e))
+            })
+            .collect();
+        if !failures.is_empty() {
+            return Err(anyhow!("Couldn't release {}", failures.join(", ")));
+        }
+        Ok(())
+    }
+
+    /// Resets a single slot to neutral, recording why.
+    pub fn release(&mut self, player_id: usize, reason: ReleaseReason) -> Result<()> {
+        self.check_slot(player_id)?;
+        log::info!("Releasing controller {}: {}", player_id, reason);
+        // the next player on the slot starts with the default layout
+        let profile = match reason {
+            ReleaseReason::ConsumerEnded | ReleaseReason::Reassigned => {
+                self.sticks[player_id] = SlotSticks::default();
+                self.slot_macros[player_id] = SlotMacros::default();
+                None
+            }
+            _ => self.activity[player_id].profile.take(),
+        };
+        self.activity[player_id] = SlotActivity {
+            released: Some(reason),
+            profile,
+            ..SlotActivity::default()
+        };
+        self.slot_macros[player_id].stop();
+        self.input[player_id] = NetworkControllerState::neutral(player_id);
+        self.write_state(self.input[player_id])
+    }
+
+    /// Releases every slot that has had no input for longer than `timeout`.
+    pub fn release_idle(&mut self, timeout: Duration) -> Result<()> {
+        let idle: Vec<usize> = (0..self.activity.len())
+            .filter(|&player_id| match self.activity[player_id].last_input {
+                Some(last_input) => last_input.elapsed() > timeout,
+                None => false,
+            })
+            .collect();
+        self.release_each(idle, ReleaseReason::InputTimeout)
+    }
+
+    /// The state last written to each slot.
+    pub fn states(&self) -> Vec<NetworkControllerState> {
+        self.last_state.clone()
+    }
+
+    /// Why each slot was last released, or `None` if it is receiving input.
+    pub fn release_reasons(&self) -> Vec<Option<ReleaseReason>> {
+        self.activity
+            .iter()
+            .map(|activity| activity.released)
+            .collect()
+    }
+
+    /// The remapping profile each slot is using, `None` for the default.
+    pub fn profiles(&self) -> Vec<Option<String>> {
+        self.activity
+            .iter()
+            .map(|activity| activity.profile.clone())
+            .collect()
+    }
+
+    /// Whether each slot's sticks are being calibrated.
+    pub fn calibrating(&self) -> Vec<bool> {
+        self.sticks.iter().map(SlotSticks::is_calibrating).collect()
+    }
+
+    /// The data producer bound to each slot, if any.
+    pub fn owners(&self) -> Vec<Option<DataProducerId>> {
+        (0..self.last_state.len())
+            .map(|slot| self.owners.owner(slot).cloned())
+            .collect()
+    }
+
+    /// Buttons with turbo enabled on each slot.
+    pub fn turbo_buttons(&self) -> Vec<Vec<usize>> {
+        self.slot_macros
+            .iter()
+            .map(SlotMacros::turbo_buttons)
+            .collect()
+    }
+
+    /// The macro playing on each slot, if any.
+    pub fn playing_macros(&self) -> Vec<Option<String>> {
+        self.slot_macros
+            .iter()
+            .map(|slot| slot.playing().map(str::to_owned))
+            .collect()
+    }
+
+    pub fn sequence_stats(&self) -> Vec<SequenceStats> {
+        self.sequence_stats.clone()
+    }
+
+    pub fn recorded_events(&self) -> Option<Vec<MockEvent>> {
+        self.controllers.recorded_events()
+    }
+
+    /// Updates the slot's sequence tracking and decides whether a message
+    /// should be applied. Sequence numbers wrap, so a message is newer if it
+    /// is less than half the sequence space ahead of the last one. Messages
+    /// without a usable sequence number are always applied.
+    fn accept_sequence(&mut self, player_id: usize, sequence_no: Option<u8>) -> bool {
+        let activity = &mut self.activity[player_id];
+        let stats = &mut self.sequence_stats[player_id];
+        stats.received += 1;
+
+        let sequence_no = match sequence_no {
+            Some(sequence_no) => sequence_no,
+            None => return true,
+        };
+        let last = match activity.last_sequence {
+            Some(last) => last,
+            None => {
+                activity.last_sequence = Some(sequence_no);
+                return true;
+            }
+        };
+        let delta = sequence_no.wrapping_sub(last) as i8;
+        if delta == 0 {
+            stats.duplicated += 1;
+            return false;
+        }
+        if delta < 0 {
+            if !self.ordered_input {
+                stats.out_of_order += 1;
+                return false;
+            }
+            log::debug!(
+                "Controller {} sequence restarted at {}",
+                player_id,
+                sequence_no
+            );
+        } else {
+            stats.dropped += (delta - 1) as u64;
+        }
+        activity.last_sequence = Some(sequence_no);
+        true
+    }
 
+    fn write_state(&mut self, state: NetworkControllerState) -> Result<()> {
         self.last_state[state.player_id()] = state;
+        self.controllers.write_state(&state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::MockInput;
+    use super::*;
 
-        let controller = &mut self.controllers[state.player_id()];
+    fn mock_slots() -> ControllerSlots {
+        let mut conf = Config::default();
+        conf.controller.backend = ControllerBackend::Mock;
+        let controllers = Box::new(MockControllers::new(&conf.controller));
+        ControllerSlots::new(controllers, &conf, Path::new("/nonexistent")).unwrap()
+    }
 
-        for (button, &mapping) in PROCON_BUTTON_MAP
+    /// Inputs the mock devices have seen change, without their times.
+    fn device_inputs(slots: &ControllerSlots) -> Vec<(usize, MockInput)> {
+        slots
+            .recorded_events()
+            .unwrap()
             .iter()
-            .enumerate()
-            .take(state.num_buttons())
-        {
-            let _ = controller.set(mapping, state.get_button(button), false);
+            .map(|event| (event.player_id, event.input))
+            .collect()
+    }
+
+    fn press(index: usize) -> MockInput {
+        MockInput::Button {
+            index,
+            pressed: true,
+        }
+    }
+
+    fn unpress(index: usize) -> MockInput {
+        MockInput::Button {
+            index,
+            pressed: false,
+        }
+    }
+
+    fn input(sequence_no: u8, buttons: u32) -> NetworkControllerState {
+        NetworkControllerState {
+            sequence_no,
+            buttons,
+            ..NetworkControllerState::neutral(0)
+        }
+    }
+
+    fn legacy_input(sequence_no: u8, buttons: u32) -> NetworkControllerState {
+        NetworkControllerState {
+            legacy: true,
+            ..input(sequence_no, buttons)
         }
+    }
+
+    #[test]
+    fn legacy_input_is_sequenced() {
+        let mut slots = mock_slots();
+        slots.set_state(legacy_input(3, 1 << buttons::A)).unwrap();
+        slots.set_state(legacy_input(3, 1 << buttons::B)).unwrap();
+        slots.set_state(legacy_input(2, 0)).unwrap();
+
+        assert_eq!(slots.last_state[0].buttons, 1 << buttons::A);
+        let stats = slots.sequence_stats()[0];
+        assert_eq!(stats.received, 3);
+        assert_eq!(stats.duplicated, 1);
+        assert_eq!(stats.out_of_order, 1);
+    }
+
+    #[test]
+    fn legacy_sequence_numbers_can_be_ignored() {
+        let mut conf = Config::default();
+        conf.controller.backend = ControllerBackend::Mock;
+        conf.controller.legacy_sequence_numbers = false;
+        let controllers = Box::new(MockControllers::new(&conf.controller));
+        let mut slots =
+            ControllerSlots::new(controllers, &conf, Path::new("/nonexistent")).unwrap();
+        slots.set_state(legacy_input(0, 1 << buttons::A)).unwrap();
+        slots.set_state(legacy_input(0, 1 << buttons::B)).unwrap();
+        slots.set_state(legacy_input(0, 0)).unwrap();
+
+        assert_eq!(slots.last_state[0].buttons, 0);
+        assert_eq!(slots.recorded_events().unwrap().len(), 4);
+        let stats = slots.sequence_stats()[0];
+        assert_eq!(stats.received, 3);
+        assert_eq!(stats.duplicated, 0);
+        assert_eq!(stats.out_of_order, 0);
+
+        // versioned clients are still held to their sequence numbers
+        slots.set_state(input(5, 1 << buttons::X)).unwrap();
+        slots.set_state(input(5, 1 << buttons::Y)).unwrap();
+        assert_eq!(slots.last_state[0].buttons, 1 << buttons::X);
+        assert_eq!(slots.sequence_stats()[0].duplicated, 1);
+    }
+
+    #[test]
+    fn repeated_sequence_is_duplicate() {
+        let mut slots = mock_slots();
+        slots.set_state(input(7, 1 << buttons::A)).unwrap();
+        slots.set_state(input(7, 1 << buttons::B)).unwrap();
+
+        assert_eq!(slots.last_state[0].buttons, 1 << buttons::A);
+        assert_eq!(slots.sequence_stats()[0].duplicated, 1);
+    }
+
+    #[test]
+    fn sequence_gaps_and_late_messages() {
+        let mut slots = mock_slots();
+        slots.set_state(input(254, 0)).unwrap();
+        slots.set_state(input(2, 1 << buttons::A)).unwrap();
+        slots.set_state(input(1, 1 << buttons::B)).unwrap();
+
+        assert_eq!(slots.last_state[0].buttons, 1 << buttons::A);
+        let stats = slots.sequence_stats()[0];
+        assert_eq!(stats.dropped, 3);
+        assert_eq!(stats.out_of_order, 1);
+    }
+
+    #[test]
+    fn input_reaches_device_until_released() {
+        let mut slots = mock_slots();
+        let mut state = input(0, 1 << buttons::A);
+        state.player_id = 1;
+        state.axes[0] = 0xffff;
+        slots.set_state(state).unwrap();
+        slots.release(1, ReleaseReason::Requested).unwrap();
+
+        assert_eq!(
+            device_inputs(&slots),
+            vec![
+                (1, press(buttons::A)),
+                (1, MockInput::Lh(0xffff)),
+                (1, unpress(buttons::A)),
+                (1, MockInput::Lh(AXIS_CENTER)),
+            ]
+        );
+        assert_eq!(
+            slots.release_reasons(),
+            vec![None, Some(ReleaseReason::Requested), None, None]
+        );
+    }
+
+    #[test]
+    fn release_idle_only_releases_slots_with_input() {
+        let mut slots = mock_slots();
+        slots.set_state(input(0, 1 << buttons::B)).unwrap();
+        std::thread::sleep(Duration::from_millis(2));
+        slots.release_idle(Duration::from_millis(1)).unwrap();
+
+        assert_eq!(
+            device_inputs(&slots),
+            vec![(0, press(buttons::B)), (0, unpress(buttons::B))]
+        );
+        assert_eq!(
+            slots.release_reasons(),
+            vec![Some(ReleaseReason::InputTimeout), None, None, None]
+        );
+    }
+
+    #[test]
+    fn reassigned_slot_gets_default_layout() {
+        let config_dir =
+            std::env::temp_dir().join(format!("vulcast-reassign-{}", std::process::id()));
+        std::fs::create_dir_all(&config_dir).unwrap();
+        std::fs::write(config_dir.join("profiles.conf"), "[swap]\na = b\n").unwrap();
+        let mut conf = Config::default();
+        conf.controller.backend = ControllerBackend::Mock;
+        let controllers = Box::new(MockControllers::new(&conf.controller));
+        let mut slots = ControllerSlots::new(controllers, &conf, &config_dir).unwrap();
+        std::fs::remove_dir_all(&config_dir).unwrap();
+
+        let producer = |id: &str| -> DataProducerId {
+            serde_json::from_value(serde_json::Value::from(id)).unwrap()
+        };
+        let slot = slots.claim(&producer("first"), 0).unwrap();
+        slots.select_profile(slot, Some("swap")).unwrap();
+        slots.set_turbo(slot, buttons::X, true).unwrap();
+        slots.calibrate_sticks(slot).unwrap();
+        slots.assign(&producer("second"), slot).unwrap();
+
+        assert_eq!(
+            slots.release_reasons()[slot],
+            Some(ReleaseReason::Reassigned)
+        );
+        assert_eq!(slots.profiles()[slot], None);
+        assert!(slots.turbo_buttons()[slot].is_empty());
+        assert!(!slots.calibrating()[slot]);
+        slots
+            .set_state(input(0, 1 << buttons::A | 1 << buttons::X))
+            .unwrap();
+        let start = Instant::now();
+        slots.tick(start + Duration::from_millis(75)).unwrap();
+        assert_eq!(
+            device_inputs(&slots),
+            vec![(0, press(buttons::A)), (0, press(buttons::X))]
+        );
+    }
+
+    #[test]
+    fn turbo_fires_on_tick() {
+        let mut slots = mock_slots();
+        slots.set_turbo(0, buttons::A, true).unwrap();
+        let start = Instant::now();
+        slots.set_state(input(0, 1 << buttons::A)).unwrap();
+        // the default 10Hz turbo holds for 50ms and releases for 50ms
+        slots.tick(start + Duration::from_millis(25)).unwrap();
+        slots.tick(start + Duration::from_millis(75)).unwrap();
+        slots.tick(start + Duration::from_millis(125)).unwrap();
+        slots.set_state(input(1, 0)).unwrap();
+        slots.tick(start + Duration::from_millis(175)).unwrap();
+
+        assert_eq!(
+            device_inputs(&slots),
+            vec![
+                (0, press(buttons::A)),
+                (0, unpress(buttons::A)),
+                (0, press(buttons::A)),
+                (0, unpress(buttons::A)),
+            ]
+        );
+    }
+
+    #[test]
+    fn macro_plays_on_tick() {
+        let config_dir =
+            std::env::temp_dir().join(format!("vulcast-macros-{}", std::process::id()));
+        std::fs::create_dir_all(&config_dir).unwrap();
+        std::fs::write(
+            config_dir.join("macros.conf"),
+            "[tap]\nsteps = y:100, none:100\n",
+        )
+        .unwrap();
+        let mut conf = Config::default();
+        conf.controller.backend = ControllerBackend::Mock;
+        let controllers = Box::new(MockControllers::new(&conf.controller));
+        let mut slots = ControllerSlots::new(controllers, &conf, &config_dir).unwrap();
+        std::fs::remove_dir_all(&config_dir).unwrap();
 
-        let _ = controller.set_axis(ns_procon::inputs::AXIS_LH, state.lh(), false);
-        let _ = controller.set_axis(ns_procon::inputs::AXIS_LV, state.lv(), false);
-        let _ = controller.set_axis(ns_procon::inputs::AXIS_RH, state.rh(), false);
-        let _ = controller.set_axis(ns_procon::inputs::AXIS_RV, state.rv(), false);
+        assert!(slots.play_macro(2, Some("missing")).is_err());
+        let start = Instant::now();
+        slots.play_macro(2, Some("tap")).unwrap();
+        slots.tick(start + Duration::from_millis(50)).unwrap();
+        slots.tick(start + Duration::from_millis(150)).unwrap();
+        slots.tick(start + Duration::from_millis(250)).unwrap();
 
-        controller.flush_input()
+        assert_eq!(
+            device_inputs(&slots),
+            vec![(2, press(buttons::Y)), (2, unpress(buttons::Y))]
+        );
+        assert_eq!(slots.playing_macros()[2], None);
     }
 }
@@ -0,0 +1,191 @@
+//! DualShock 4-style gamepads, sending the controller's 64-byte USB input
+//! report (report id 1). Only the fields below are filled in; the touchpad
+//! always reports no touches.
+//!
+//! | offset | size | field                                                   |
+//! |--------|------|---------------------------------------------------------|
+//! | 0      | 1    | report id                                               |
+//! | 1      | 4    | LX LY RX RY as u8, Y down                               |
+//! | 5      | 1    | hat switch in the low nibble, Square Cross Circle Triangle |
+//! | 6      | 1    | L1 R1 L2 R2 Share Options L3 R3                         |
+//! | 7      | 1    | PS, touchpad click, 6-bit report counter                |
+//! | 8      | 2    | L2 R2 as u8                                             |
+//! | 13     | 12   | gyro x y z, accel x y z as little endian i16            |
+
+use super::gadget::{Gadget, HidFunction};
+use super::hid::{self, HidDevice};
+use super::{buttons, Controllers, NetworkControllerState};
+use crate::config::ControllerConfig;
+
+use anyhow::Result;
+use std::thread::sleep;
+use std::time::Duration;
+
+const REPORT_LENGTH: usize = 64;
+const REPORT_ID: u8 = 0x01;
+
+/// Describes the fields filled in above, with the rest of the report as
+/// vendor-defined bytes.
+static REPORT_DESC: &[u8] = &[
+    0x05, 0x01, // usage page (generic desktop)
+    0x09, 0x05, // usage (game pad)
+    0xa1, 0x01, // collection (application)
+    0x85, 0x01, //   report id (1)
+    0x09, 0x30, //   usage (x)
+    0x09, 0x31, //   usage (y)
+    0x09, 0x32, //   usage (z)
+    0x09, 0x35, //   usage (rz)
+    0x15, 0x00, //   logical minimum (0)
+    0x26, 0xff, 0x00, // logical maximum (255)
+    0x75, 0x08, //   report size (8)
+    0x95, 0x04, //   report count (4)
+    0x81, 0x02, //   input (data, variable, absolute)
+    0x09, 0x39, //   usage (hat switch)
+    0x25, 0x07, //   logical maximum (7)
+    0x35, 0x00, //   physical minimum (0)
+    0x46, 0x3b, 0x01, // physical maximum (315)
+    0x65, 0x14, //   unit (degrees)
+    0x75, 0x04, //   report size (4)
+    0x95, 0x01, //   report count (1)
+    0x81, 0x42, //   input (data, variable, absolute, null state)
+    0x65, 0x00, //   unit (none)
+    0x45, 0x00, //   physical maximum (0)
+    0x05, 0x09, //   usage page (button)
+    0x19, 0x01, //   usage minimum (1)
+    0x29, 0x0e, //   usage maximum (14)
+    0x25, 0x01, //   logical maximum (1)
+    0x75, 0x01, //   report size (1)
+    0x95, 0x0e, //   report count (14)
+    0x81, 0x02, //   input (data, variable, absolute)
+    0x06, 0x00, 0xff, // usage page (vendor defined)
+    0x09, 0x20, //   usage (report counter)
+    0x75, 0x06, //   report size (6)
+    0x95, 0x01, //   report count (1)
+    0x81, 0x02, //   input (data, variable, absolute)
+    0x05, 0x01, //   usage page (generic desktop)
+    0x09, 0x33, //   usage (rx)
+    0x09, 0x34, //   usage (ry)
+    0x26, 0xff, 0x00, // logical maximum (255)
+    0x75, 0x08, //   report size (8)
+    0x95, 0x02, //   report count (2)
+    0x81, 0x02, //   input (data, variable, absolute)
+    0x06, 0x00, 0xff, // usage page (vendor defined)
+    0x09, 0x21, //   usage (remaining report)
+    0x95, 0x36, //   report count (54)
+    0x81, 0x02, //   input (data, variable, absolute)
+    0xc0, // end collection
+];
+/// Cable connected, battery full.
+const POWER_STATUS: u8 = 0x1b;
+/// Marks a touch point as not touching.
+const TOUCH_INACTIVE: u8 = 0x80;
+
+/// Bits 4-7 of byte 5, by position: Square, Cross, Circle, Triangle.
+static FACE_BUTTONS: &[usize] = &[buttons::Y, buttons::B, buttons::A, buttons::X];
+/// Bits 0-7 of byte 6.
+static SHOULDER_BUTTONS: &[usize] = &[
+    buttons::L,
+    buttons::R,
+    buttons::ZL,
+    buttons::ZR,
+    buttons::MINUS,
+    buttons::PLUS,
+    buttons::L_STICK,
+    buttons::R_STICK,
+];
+/// Bits 0-1 of byte 7: PS and touchpad click.
+static SYSTEM_BUTTONS: &[usize] = &[buttons::HOME, buttons::CAPTURE];
+
+/// DualShock 4-style gamepads, one per slot.
+pub struct Ds4Gamepads {
+    gadget: Gadget,
+    devices: Vec<HidDevice>,
+    counters: Vec<u8>,
+}
+
+impl Ds4Gamepads {
+    pub fn new(conf: &ControllerConfig) -> Self {
+        let devices: Vec<HidDevice> = conf
+            .device_paths()
+            .iter()
+            .map(|path| HidDevice::new(path))
+            .collect();
+        Self {
+            gadget: Gadget {
+                name: conf.gadget_name().to_owned(),
+                vendor_id: 0x054c,
+                product_id: 0x05c4,
+                manufacturer: "Sony Computer Entertainment",
+                product: "Wireless Controller",
+                functions: (0..devices.len())
+                    .map(|_| HidFunction {
+                        protocol: 0,
+                        subclass: 0,
+                        report_length: REPORT_LENGTH,
+                        report_desc: REPORT_DESC,
+                    })
+                    .collect(),
+            },
+            counters: vec![0; devices.len()],
+            devices,
+        }
+    }
+}
+
+fn button_bits(state: &NetworkControllerState, map: &[usize]) -> u8 {
+    map.iter()
+        .enumerate()
+        .filter(|&(_, &button)| state.is_pressed(button))
+        .fold(0, |bits, (bit, _)| bits | 1 << bit)
+}
+
+fn report(state: &NetworkControllerState, counter: u8) -> [u8; REPORT_LENGTH] {
+    let mut report = [0u8; REPORT_LENGTH];
+    report[0] = REPORT_ID;
+    report[1] = (state.lh() >> 8) as u8;
+    report[2] = (hid::flip_axis(state.lv()) >> 8) as u8;
+    report[3] = (state.rh() >> 8) as u8;
+    report[4] = (hid::flip_axis(state.rv()) >> 8) as u8;
+    report[5] = hid::hat(state) | button_bits(state, FACE_BUTTONS) << 4;
+    report[6] = button_bits(state, SHOULDER_BUTTONS);
+    report[7] = button_bits(state, SYSTEM_BUTTONS) | (counter & 0x3f) << 2;
+    let [left, right] = state.analog_triggers();
+    report[8] = (left >> 8) as u8;
+    report[9] = (right >> 8) as u8;
+    if let Some(motion) = state.motion() {
+        for (i, value) in motion.gyro.iter().chain(&motion.accel).enumerate() {
+            let offset = 13 + 2 * i;
+            report[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+    report[30] = POWER_STATUS;
+    report[35] = TOUCH_INACTIVE;
+    report[39] = TOUCH_INACTIVE;
+    report
+}
+
+impl Controllers for Ds4Gamepads {
+    fn initialize(&mut self) -> Result<()> {
+        self.gadget.activate()?;
+        sleep(Duration::from_secs(1));
+        for device in &mut self.devices {
+            device.open()?;
+        }
+        Ok(())
+    }
+
+    fn deinitialize(&mut self) -> Result<()> {
+        self.gadget.deactivate()
+    }
+
+    fn slots(&self) -> usize {
+        self.devices.len()
+    }
+
+    fn write_state(&mut self, state: &NetworkControllerState) -> Result<()> {
+        let counter = &mut self.counters[state.player_id()];
+        *counter = counter.wrapping_add(1);
+        let report = report(state, *counter);
+        self.devices[state.player_id()].write_report(&report)
+    }
+}
@@ -0,0 +1,129 @@
+use anyhow::{anyhow, Context, Result};
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+const CONFIGFS: &str = "/sys/kernel/config/usb_gadget";
+const UDC_CLASS: &str = "/sys/class/udc";
+const LANGUAGE: &str = "0x409";
+const CONFIG: &str = "c.1";
+
+/// One HID function of a gadget, which shows up as a `/dev/hidgN` node.
+pub struct HidFunction {
+    /// 1 for a boot keyboard, 2 for a boot mouse, 0 otherwise.
+    pub protocol: u8,
+    /// 1 for a boot protocol device, 0 otherwise.
+    pub subclass: u8,
+    pub report_length: usize,
+    pub report_desc: &'static [u8],
+}
+
+/// A composite USB gadget made of HID functions, created through configfs.
+/// Device nodes are numbered in the order the functions are listed, provided
+/// no other HID gadget is active.
+pub struct Gadget {
+    pub name: String,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub manufacturer: &'static str,
+    pub product: &'static str,
+    pub functions: Vec<HidFunction>,
+}
+
+fn write(path: &Path, value: impl AsRef<[u8]>) -> Result<()> {
+    fs::write(path, value).with_context(|| format!("Couldn't write {}", path.display()))
+}
+
+fn create_dir(path: &Path) -> Result<()> {
+    fs::create_dir(path).with_context(|| format!("Couldn't create {}", path.display()))
+}
+
+impl Gadget {
+    fn root(&self) -> PathBuf {
+        Path::new(CONFIGFS).join(&self.name)
+    }
+
+    /// Creates the gadget, replacing any left behind by a previous run, and
+    /// binds it to the first USB device controller.
+    pub fn activate(&self) -> Result<()> {
+        let root = self.root();
+        if root.exists() {
+            log::info!("Removing existing USB gadget {}", self.name);
+            self.deactivate()?;
+        }
+        log::info!(
+            "Creating USB gadget {} with {} HID functions",
+            self.name,
+            self.functions.len()
+        );
+
+        create_dir(&root)?;
+        write(&root.join("idVendor"), format!("{:#06x}", self.vendor_id))?;
+        write(&root.join("idProduct"), format!("{:#06x}", self.product_id))?;
+        write(&root.join("bcdDevice"), "0x0200")?;
+        write(&root.join("bcdUSB"), "0x0200")?;
+
+        let strings = root.join("strings").join(LANGUAGE);
+        create_dir(&strings)?;
+        write(&strings.join("serialnumber"), "000000000001")?;
+        write(&strings.join("manufacturer"), self.manufacturer)?;
+        write(&strings.join("product"), self.product)?;
+
+        let config = root.join("configs").join(CONFIG);
+        create_dir(&config)?;
+        write(&config.join("MaxPower"), "500")?;
+        let config_strings = config.join("strings").join(LANGUAGE);
+        create_dir(&config_strings)?;
+        write(&config_strings.join("configuration"), self.product)?;
+
+        for (i, function) in self.functions.iter().enumerate() {
+            let name = format!("hid.usb{}", i);
+            let dir = root.join("functions").join(&name);
+            create_dir(&dir)?;
+            write(&dir.join("protocol"), function.protocol.to_string())?;
+            write(&dir.join("subclass"), function.subclass.to_string())?;
+            write(
+                &dir.join("report_length"),
+                function.report_length.to_string(),
+            )?;
+            write(&dir.join("report_desc"), function.report_desc)?;
+            symlink(&dir, config.join(&name))
+                .with_context(|| format!("Couldn't add {} to the gadget", name))?;
+        }
+
+        let udc = fs::read_dir(UDC_CLASS)
+            .with_context(|| format!("Couldn't list {}", UDC_CLASS))?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name())
+            .next()
+            .ok_or_else(|| anyhow!("No USB device controller found in {}", UDC_CLASS))?;
+        write(&root.join("UDC"), udc.to_string_lossy().as_bytes())
+    }
+
+    /// Unbinds the gadget and removes it, in the reverse order of creation
+    /// as configfs requires. Functions are found on disk, so a gadget left
+    /// behind with a different number of slots is removed as well.
+    pub fn deactivate(&self) -> Result<()> {
+        let root = self.root();
+        let config = root.join("configs").join(CONFIG);
+        // unbinding an already unbound gadget fails harmlessly
+        let _ = fs::write(root.join("UDC"), "");
+        let entries = |dir: &Path| -> Vec<PathBuf> {
+            fs::read_dir(dir)
+                .map(|entries| entries.filter_map(|e| e.ok()).map(|e| e.path()).collect())
+                .unwrap_or_default()
+        };
+        for link in entries(&config) {
+            if link.is_symlink() {
+                let _ = fs::remove_file(link);
+            }
+        }
+        for function in entries(&root.join("functions")) {
+            let _ = fs::remove_dir(function);
+        }
+        let _ = fs::remove_dir(config.join("strings").join(LANGUAGE));
+        let _ = fs::remove_dir(&config);
+        let _ = fs::remove_dir(root.join("strings").join(LANGUAGE));
+        fs::remove_dir(&root).with_context(|| format!("Couldn't remove USB gadget {}", self.name))
+    }
+}
@@ -0,0 +1,71 @@
+use super::{buttons, NetworkControllerState};
+
+use anyhow::{anyhow, Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+
+/// A HID function of the USB gadget, written to through its `/dev/hidgN`
+/// node. Reports are written whole; a write blocks until the host has read
+/// the previous report.
+pub struct HidDevice {
+    path: String,
+    file: Option<File>,
+}
+
+impl HidDevice {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_owned(),
+            file: None,
+        }
+    }
+
+    /// Opens the device node, which only exists once the gadget is active.
+    pub fn open(&mut self) -> Result<()> {
+        let file = OpenOptions::new()
+            .write(true)
+            .open(&self.path)
+            .with_context(|| format!("Couldn't open HID device {}", self.path))?;
+        self.file = Some(file);
+        Ok(())
+    }
+
+    pub fn write_report(&mut self, report: &[u8]) -> Result<()> {
+        let path = &self.path;
+        let file = self
+            .file
+            .as_mut()
+            .ok_or_else(|| anyhow!("HID device {} is not open", path))?;
+        file.write_all(report)
+            .with_context(|| format!("Couldn't write to HID device {}", path))
+    }
+}
+
+/// Hat switch value for the D-pad: 0 for up, clockwise to 7 for up-left, and
+/// 8 when centered or when opposite directions cancel out.
+pub fn hat(state: &NetworkControllerState) -> u8 {
+    let vertical = state.get_button(buttons::DOWN) as i8 - state.get_button(buttons::UP) as i8;
+    let horizontal = state.get_button(buttons::RIGHT) as i8 - state.get_button(buttons::LEFT) as i8;
+    match (vertical, horizontal) {
+        (-1, 0) => 0,
+        (-1, 1) => 1,
+        (0, 1) => 2,
+        (1, 1) => 3,
+        (1, 0) => 4,
+        (1, -1) => 5,
+        (0, -1) => 6,
+        (-1, -1) => 7,
+        _ => 8,
+    }
+}
+
+/// Converts an axis centered on `AXIS_CENTER` to one centered on zero.
+pub fn signed_axis(value: u16) -> i16 {
+    (value ^ 0x8000) as i16
+}
+
+/// Flips a vertical axis. Player input has up as the larger value, like the
+/// Pro Controller, where HID gamepads have down as the larger value.
+pub fn flip_axis(value: u16) -> u16 {
+    !value
+}
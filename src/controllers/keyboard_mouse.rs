@@ -0,0 +1,257 @@
+//! A USB keyboard and mouse driven by the first player's gamepad input. The
+//! left stick presses WASD, the right stick moves the mouse, ZR and ZL click
+//! the left and right mouse buttons and the remaining buttons press keys.
+//! The keyboard takes 8-byte boot protocol reports, the mouse takes 4-byte
+//! reports: buttons, then x, y and wheel motion as i8.
+
+use super::gadget::{Gadget, HidFunction};
+use super::hid::{self, HidDevice};
+use super::{Controllers, NetworkControllerState, AXIS_CENTER, NUM_BUTTONS};
+use crate::config::ControllerConfig;
+
+use anyhow::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, sleep};
+use std::time::Duration;
+
+/// What a gamepad button does on the keyboard or mouse.
+#[derive(Debug, Clone, Copy)]
+enum Binding {
+    /// HID keyboard usage ID.
+    Key(u8),
+    /// Bit in the keyboard modifier byte.
+    Modifier(u8),
+    /// Bit in the mouse button byte.
+    Mouse(u8),
+    Unbound,
+}
+
+const KEY_A: u8 = 0x04;
+const KEY_C: u8 = 0x06;
+const KEY_D: u8 = 0x07;
+const KEY_E: u8 = 0x08;
+const KEY_F: u8 = 0x09;
+const KEY_Q: u8 = 0x14;
+const KEY_R: u8 = 0x15;
+const KEY_S: u8 = 0x16;
+const KEY_W: u8 = 0x1a;
+const KEY_1: u8 = 0x1e;
+const KEY_2: u8 = 0x1f;
+const KEY_3: u8 = 0x20;
+const KEY_4: u8 = 0x21;
+const KEY_ESCAPE: u8 = 0x29;
+const KEY_TAB: u8 = 0x2b;
+const KEY_SPACE: u8 = 0x2c;
+const MODIFIER_LEFT_SHIFT: u8 = 0x02;
+const MOUSE_LEFT: u8 = 0x01;
+const MOUSE_RIGHT: u8 = 0x02;
+const MOUSE_MIDDLE: u8 = 0x04;
+
+/// Bindings by player input button index.
+static BINDINGS: [Binding; NUM_BUTTONS] = [
+    Binding::Key(KEY_SPACE),                // A
+    Binding::Key(KEY_C),                    // B
+    Binding::Key(KEY_R),                    // X
+    Binding::Key(KEY_E),                    // Y
+    Binding::Key(KEY_Q),                    // L
+    Binding::Key(KEY_F),                    // R
+    Binding::Mouse(MOUSE_RIGHT),            // ZL
+    Binding::Mouse(MOUSE_LEFT),             // ZR
+    Binding::Key(KEY_TAB),                  // Minus
+    Binding::Key(KEY_ESCAPE),               // Plus
+    Binding::Modifier(MODIFIER_LEFT_SHIFT), // left stick
+    Binding::Mouse(MOUSE_MIDDLE),           // right stick
+    Binding::Key(KEY_1),                    // up
+    Binding::Key(KEY_2),                    // down
+    Binding::Key(KEY_3),                    // left
+    Binding::Key(KEY_4),                    // right
+    Binding::Unbound,                       // Home
+    Binding::Unbound,                       // Capture
+    Binding::Unbound,                       // SL
+    Binding::Unbound,                       // SR
+];
+
+/// Boot protocol keyboard, as in the kernel's HID gadget documentation.
+static KEYBOARD_REPORT_DESC: &[u8] = &[
+    0x05, 0x01, 0x09, 0x06, 0xa1, 0x01, 0x05, 0x07, 0x19, 0xe0, 0x29, 0xe7, 0x15, 0x00, 0x25, 0x01,
+    0x75, 0x01, 0x95, 0x08, 0x81, 0x02, 0x95, 0x01, 0x75, 0x08, 0x81, 0x03, 0x95, 0x05, 0x75, 0x01,
+    0x05, 0x08, 0x19, 0x01, 0x29, 0x05, 0x91, 0x02, 0x95, 0x01, 0x75, 0x03, 0x91, 0x03, 0x95, 0x06,
+    0x75, 0x08, 0x15, 0x00, 0x25, 0x65, 0x05, 0x07, 0x19, 0x00, 0x29, 0x65, 0x81, 0x00, 0xc0,
+];
+
+/// Three-button mouse with relative x, y and wheel.
+static MOUSE_REPORT_DESC: &[u8] = &[
+    0x05, 0x01, 0x09, 0x02, 0xa1, 0x01, 0x09, 0x01, 0xa1, 0x00, 0x05, 0x09, 0x19, 0x01, 0x29, 0x03,
+    0x15, 0x00, 0x25, 0x01, 0x95, 0x03, 0x75, 0x01, 0x81, 0x02, 0x95, 0x01, 0x75, 0x05, 0x81, 0x03,
+    0x05, 0x01, 0x09, 0x30, 0x09, 0x31, 0x09, 0x38, 0x15, 0x81, 0x25, 0x7f, 0x75, 0x08, 0x95, 0x03,
+    0x81, 0x06, 0xc0, 0xc0,
+];
+
+/// Keys held by the boot protocol report at once.
+const MAX_KEYS: usize = 6;
+/// Left stick deflection from center past which a WASD key is held.
+const MOVE_THRESHOLD: u16 = 0x4000;
+/// Right stick deflection ignored as noise, out of 32768.
+const MOUSE_DEADZONE: f32 = 0.1;
+/// Mouse movement per report at full right stick deflection.
+const MOUSE_MAX_SPEED: f32 = 12.0;
+/// Time between mouse reports while the right stick is deflected.
+const MOUSE_INTERVAL: Duration = Duration::from_millis(8);
+
+/// Mouse inputs shared with the thread that sends mouse reports, which keeps
+/// the pointer moving for as long as the stick is held.
+#[derive(Debug, Clone, Copy, Default)]
+struct MouseState {
+    buttons: u8,
+    velocity: (i8, i8),
+}
+
+pub struct KeyboardMouse {
+    gadget: Gadget,
+    keyboard: HidDevice,
+    mouse_path: String,
+    mouse: Arc<Mutex<MouseState>>,
+    running: Arc<AtomicBool>,
+}
+
+impl KeyboardMouse {
+    pub fn new(conf: &ControllerConfig) -> Self {
+        let paths = conf.device_paths();
+        Self {
+            gadget: Gadget {
+                name: conf.gadget_name().to_owned(),
+                vendor_id: 0x1d6b,
+                product_id: 0x0104,
+                manufacturer: "Vulcast",
+                product: "Keyboard and Mouse",
+                functions: vec![
+                    HidFunction {
+                        protocol: 1,
+                        subclass: 1,
+                        report_length: 8,
+                        report_desc: KEYBOARD_REPORT_DESC,
+                    },
+                    HidFunction {
+                        protocol: 2,
+                        subclass: 1,
+                        report_length: 4,
+                        report_desc: MOUSE_REPORT_DESC,
+                    },
+                ],
+            },
+            keyboard: HidDevice::new(&paths[0]),
+            mouse_path: paths[1].clone(),
+            mouse: Arc::new(Mutex::new(MouseState::default())),
+            running: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+fn keyboard_report(state: &NetworkControllerState) -> [u8; 8] {
+    let mut modifiers = 0;
+    let mut keys = Vec::new();
+    for (button, binding) in BINDINGS.iter().enumerate() {
+        if !state.is_pressed(button) {
+            continue;
+        }
+        match *binding {
+            Binding::Key(key) => keys.push(key),
+            Binding::Modifier(modifier) => modifiers |= modifier,
+            Binding::Mouse(_) | Binding::Unbound => (),
+        }
+    }
+    let (lh, lv) = (state.lh(), state.lv());
+    let movement = [
+        (lv > AXIS_CENTER + MOVE_THRESHOLD, KEY_W),
+        (lh < AXIS_CENTER - MOVE_THRESHOLD, KEY_A),
+        (lv < AXIS_CENTER - MOVE_THRESHOLD, KEY_S),
+        (lh > AXIS_CENTER + MOVE_THRESHOLD, KEY_D),
+    ];
+    keys.extend(
+        movement
+            .iter()
+            .filter(|(held, _)| *held)
+            .map(|(_, key)| key),
+    );
+
+    let mut report = [0u8; 8];
+    report[0] = modifiers;
+    for (slot, key) in report[2..].iter_mut().zip(keys.iter().take(MAX_KEYS)) {
+        *slot = *key;
+    }
+    report
+}
+
+fn mouse_speed(axis: u16) -> i8 {
+    let deflection = hid::signed_axis(axis) as f32 / 32768.0;
+    if deflection.abs() < MOUSE_DEADZONE {
+        return 0;
+    }
+    (deflection * MOUSE_MAX_SPEED).round() as i8
+}
+
+fn mouse_state(state: &NetworkControllerState) -> MouseState {
+    let buttons = BINDINGS
+        .iter()
+        .enumerate()
+        .filter(|&(button, _)| state.is_pressed(button))
+        .fold(0, |bits, (_, binding)| match binding {
+            Binding::Mouse(bit) => bits | bit,
+            _ => bits,
+        });
+    MouseState {
+        buttons,
+        velocity: (
+            mouse_speed(state.rh()),
+            mouse_speed(hid::flip_axis(state.rv())),
+        ),
+    }
+}
+
+/// Sends a mouse report whenever the buttons change and every
+/// `MOUSE_INTERVAL` while the pointer is moving.
+fn run_mouse(mut device: HidDevice, mouse: Arc<Mutex<MouseState>>, running: Arc<AtomicBool>) {
+    let mut last_buttons = 0;
+    while running.load(Ordering::Relaxed) {
+        let state = *mouse.lock().unwrap();
+        let (dx, dy) = state.velocity;
+        if state.buttons != last_buttons || dx != 0 || dy != 0 {
+            let report = [state.buttons, dx as u8, dy as u8, 0];
+            if let Err(e) = device.write_report(&report) {
+                log::warn!("Error writing mouse input: {:?}", e);
+            }
+            last_buttons = state.buttons;
+        }
+        sleep(MOUSE_INTERVAL);
+    }
+}
+
+impl Controllers for KeyboardMouse {
+    fn initialize(&mut self) -> Result<()> {
+        self.gadget.activate()?;
+        sleep(Duration::from_secs(1));
+        self.keyboard.open()?;
+        let mut mouse = HidDevice::new(&self.mouse_path);
+        mouse.open()?;
+
+        self.running.store(true, Ordering::Relaxed);
+        let (state, running) = (self.mouse.clone(), self.running.clone());
+        thread::spawn(move || run_mouse(mouse, state, running));
+        Ok(())
+    }
+
+    fn deinitialize(&mut self) -> Result<()> {
+        self.running.store(false, Ordering::Relaxed);
+        self.gadget.deactivate()
+    }
+
+    fn slots(&self) -> usize {
+        1
+    }
+
+    fn write_state(&mut self, state: &NetworkControllerState) -> Result<()> {
+        *self.mouse.lock().unwrap() = mouse_state(state);
+        self.keyboard.write_report(&keyboard_report(state))
+    }
+}
@@ -0,0 +1,356 @@
+//! Turbo and macro playback, applied to a slot's input on its way to the
+//! device. Both depend on time, so the engine is driven by `render` calls
+//! with the current time, both on input and on a regular tick.
+//!
+//! Macros are read from `macros.conf` in the config directory, one section
+//! per macro with its steps: the buttons held, joined by `+`, and for how
+//! many milliseconds. `none` holds nothing:
+//!
+//! ```ini
+//! [skip_dialog]
+//! steps = a:80, none:80, a:80, none:80, down+a:120
+//! ```
+
+use super::remap::button_index;
+use super::{NetworkControllerState, NUM_BUTTONS};
+
+use anyhow::{anyhow, Context, Result};
+use ini::Ini;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy)]
+struct Step {
+    buttons: u32,
+    duration: Duration,
+}
+
+/// A timed sequence of button presses.
+#[derive(Debug)]
+pub struct Macro {
+    name: String,
+    steps: Vec<Step>,
+}
+
+impl Macro {
+    fn parse(name: &str, steps: &str) -> Result<Self> {
+        let steps = steps
+            .split(',')
+            .map(|step| {
+                let (buttons, millis) = step
+                    .trim()
+                    .split_once(':')
+                    .ok_or_else(|| anyhow!("Step {:?} is not buttons:milliseconds", step))?;
+                let mut pressed = 0;
+                if !buttons.trim().eq_ignore_ascii_case("none") {
+                    for button in buttons.split('+') {
+                        match button_index(button.trim())? {
+                            index if index < NUM_BUTTONS => pressed |= 1 << index,
+                            _ => return Err(anyhow!("Cannot press {} in a macro", button)),
+                        }
+                    }
+                }
+                let millis = millis
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("Invalid duration in step {:?}", step))?;
+                Ok(Step {
+                    buttons: pressed,
+                    duration: Duration::from_millis(millis),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            name: name.to_owned(),
+            steps,
+        })
+    }
+
+    /// Buttons held `elapsed` into the macro, or `None` once it is over.
+    fn buttons_at(&self, elapsed: Duration) -> Option<u32> {
+        let mut end = Duration::ZERO;
+        self.steps.iter().find_map(|step| {
+            end += step.duration;
+            Some(step.buttons).filter(|_| elapsed < end)
+        })
+    }
+}
+
+/// The macros available to players, by name.
+#[derive(Debug, Default)]
+pub struct Macros(BTreeMap<String, Arc<Macro>>);
+
+impl Macros {
+    /// Loads every macro in `path`, reporting all bad steps at once. A
+    /// missing file means there are no macros.
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            log::debug!("No macros at {}", path.display());
+            return Ok(Self::default());
+        }
+        let ini = Ini::load_from_file(path)
+            .with_context(|| format!("Couldn't open macros file: {}", path.display()))?;
+
+        let mut macros = BTreeMap::new();
+        let mut problems = Vec::new();
+        for (name, properties) in ini.iter() {
+            let name = match name {
+                Some(name) => name,
+                None => continue,
+            };
+            let parsed = match properties.get("steps") {
+                Some(steps) => Macro::parse(name, steps),
+                None => Err(anyhow!("missing steps")),
+            };
+            match parsed {
+                Ok(parsed) => {
+                    macros.insert(name.to_owned(), Arc::new(parsed));
+                }
+                Err(e) => problems.push(format!("[{}]: {}", name, e)),
+            }
+        }
+        if !problems.is_empty() {
+            return Err(anyhow!(
+                "Invalid macros in {}:\n  {}",
+                path.display(),
+                problems.join("\n  ")
+            ));
+        }
+        log::info!("Loaded {} macros from {}", macros.len(), path.display());
+        Ok(Self(macros))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<Macro>> {
+        self.0.get(name)
+    }
+}
+
+/// Turbo and macro state for one slot.
+#[derive(Debug, Clone, Default)]
+pub struct SlotMacros {
+    /// Buttons with turbo enabled, one bit each.
+    turbo: u32,
+    /// When each turbo button was pressed, so firing starts with a press.
+    pressed_since: [Option<Instant>; NUM_BUTTONS],
+    playing: Option<(Arc<Macro>, Instant)>,
+}
+
+impl SlotMacros {
+    pub fn set_turbo(&mut self, button: usize, enabled: bool) {
+        if enabled {
+            self.turbo |= 1 << button;
+        } else {
+            self.turbo &= !(1 << button);
+            self.pressed_since[button] = None;
+        }
+    }
+
+    pub fn turbo_buttons(&self) -> Vec<usize> {
+        (0..NUM_BUTTONS)
+            .filter(|button| (self.turbo >> button) & 1 == 1)
+            .collect()
+    }
+
+    pub fn play(&mut self, playing: Arc<Macro>, now: Instant) {
+        self.playing = Some((playing, now));
+    }
+
+    pub fn stop(&mut self) {
+        self.playing = None;
+    }
+
+    pub fn playing(&self) -> Option<&str> {
+        self.playing
+            .as_ref()
+            .map(|(playing, _)| playing.name.as_str())
+    }
+
+    /// Whether output can change without new input.
+    pub fn is_active(&self) -> bool {
+        self.turbo != 0 || self.playing.is_some()
+    }
+
+    /// Applies turbo and any playing macro to the player's input. Turbo
+    /// buttons are held for the first half of each `turbo_period` and
+    /// released for the second; macro buttons are held on top of the input.
+    pub fn render(
+        &mut self,
+        input: &NetworkControllerState,
+        turbo_period: Duration,
+        now: Instant,
+    ) -> NetworkControllerState {
+        let mut output = *input;
+        for button in 0..NUM_BUTTONS {
+            if (self.turbo >> button) & 1 == 0 {
+                continue;
+            }
+            if !input.get_button(button) {
+                self.pressed_since[button] = None;
+                continue;
+            }
+            let since = *self.pressed_since[button].get_or_insert(now);
+            let half_periods = now.duration_since(since).as_nanos() * 2 / turbo_period.as_nanos();
+            if half_periods % 2 == 1 {
+                output.buttons &= !(1 << button);
+            }
+        }
+
+        if let Some((playing, started)) = &self.playing {
+            match playing.buttons_at(now.duration_since(*started)) {
+                Some(buttons) => output.buttons |= buttons,
+                None => {
+                    log::debug!("Macro {} finished", playing.name);
+                    self.playing = None;
+                }
+            }
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controllers::buttons;
+
+    const TURBO_PERIOD: Duration = Duration::from_millis(100);
+
+    fn pressing(pressed: &[usize]) -> NetworkControllerState {
+        let mut state = NetworkControllerState::neutral(0);
+        for &button in pressed {
+            state.buttons |= 1 << button;
+        }
+        state
+    }
+
+    fn millis(start: Instant, millis: u64) -> Instant {
+        start + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn parse_steps() {
+        let parsed = Macro::parse("test", "a:80, none:20, down+b:100").unwrap();
+        assert_eq!(
+            parsed.buttons_at(Duration::from_millis(0)),
+            Some(1 << buttons::A)
+        );
+        assert_eq!(parsed.buttons_at(Duration::from_millis(80)), Some(0));
+        assert_eq!(
+            parsed.buttons_at(Duration::from_millis(199)),
+            Some(1 << buttons::DOWN | 1 << buttons::B)
+        );
+        assert_eq!(parsed.buttons_at(Duration::from_millis(200)), None);
+    }
+
+    #[test]
+    fn parse_bad_steps() {
+        let cases = [
+            ("a", "Step \"a\" is not buttons:milliseconds"),
+            ("a:10, b", "Step \" b\" is not buttons:milliseconds"),
+            ("", "Step \"\" is not buttons:milliseconds"),
+            ("a:soon", "Invalid duration in step \"a:soon\""),
+            ("a:-10", "Invalid duration in step \"a:-10\""),
+            ("a:1.5", "Invalid duration in step \"a:1.5\""),
+            ("a:", "Invalid duration in step \"a:\""),
+            ("nope:10", "Unknown button \"nope\""),
+            ("a+:10", "Unknown button \"\""),
+            ("paddle1:10", "Cannot press paddle1 in a macro"),
+        ];
+        for (steps, error) in cases.iter() {
+            let e = Macro::parse("test", steps).unwrap_err().to_string();
+            assert!(e.starts_with(error), "{:?}: {}", steps, e);
+        }
+    }
+
+    #[test]
+    fn turbo_half_period_boundaries() {
+        let cases = [
+            // period, elapsed since the press, held
+            (TURBO_PERIOD, Duration::ZERO, true),
+            (TURBO_PERIOD, Duration::from_micros(49_999), true),
+            (TURBO_PERIOD, Duration::from_millis(50), false),
+            (TURBO_PERIOD, Duration::from_micros(99_999), false),
+            (TURBO_PERIOD, Duration::from_millis(100), true),
+            (TURBO_PERIOD, Duration::from_millis(150), false),
+            (TURBO_PERIOD, Duration::from_millis(1_000), true),
+            // 3Hz, where half a period is not a whole number of nanoseconds
+            (
+                Duration::from_secs(1) / 3,
+                Duration::from_nanos(166_666_666),
+                true,
+            ),
+            (
+                Duration::from_secs(1) / 3,
+                Duration::from_nanos(166_666_667),
+                false,
+            ),
+            (
+                Duration::from_secs(1) / 3,
+                Duration::from_nanos(333_333_333),
+                true,
+            ),
+        ];
+        let start = Instant::now();
+        let held = pressing(&[buttons::A]);
+        for &(period, elapsed, expected) in cases.iter() {
+            let mut slot = SlotMacros::default();
+            slot.set_turbo(buttons::A, true);
+            slot.render(&held, period, start);
+            let output = slot.render(&held, period, start + elapsed);
+            assert_eq!(
+                output.get_button(buttons::A),
+                expected,
+                "{:?} into {:?}",
+                elapsed,
+                period
+            );
+        }
+    }
+
+    #[test]
+    fn turbo_alternates_from_the_press() {
+        let mut slot = SlotMacros::default();
+        slot.set_turbo(buttons::A, true);
+        let start = Instant::now();
+        let held = pressing(&[buttons::A, buttons::B]);
+        let render = |slot: &mut SlotMacros, input: &NetworkControllerState, at: u64| {
+            slot.render(input, TURBO_PERIOD, millis(start, at)).buttons
+        };
+
+        // only the turbo button is let go in the second half of each period
+        assert_eq!(render(&mut slot, &held, 10), held.buttons);
+        assert_eq!(render(&mut slot, &held, 59), held.buttons);
+        assert_eq!(render(&mut slot, &held, 60), 1 << buttons::B);
+        assert_eq!(render(&mut slot, &held, 110), held.buttons);
+
+        // letting go restarts the cycle on the next press
+        assert_eq!(render(&mut slot, &pressing(&[]), 120), 0);
+        assert_eq!(render(&mut slot, &held, 165), held.buttons);
+        assert_eq!(render(&mut slot, &held, 215), 1 << buttons::B);
+
+        slot.set_turbo(buttons::A, false);
+        assert!(!slot.is_active());
+        assert_eq!(render(&mut slot, &held, 215), held.buttons);
+    }
+
+    #[test]
+    fn macro_plays_over_input() {
+        let mut slot = SlotMacros::default();
+        let start = Instant::now();
+        let playing = Macro::parse("combo", "a:100, none:50, x:100").unwrap();
+        slot.play(Arc::new(playing), start);
+        assert_eq!(slot.playing(), Some("combo"));
+        let input = pressing(&[buttons::B]);
+        let mut render = |at: u64| slot.render(&input, TURBO_PERIOD, millis(start, at)).buttons;
+
+        assert_eq!(render(0), 1 << buttons::A | 1 << buttons::B);
+        assert_eq!(render(99), 1 << buttons::A | 1 << buttons::B);
+        assert_eq!(render(100), 1 << buttons::B);
+        assert_eq!(render(150), 1 << buttons::X | 1 << buttons::B);
+        assert_eq!(render(250), 1 << buttons::B);
+        assert_eq!(slot.playing(), None);
+        assert!(!slot.is_active());
+    }
+}
@@ -0,0 +1,154 @@
+use super::{Controllers, NetworkControllerState, NUM_BUTTONS};
+use crate::config::ControllerConfig;
+
+use anyhow::Result;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+/// A single input as the console would see it change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MockInput {
+    Button { index: usize, pressed: bool },
+    Lh(u16),
+    Lv(u16),
+    Rh(u16),
+    Rv(u16),
+    Triggers([u16; 2]),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct MockEvent {
+    /// Time since the mock controllers were created.
+    pub at: Duration,
+    pub player_id: usize,
+    pub input: MockInput,
+}
+
+/// Controllers that only record every button and axis change, with the time
+/// it happened, so the input path can run without a USB gadget.
+pub struct MockControllers {
+    created: Instant,
+    current: Vec<NetworkControllerState>,
+    events: Vec<MockEvent>,
+}
+
+impl MockControllers {
+    pub fn new(conf: &ControllerConfig) -> Self {
+        Self {
+            created: Instant::now(),
+            current: (0..conf.slot_count())
+                .map(NetworkControllerState::neutral)
+                .collect(),
+            events: Vec::new(),
+        }
+    }
+}
+
+impl Controllers for MockControllers {
+    fn initialize(&mut self) -> Result<()> {
+        log::info!("Using mock controllers, input is only logged");
+        Ok(())
+    }
+
+    fn deinitialize(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn slots(&self) -> usize {
+        self.current.len()
+    }
+
+    fn write_state(&mut self, state: &NetworkControllerState) -> Result<()> {
+        let player_id = state.player_id();
+        let previous = self.current[player_id];
+        let mut changes: Vec<MockInput> = (0..NUM_BUTTONS)
+            .filter(|&index| previous.is_pressed(index) != state.is_pressed(index))
+            .map(|index| MockInput::Button {
+                index,
+                pressed: state.is_pressed(index),
+            })
+            .collect();
+        let axes = [
+            (
+                previous.lh(),
+                state.lh(),
+                MockInput::Lh as fn(u16) -> MockInput,
+            ),
+            (previous.lv(), state.lv(), MockInput::Lv),
+            (previous.rh(), state.rh(), MockInput::Rh),
+            (previous.rv(), state.rv(), MockInput::Rv),
+        ];
+        for (old, new, input) in axes.iter() {
+            if old != new {
+                changes.push(input(*new));
+            }
+        }
+        if previous.analog_triggers() != state.analog_triggers() {
+            changes.push(MockInput::Triggers(state.analog_triggers()));
+        }
+        self.current[player_id] = *state;
+
+        let at = self.created.elapsed();
+        for input in changes {
+            log::info!("Mock controller {}: {:?}", player_id, input);
+            self.events.push(MockEvent {
+                at,
+                player_id,
+                input,
+            });
+        }
+        Ok(())
+    }
+
+    fn recorded_events(&self) -> Option<Vec<MockEvent>> {
+        Some(self.events.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controllers::buttons;
+
+    #[test]
+    fn records_only_changes() {
+        let mut controllers = MockControllers::new(&ControllerConfig::default());
+        let mut state = NetworkControllerState::neutral(2);
+        controllers.write_state(&state).unwrap();
+        state.buttons = 1 << buttons::X;
+        state.axes[3] = 0;
+        state.triggers = Some([0, 0xffff]);
+        controllers.write_state(&state).unwrap();
+        controllers.write_state(&state).unwrap();
+
+        let inputs: Vec<_> = controllers
+            .recorded_events()
+            .unwrap()
+            .iter()
+            .map(|event| (event.player_id, event.input))
+            .collect();
+        assert_eq!(
+            inputs,
+            vec![
+                (
+                    2,
+                    MockInput::Button {
+                        index: buttons::X,
+                        pressed: true
+                    }
+                ),
+                // a trigger pulled past halfway also presses its button
+                (
+                    2,
+                    MockInput::Button {
+                        index: buttons::ZR,
+                        pressed: true
+                    }
+                ),
+                (2, MockInput::Rv(0)),
+                (2, MockInput::Triggers([0, 0xffff])),
+            ]
+        );
+    }
+}
@@ -0,0 +1,201 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use vulcast_rtc::types::DataProducerId;
+
+/// What to do with input for a slot another data producer already owns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SlotConflict {
+    /// Drop the input.
+    Reject,
+    /// Move the player to the first free slot.
+    Remap,
+}
+
+#[derive(Debug, Clone)]
+struct Binding {
+    owner: DataProducerId,
+    /// Player id the owner's messages carry for this slot, or `None` if the
+    /// host assigned the slot, which then takes all of the owner's input.
+    player_id: Option<u8>,
+    slot: usize,
+}
+
+/// Binds slots to the data producers driving them, so a client can only
+/// write to slots it claimed first or was assigned.
+#[derive(Debug, Clone)]
+pub struct SlotOwners {
+    bindings: Vec<Binding>,
+    slots: usize,
+    conflict: SlotConflict,
+    /// Slots each owner may claim, so one cannot take them all by cycling
+    /// player ids.
+    per_owner: usize,
+}
+
+impl SlotOwners {
+    pub fn new(slots: usize, conflict: SlotConflict, per_owner: usize) -> Self {
+        Self {
+            bindings: Vec::new(),
+            slots,
+            conflict,
+            per_owner,
+        }
+    }
+
+    pub fn owner(&self, slot: usize) -> Option<&DataProducerId> {
+        self.bindings
+            .iter()
+            .find(|binding| binding.slot == slot)
+            .map(|binding| &binding.owner)
+    }
+
+    /// The slot a message from `owner` for `player_id` goes to, binding a
+    /// free slot on first use.
+    pub fn resolve(&mut self, owner: &DataProducerId, player_id: u8) -> Result<usize> {
+        let bound = self.bindings.iter().find(|binding| {
+            binding.owner == *owner
+                && (binding.player_id.is_none() || binding.player_id == Some(player_id))
+        });
+        if let Some(binding) = bound {
+            return Ok(binding.slot);
+        }
+
+        let claimed = self
+            .bindings
+            .iter()
+            .filter(|binding| binding.owner == *owner)
+            .count();
+        if claimed >= self.per_owner {
+            return Err(anyhow!(
+                "Data producer already has {} controllers, can't claim one for player {}",
+                claimed,
+                player_id
+            ));
+        }
+
+        let requested = player_id as usize;
+        let slot = if requested < self.slots && self.owner(requested).is_none() {
+            requested
+        } else {
+            match self.conflict {
+                SlotConflict::Reject if requested >= self.slots => {
+                    return Err(anyhow!("Invalid controller number: {}", requested))
+                }
+                SlotConflict::Reject => {
+                    return Err(anyhow!(
+                        "Controller {} belongs to another data producer",
+                        requested
+                    ))
+                }
+                SlotConflict::Remap => {
+                    let free = (0..self.slots).find(|&slot| self.owner(slot).is_none());
+                    let slot = free.ok_or_else(|| anyhow!("No free controller slots"))?;
+                    log::info!(
+                        "Data producer {:?} player {} remapped to controller {}",
+                        owner,
+                        player_id,
+                        slot
+                    );
+                    slot
+                }
+            }
+        };
+        log::info!("Controller {} bound to data producer {:?}", slot, owner);
+        self.bindings.push(Binding {
+            owner: owner.clone(),
+            player_id: Some(player_id),
+            slot,
+        });
+        Ok(slot)
+    }
+
+    /// Gives `slot` to `owner` alone, taking it from whoever had it. Returns
+    /// the slots that changed hands, including any `owner` had before.
+    pub fn assign(&mut self, owner: &DataProducerId, slot: usize) -> Vec<usize> {
+        let mut changed = Vec::new();
+        self.bindings.retain(|binding| {
+            let replaced = binding.owner == *owner || binding.slot == slot;
+            if replaced {
+                changed.push(binding.slot);
+            }
+            !replaced
+        });
+        self.bindings.push(Binding {
+            owner: owner.clone(),
+            player_id: None,
+            slot,
+        });
+        changed
+    }
+
+    /// Unbinds every slot `owner` had, returning them.
+    pub fn remove(&mut self, owner: &DataProducerId) -> Vec<usize> {
+        let mut removed = Vec::new();
+        self.bindings.retain(|binding| {
+            if binding.owner == *owner {
+                removed.push(binding.slot);
+            }
+            binding.owner != *owner
+        });
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn producer(id: &str) -> DataProducerId {
+        serde_json::from_value(serde_json::Value::from(id)).unwrap()
+    }
+
+    #[test]
+    fn one_slot_per_producer_by_default() {
+        let mut owners = SlotOwners::new(4, SlotConflict::Remap, 1);
+        let a = producer("a");
+        assert_eq!(owners.resolve(&a, 2).unwrap(), 2);
+        assert_eq!(owners.resolve(&a, 2).unwrap(), 2);
+        // cycling player ids can't take more slots
+        assert!(owners.resolve(&a, 0).is_err());
+        assert!(owners.resolve(&a, 3).is_err());
+        assert_eq!(owners.owner(0), None);
+        assert_eq!(owners.owner(3), None);
+    }
+
+    #[test]
+    fn producers_may_claim_up_to_their_limit() {
+        let mut owners = SlotOwners::new(4, SlotConflict::Remap, 2);
+        let a = producer("a");
+        assert_eq!(owners.resolve(&a, 0).unwrap(), 0);
+        assert_eq!(owners.resolve(&a, 1).unwrap(), 1);
+        assert!(owners.resolve(&a, 2).is_err());
+        assert_eq!(owners.remove(&a), vec![0, 1]);
+        assert_eq!(owners.resolve(&a, 2).unwrap(), 2);
+    }
+
+    #[test]
+    fn conflicts_remap_or_reject() {
+        let (a, b) = (producer("a"), producer("b"));
+        let mut owners = SlotOwners::new(2, SlotConflict::Remap, 1);
+        owners.resolve(&a, 0).unwrap();
+        assert_eq!(owners.resolve(&b, 0).unwrap(), 1);
+        assert!(owners.resolve(&producer("c"), 0).is_err());
+
+        let mut owners = SlotOwners::new(2, SlotConflict::Reject, 1);
+        owners.resolve(&a, 0).unwrap();
+        assert!(owners.resolve(&b, 0).is_err());
+        assert!(owners.resolve(&b, 5).is_err());
+        assert_eq!(owners.resolve(&b, 1).unwrap(), 1);
+    }
+
+    #[test]
+    fn assigned_slot_takes_all_input() {
+        let (a, b) = (producer("a"), producer("b"));
+        let mut owners = SlotOwners::new(4, SlotConflict::Remap, 1);
+        owners.resolve(&a, 1).unwrap();
+        assert_eq!(owners.assign(&b, 1), vec![1]);
+        assert_eq!(owners.resolve(&b, 3).unwrap(), 1);
+        assert_eq!(owners.owner(1), Some(&b));
+    }
+}
@@ -0,0 +1,147 @@
+use super::gadget::{Gadget, HidFunction};
+use super::{buttons, parse_colors, Controllers, NetworkControllerState};
+use crate::config::ControllerConfig;
+
+use anyhow::Result;
+use controller_emulator::controller::ns_procon;
+use controller_emulator::controller::Controller;
+use std::thread::sleep;
+use std::time::Duration;
+
+/// Switch Pro Controllers, one per slot.
+pub struct NsProcons {
+    gadget: Gadget,
+    controllers: Vec<ns_procon::NsProcon>,
+}
+
+static DEFAULT_BODY_COLORS: &[[u8; 3]] = &[
+    [255, 0, 0],
+    [0, 192, 0],
+    [255, 255, 0],
+    [64, 64, 255],
+    [255, 0, 255],
+    [0, 255, 255],
+    [255, 128, 0],
+    [255, 255, 255],
+];
+
+/// HID report descriptor of the Pro Controller.
+static PROCON_REPORT_DESC: &[u8] = &[
+    0x05, 0x01, 0x15, 0x00, 0x09, 0x04, 0xa1, 0x01, 0x85, 0x30, 0x05, 0x01, 0x05, 0x09, 0x19, 0x01,
+    0x29, 0x0a, 0x15, 0x00, 0x25, 0x01, 0x75, 0x01, 0x95, 0x0a, 0x55, 0x00, 0x65, 0x00, 0x81, 0x02,
+    0x05, 0x09, 0x19, 0x0b, 0x29, 0x0e, 0x15, 0x00, 0x25, 0x01, 0x75, 0x01, 0x95, 0x04, 0x81, 0x02,
+    0x75, 0x01, 0x95, 0x02, 0x81, 0x03, 0x0b, 0x01, 0x00, 0x01, 0x00, 0xa1, 0x00, 0x0b, 0x30, 0x00,
+    0x01, 0x00, 0x0b, 0x31, 0x00, 0x01, 0x00, 0x0b, 0x32, 0x00, 0x01, 0x00, 0x0b, 0x35, 0x00, 0x01,
+    0x00, 0x15, 0x00, 0x27, 0xff, 0xff, 0x00, 0x00, 0x75, 0x10, 0x95, 0x04, 0x81, 0x02, 0xc0, 0x0b,
+    0x39, 0x00, 0x01, 0x00, 0x15, 0x00, 0x25, 0x07, 0x35, 0x00, 0x46, 0x3b, 0x01, 0x65, 0x14, 0x75,
+    0x04, 0x95, 0x01, 0x81, 0x02, 0x05, 0x09, 0x19, 0x0f, 0x29, 0x12, 0x15, 0x00, 0x25, 0x01, 0x75,
+    0x01, 0x95, 0x04, 0x81, 0x02, 0x75, 0x08, 0x95, 0x34, 0x81, 0x03, 0x06, 0x00, 0xff, 0x85, 0x21,
+    0x09, 0x01, 0x75, 0x08, 0x95, 0x3f, 0x81, 0x03, 0x85, 0x81, 0x09, 0x02, 0x75, 0x08, 0x95, 0x3f,
+    0x81, 0x03, 0x85, 0x01, 0x09, 0x03, 0x75, 0x08, 0x95, 0x3f, 0x91, 0x83, 0x85, 0x10, 0x09, 0x04,
+    0x75, 0x08, 0x95, 0x3f, 0x91, 0x83, 0x85, 0x80, 0x09, 0x05, 0x75, 0x08, 0x95, 0x3f, 0x91, 0x83,
+    0x85, 0x82, 0x09, 0x06, 0x75, 0x08, 0x95, 0x3f, 0x91, 0x83, 0xc0,
+];
+
+// Buttons `ns_procon::inputs` does not name, indexed like its own: bit i of
+// byte 3 of the standard input report (0x30) is button i, bit i of byte 4 is
+// button 8 + i. Byte 3 holds Y, X, B, A, SR, SL, R, ZR of the right side and
+// byte 4 Minus, Plus, R Stick, L Stick, Home, Capture (dekuNukem's
+// Nintendo_Switch_Reverse_Engineering, bluetooth_hid_notes.md).
+const PROCON_BUTTON_SR: usize = 4;
+const PROCON_BUTTON_SL: usize = 5;
+const PROCON_BUTTON_CAPTURE: usize = 13;
+
+/// Pro Controller inputs by player input button.
+static PROCON_BUTTON_MAP: &[(usize, usize)] = &[
+    (buttons::A, ns_procon::inputs::BUTTON_A),
+    (buttons::B, ns_procon::inputs::BUTTON_B),
+    (buttons::X, ns_procon::inputs::BUTTON_X),
+    (buttons::Y, ns_procon::inputs::BUTTON_Y),
+    (buttons::L, ns_procon::inputs::BUTTON_L),
+    (buttons::R, ns_procon::inputs::BUTTON_R),
+    (buttons::ZL, ns_procon::inputs::BUTTON_ZL),
+    (buttons::ZR, ns_procon::inputs::BUTTON_ZR),
+    (buttons::MINUS, ns_procon::inputs::BUTTON_MINUS),
+    (buttons::PLUS, ns_procon::inputs::BUTTON_PLUS),
+    (buttons::L_STICK, ns_procon::inputs::BUTTON_L_STICK),
+    (buttons::R_STICK, ns_procon::inputs::BUTTON_R_STICK),
+    (buttons::UP, ns_procon::inputs::BUTTON_UP),
+    (buttons::DOWN, ns_procon::inputs::BUTTON_DOWN),
+    (buttons::LEFT, ns_procon::inputs::BUTTON_LEFT),
+    (buttons::RIGHT, ns_procon::inputs::BUTTON_RIGHT),
+    (buttons::HOME, ns_procon::inputs::BUTTON_HOME),
+    (buttons::CAPTURE, PROCON_BUTTON_CAPTURE),
+    (buttons::SL, PROCON_BUTTON_SL),
+    (buttons::SR, PROCON_BUTTON_SR),
+];
+
+impl NsProcons {
+    pub fn new(conf: &ControllerConfig) -> Result<Self> {
+        let slots = conf.slot_count();
+        let body_colors = match &conf.body_colors {
+            Some(colors) => parse_colors(colors)?,
+            None => DEFAULT_BODY_COLORS[..slots].to_vec(),
+        };
+        let controllers = conf
+            .device_paths()
+            .iter()
+            .zip(body_colors)
+            .map(|(path, body)| ns_procon::NsProcon::create(path, body))
+            .collect();
+
+        Ok(Self {
+            gadget: Gadget {
+                name: conf.gadget_name().to_owned(),
+                vendor_id: 0x057e,
+                product_id: 0x2009,
+                manufacturer: "Nintendo Co., Ltd.",
+                product: "Pro Controller",
+                functions: (0..slots)
+                    .map(|_| HidFunction {
+                        protocol: 0,
+                        subclass: 0,
+                        report_length: 64,
+                        report_desc: PROCON_REPORT_DESC,
+                    })
+                    .collect(),
+            },
+            controllers,
+        })
+    }
+}
+
+impl Controllers for NsProcons {
+    fn initialize(&mut self) -> Result<()> {
+        self.gadget.activate()?;
+
+        sleep(Duration::from_secs(1));
+
+        for controller in &mut self.controllers {
+            controller.start_comms()?;
+        }
+        Ok(())
+    }
+
+    fn deinitialize(&mut self) -> Result<()> {
+        self.gadget.deactivate()
+    }
+
+    fn slots(&self) -> usize {
+        self.controllers.len()
+    }
+
+    fn write_state(&mut self, state: &NetworkControllerState) -> Result<()> {
+        let controller = &mut self.controllers[state.player_id()];
+
+        for &(button, mapping) in PROCON_BUTTON_MAP {
+            let _ = controller.set(mapping, state.is_pressed(button), false);
+        }
+
+        let _ = controller.set_axis(ns_procon::inputs::AXIS_LH, state.lh(), false);
+        let _ = controller.set_axis(ns_procon::inputs::AXIS_LV, state.lv(), false);
+        let _ = controller.set_axis(ns_procon::inputs::AXIS_RH, state.rh(), false);
+        let _ = controller.set_axis(ns_procon::inputs::AXIS_RV, state.rv(), false);
+
+        controller.flush_input()
+    }
+}
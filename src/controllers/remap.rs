@@ -0,0 +1,213 @@
+//! Button remapping profiles, read from `profiles.conf` in the config
+//! directory. Each section is a profile and each key a button the player
+//! presses, mapped to the button the console sees or `none`:
+//!
+//! ```ini
+//! [nintendo]
+//! a = b
+//! b = a
+//! home = none
+//! paddle1 = l_stick
+//! ```
+//!
+//! Unmapped buttons keep their position, except the paddles, which do
+//! nothing unless mapped. Analog triggers are never remapped.
+
+use super::{buttons, NUM_BUTTONS};
+
+use anyhow::{anyhow, Context, Result};
+use ini::Ini;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Buttons in the v1 wire format beyond those the devices have.
+const PADDLES: usize = 4;
+const WIRE_BUTTONS: usize = NUM_BUTTONS + PADDLES;
+
+const BUTTON_NAMES: [(&str, usize); WIRE_BUTTONS] = [
+    ("a", buttons::A),
+    ("b", buttons::B),
+    ("x", buttons::X),
+    ("y", buttons::Y),
+    ("l", buttons::L),
+    ("r", buttons::R),
+    ("zl", buttons::ZL),
+    ("zr", buttons::ZR),
+    ("minus", buttons::MINUS),
+    ("plus", buttons::PLUS),
+    ("l_stick", buttons::L_STICK),
+    ("r_stick", buttons::R_STICK),
+    ("up", buttons::UP),
+    ("down", buttons::DOWN),
+    ("left", buttons::LEFT),
+    ("right", buttons::RIGHT),
+    ("home", buttons::HOME),
+    ("capture", buttons::CAPTURE),
+    ("sl", buttons::SL),
+    ("sr", buttons::SR),
+    ("paddle1", NUM_BUTTONS),
+    ("paddle2", NUM_BUTTONS + 1),
+    ("paddle3", NUM_BUTTONS + 2),
+    ("paddle4", NUM_BUTTONS + 3),
+];
+
+pub(super) fn button_index(name: &str) -> Result<usize> {
+    BUTTON_NAMES
+        .iter()
+        .find(|(button, _)| button.eq_ignore_ascii_case(name))
+        .map(|&(_, index)| index)
+        .ok_or_else(|| anyhow!("Unknown button {:?}", name))
+}
+
+/// Where each button on the wire ends up, if anywhere.
+#[derive(Debug, Clone)]
+pub struct RemapProfile {
+    targets: [Option<usize>; WIRE_BUTTONS],
+}
+
+impl Default for RemapProfile {
+    fn default() -> Self {
+        let mut targets = [None; WIRE_BUTTONS];
+        for (button, target) in targets.iter_mut().enumerate().take(NUM_BUTTONS) {
+            *target = Some(button);
+        }
+        Self { targets }
+    }
+}
+
+impl RemapProfile {
+    fn parse<'a>(mappings: impl Iterator<Item = (&'a str, &'a str)>) -> Result<Self> {
+        let mut profile = Self::default();
+        for (from, to) in mappings {
+            let from_index = button_index(from.trim())?;
+            let to = to.trim();
+            profile.targets[from_index] = if to.eq_ignore_ascii_case("none") {
+                None
+            } else {
+                match button_index(to)? {
+                    index if index < NUM_BUTTONS => Some(index),
+                    _ => {
+                        return Err(anyhow!(
+                            "Cannot map to {}, the device has no such button",
+                            to
+                        ))
+                    }
+                }
+            };
+        }
+        Ok(profile)
+    }
+
+    /// Moves each pressed button to its target.
+    pub fn apply(&self, pressed: u32) -> u32 {
+        self.targets
+            .iter()
+            .enumerate()
+            .filter(|&(button, _)| (pressed >> button) & 1 == 1)
+            .filter_map(|(_, target)| *target)
+            .fold(0, |remapped, target| remapped | 1 << target)
+    }
+}
+
+/// The profiles available to players, by name.
+#[derive(Debug, Clone, Default)]
+pub struct RemapProfiles(BTreeMap<String, RemapProfile>);
+
+impl RemapProfiles {
+    /// Loads every profile in `path`, reporting all bad mappings at once.
+    /// A missing file means there are no profiles.
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            log::debug!("No remapping profiles at {}", path.display());
+            return Ok(Self::default());
+        }
+        let ini = Ini::load_from_file(path)
+            .with_context(|| format!("Couldn't open profiles file: {}", path.display()))?;
+
+        let mut profiles = BTreeMap::new();
+        let mut problems = Vec::new();
+        for (name, mappings) in ini.iter() {
+            let name = match name {
+                Some(name) => name,
+                None => continue,
+            };
+            match RemapProfile::parse(mappings.iter()) {
+                Ok(profile) => {
+                    profiles.insert(name.to_owned(), profile);
+                }
+                Err(e) => problems.push(format!("[{}]: {}", name, e)),
+            }
+        }
+        if !problems.is_empty() {
+            return Err(anyhow!(
+                "Invalid remapping profiles in {}:\n  {}",
+                path.display(),
+                problems.join("\n  ")
+            ));
+        }
+        log::info!(
+            "Loaded {} remapping profiles from {}",
+            profiles.len(),
+            path.display()
+        );
+        Ok(Self(profiles))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&RemapProfile> {
+        self.0.get(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Mappings = &'static [(&'static str, &'static str)];
+
+    fn parse(mappings: Mappings) -> Result<RemapProfile> {
+        RemapProfile::parse(mappings.iter().copied())
+    }
+
+    #[test]
+    fn parse_mappings() {
+        let cases: &[(Mappings, u32, u32)] = &[
+            (&[], 1 << buttons::A, 1 << buttons::A),
+            (&[("a", "b"), ("b", "a")], 1 << buttons::A, 1 << buttons::B),
+            (&[(" A ", " ZR ")], 1 << buttons::A, 1 << buttons::ZR),
+            (&[("home", "none")], 1 << buttons::HOME | 1, 1),
+            (&[("home", "NONE")], 1 << buttons::HOME, 0),
+            (
+                &[("paddle2", "l_stick")],
+                1 << (NUM_BUTTONS + 1),
+                1 << buttons::L_STICK,
+            ),
+            // unmapped paddles do nothing
+            (&[], 1 << NUM_BUTTONS, 0),
+            // two buttons onto one
+            (
+                &[("x", "a")],
+                1 << buttons::X | 1 << buttons::A,
+                1 << buttons::A,
+            ),
+        ];
+        for (mappings, pressed, expected) in cases {
+            let profile = parse(mappings).unwrap();
+            assert_eq!(profile.apply(*pressed), *expected, "{:?}", mappings);
+        }
+    }
+
+    #[test]
+    fn parse_bad_mappings() {
+        let cases: &[(Mappings, &str)] = &[
+            (&[("turbo", "a")], "Unknown button \"turbo\""),
+            (&[("a", "jump")], "Unknown button \"jump\""),
+            (&[("a", "")], "Unknown button \"\""),
+            (&[("a", "paddle1")], "Cannot map to paddle1"),
+            (&[("b", "a"), ("a", "paddle4")], "Cannot map to paddle4"),
+        ];
+        for (mappings, error) in cases {
+            let e = parse(mappings).unwrap_err().to_string();
+            assert!(e.starts_with(error), "{:?}: {}", mappings, e);
+        }
+    }
+}
@@ -0,0 +1,293 @@
+//! Analog stick processing, applied to every slot's input before it reaches
+//! the devices: center calibration, inner and outer deadzones, an
+//! anti-deadzone and a response curve.
+//!
+//! The math works on each stick's deflection scaled to -1.0..=1.0. Inner
+//! deadzone, outer deadzone and anti-deadzone are fractions of full
+//! deflection.
+
+use super::AXIS_CENTER;
+use crate::config::SticksConfig;
+
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// Samples averaged to find a slot's stick centers.
+const CALIBRATION_SAMPLES: u32 = 32;
+/// Longest calibration runs, as clients that only send input when it
+/// changes may never send all the samples. Whatever arrived by then is
+/// averaged.
+const CALIBRATION_TIME: Duration = Duration::from_millis(500);
+
+/// How the deadzones are measured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeadzoneShape {
+    /// By distance from the center, keeping the stick's direction.
+    Radial,
+    /// Along each axis on its own.
+    Axial,
+}
+
+fn to_unit(value: u16) -> f32 {
+    (value as f32 - AXIS_CENTER as f32)
+        / if value < AXIS_CENTER {
+            32768.0
+        } else {
+            32767.0
+        }
+}
+
+fn from_unit(value: f32) -> u16 {
+    let value = value.clamp(-1.0, 1.0);
+    let scale = if value < 0.0 { 32768.0 } else { 32767.0 };
+    (AXIS_CENTER as f32 + value * scale).round() as u16
+}
+
+impl SticksConfig {
+    /// Maps a deflection between 0 and 1 through the deadzones and curve.
+    fn response(&self, deflection: f32) -> f32 {
+        if deflection <= self.inner_deadzone {
+            return 0.0;
+        }
+        let live_range = 1.0 - self.inner_deadzone - self.outer_deadzone;
+        let scaled = ((deflection - self.inner_deadzone) / live_range).min(1.0);
+        self.anti_deadzone + (1.0 - self.anti_deadzone) * scaled.powf(self.curve)
+    }
+
+    /// Processes one stick's x and y deflection.
+    fn apply(&self, x: f32, y: f32) -> (f32, f32) {
+        match self.deadzone_shape {
+            DeadzoneShape::Radial => {
+                let magnitude = x.hypot(y);
+                if magnitude == 0.0 {
+                    return (0.0, 0.0);
+                }
+                // corners of a square gate reach past 1
+                let scale = self.response(magnitude.min(1.0)) / magnitude;
+                (x * scale, y * scale)
+            }
+            DeadzoneShape::Axial => (
+                self.response(x.abs()).copysign(x),
+                self.response(y.abs()).copysign(y),
+            ),
+        }
+    }
+}
+
+/// Samples of a calibration in progress.
+#[derive(Debug, Clone)]
+struct Calibration {
+    started: Instant,
+    sum: [f32; 4],
+    samples: u32,
+}
+
+/// Stick processing state for one slot.
+#[derive(Debug, Clone, Default)]
+pub struct SlotSticks {
+    /// Resting deflection of each axis, subtracted from every input.
+    center: [f32; 4],
+    calibration: Option<Calibration>,
+}
+
+impl SlotSticks {
+    /// Starts averaging the inputs over the next moments, during which the
+    /// sticks read as centered, to find where they rest.
+    pub fn calibrate(&mut self, now: Instant) {
+        self.calibration = Some(Calibration {
+            started: now,
+            sum: [0.0; 4],
+            samples: 0,
+        });
+    }
+
+    pub fn is_calibrating(&self) -> bool {
+        self.calibration.is_some()
+    }
+
+    /// Processes raw `lh, lv, rh, rv` values received at `now`.
+    pub fn apply(&mut self, settings: &SticksConfig, axes: [u16; 4], now: Instant) -> [u16; 4] {
+        // untouched unless configured, so square gates still reach corners
+        if self.calibration.is_none()
+            && self.center == [0.0; 4]
+            && *settings == SticksConfig::default()
+        {
+            return axes;
+        }
+        let raw = axes.map(to_unit);
+        if let Some(mut calibration) = self.calibration.take() {
+            // input after the calibration time is the player moving again
+            let timed_out = now.duration_since(calibration.started) >= CALIBRATION_TIME;
+            if !timed_out {
+                for (sum, value) in calibration.sum.iter_mut().zip(raw.iter()) {
+                    *sum += value;
+                }
+                calibration.samples += 1;
+            }
+            if !timed_out && calibration.samples < CALIBRATION_SAMPLES {
+                self.calibration = Some(calibration);
+                return [AXIS_CENTER; 4];
+            }
+            if calibration.samples > 0 {
+                self.center = calibration.sum.map(|sum| sum / calibration.samples as f32);
+                log::info!(
+                    "Calibrated stick centers to {:?} from {} samples",
+                    self.center,
+                    calibration.samples
+                );
+            } else {
+                log::warn!("No input to calibrate stick centers from, keeping them");
+            }
+            if !timed_out {
+                return [AXIS_CENTER; 4];
+            }
+        }
+
+        let [lh, lv, rh, rv] = [0, 1, 2, 3].map(|i| raw[i] - self.center[i]);
+        let (lh, lv) = settings.apply(lh, lv);
+        let (rh, rv) = settings.apply(rh, rv);
+        [lh, lv, rh, rv].map(from_unit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(inner_deadzone: f32, outer_deadzone: f32, curve: f32) -> SticksConfig {
+        SticksConfig {
+            inner_deadzone,
+            outer_deadzone,
+            curve,
+            ..SticksConfig::default()
+        }
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-4,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    fn assert_axes_near(actual: [u16; 4], expected: [u16; 4]) {
+        for (actual, expected) in actual.iter().zip(expected.iter()) {
+            assert!(
+                (*actual as i32 - *expected as i32).abs() <= 1,
+                "{:?} is not {:?}",
+                actual,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn inner_deadzone() {
+        let settings = settings(0.2, 0.0, 1.0);
+        assert_eq!(settings.response(0.1), 0.0);
+        assert_eq!(settings.response(0.2), 0.0);
+        assert_close(settings.response(0.6), 0.5);
+        assert_close(settings.response(1.0), 1.0);
+    }
+
+    #[test]
+    fn outer_deadzone() {
+        let settings = settings(0.0, 0.2, 1.0);
+        assert_close(settings.response(0.4), 0.5);
+        assert_close(settings.response(0.8), 1.0);
+        assert_close(settings.response(0.9), 1.0);
+    }
+
+    #[test]
+    fn anti_deadzone() {
+        let settings = SticksConfig {
+            anti_deadzone: 0.3,
+            ..settings(0.1, 0.0, 1.0)
+        };
+        assert_eq!(settings.response(0.1), 0.0);
+        assert_close(settings.response(0.1001), 0.3);
+        assert_close(settings.response(1.0), 1.0);
+    }
+
+    #[test]
+    fn response_curve() {
+        let settings = settings(0.0, 0.0, 2.0);
+        assert_close(settings.response(0.5), 0.25);
+        assert_close(settings.response(1.0), 1.0);
+    }
+
+    #[test]
+    fn deadzone_shapes() {
+        // each axis inside the deadzone, but not the stick's distance
+        let axes = [from_unit(0.08), from_unit(-0.08), AXIS_CENTER, AXIS_CENTER];
+        let radial = settings(0.1, 0.0, 1.0);
+        let output = SlotSticks::default().apply(&radial, axes, Instant::now());
+        assert!(output[0] > AXIS_CENTER && output[1] < AXIS_CENTER);
+
+        let axial = SticksConfig {
+            deadzone_shape: DeadzoneShape::Axial,
+            ..radial
+        };
+        let output = SlotSticks::default().apply(&axial, axes, Instant::now());
+        assert_eq!(output, [AXIS_CENTER; 4]);
+    }
+
+    #[test]
+    fn default_settings_leave_axes_alone() {
+        let axes = [0, 0x1234, 0xffff, 0x8001];
+        let output = SlotSticks::default().apply(&SticksConfig::default(), axes, Instant::now());
+        assert_eq!(output, axes);
+    }
+
+    #[test]
+    fn calibration_completes_after_samples() {
+        let settings = SticksConfig::default();
+        let resting = [0x8000 + 3000, 0x8000 - 2000, AXIS_CENTER, 0x8000 + 100];
+        let mut sticks = SlotSticks::default();
+        let start = Instant::now();
+        sticks.calibrate(start);
+        for _ in 0..CALIBRATION_SAMPLES {
+            assert!(sticks.is_calibrating());
+            assert_eq!(sticks.apply(&settings, resting, start), [AXIS_CENTER; 4]);
+        }
+        assert!(!sticks.is_calibrating());
+        assert_axes_near(sticks.apply(&settings, resting, start), [AXIS_CENTER; 4]);
+    }
+
+    #[test]
+    fn calibration_completes_after_time() {
+        let settings = SticksConfig::default();
+        let resting = [0x8000 + 3000, AXIS_CENTER, AXIS_CENTER, AXIS_CENTER];
+        let mut sticks = SlotSticks::default();
+        let start = Instant::now();
+        sticks.calibrate(start);
+        let sampled = start + Duration::from_millis(10);
+        sticks.apply(&settings, resting, sampled);
+        sticks.apply(&settings, resting, sampled);
+        assert!(sticks.is_calibrating());
+
+        // the first input after the calibration time is applied, not sampled
+        let moved = [0x8000 + 4000, AXIS_CENTER, AXIS_CENTER, AXIS_CENTER];
+        let output = sticks.apply(&settings, moved, start + CALIBRATION_TIME);
+        assert!(!sticks.is_calibrating());
+        assert_axes_near(
+            output,
+            [0x8000 + 1000, AXIS_CENTER, AXIS_CENTER, AXIS_CENTER],
+        );
+    }
+
+    #[test]
+    fn calibration_without_samples_keeps_centers() {
+        let settings = SticksConfig::default();
+        let mut sticks = SlotSticks::default();
+        let start = Instant::now();
+        sticks.calibrate(start);
+        let axes = [0xffff, AXIS_CENTER, AXIS_CENTER, 0];
+        let output = sticks.apply(&settings, axes, start + 2 * CALIBRATION_TIME);
+        assert!(!sticks.is_calibrating());
+        assert_eq!(output, axes);
+    }
+}
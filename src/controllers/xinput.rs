@@ -0,0 +1,167 @@
+//! Generic gamepads with the Xbox controller layout. Each player's gadget HID
+//! function takes 13-byte input reports:
+//!
+//! | offset | size | field                                                  |
+//! |--------|------|--------------------------------------------------------|
+//! | 0      | 2    | A B X Y LB RB Back Start LS RS Guide, bit 0 first      |
+//! | 2      | 1    | hat switch, see `hid::hat`                             |
+//! | 3      | 8    | LX LY RX RY as little endian i16, Y down               |
+//! | 11     | 2    | LT RT as u8                                            |
+
+use super::gadget::{Gadget, HidFunction};
+use super::hid::{self, HidDevice};
+use super::{buttons, Controllers, NetworkControllerState};
+use crate::config::ControllerConfig;
+
+use anyhow::Result;
+use std::thread::sleep;
+use std::time::Duration;
+
+const REPORT_LENGTH: usize = 13;
+
+/// Gamepad with 11 buttons, a hat switch, four 16-bit sticks and two 8-bit
+/// triggers, matching the report layout above.
+static REPORT_DESC: &[u8] = &[
+    0x05, 0x01, // usage page (generic desktop)
+    0x09, 0x05, // usage (game pad)
+    0xa1, 0x01, // collection (application)
+    0x05, 0x09, //   usage page (button)
+    0x19, 0x01, //   usage minimum (1)
+    0x29, 0x0b, //   usage maximum (11)
+    0x15, 0x00, //   logical minimum (0)
+    0x25, 0x01, //   logical maximum (1)
+    0x75, 0x01, //   report size (1)
+    0x95, 0x0b, //   report count (11)
+    0x81, 0x02, //   input (data, variable, absolute)
+    0x75, 0x05, //   report size (5)
+    0x95, 0x01, //   report count (1)
+    0x81, 0x03, //   input (constant)
+    0x05, 0x01, //   usage page (generic desktop)
+    0x09, 0x39, //   usage (hat switch)
+    0x25, 0x07, //   logical maximum (7)
+    0x35, 0x00, //   physical minimum (0)
+    0x46, 0x3b, 0x01, // physical maximum (315)
+    0x65, 0x14, //   unit (degrees)
+    0x75, 0x04, //   report size (4)
+    0x95, 0x01, //   report count (1)
+    0x81, 0x42, //   input (data, variable, absolute, null state)
+    0x65, 0x00, //   unit (none)
+    0x45, 0x00, //   physical maximum (0)
+    0x81, 0x03, //   input (constant)
+    0x09, 0x30, //   usage (x)
+    0x09, 0x31, //   usage (y)
+    0x09, 0x33, //   usage (rx)
+    0x09, 0x34, //   usage (ry)
+    0x16, 0x00, 0x80, // logical minimum (-32768)
+    0x26, 0xff, 0x7f, // logical maximum (32767)
+    0x75, 0x10, //   report size (16)
+    0x95, 0x04, //   report count (4)
+    0x81, 0x02, //   input (data, variable, absolute)
+    0x09, 0x32, //   usage (z)
+    0x09, 0x35, //   usage (rz)
+    0x15, 0x00, //   logical minimum (0)
+    0x26, 0xff, 0x00, // logical maximum (255)
+    0x75, 0x08, //   report size (8)
+    0x95, 0x02, //   report count (2)
+    0x81, 0x02, //   input (data, variable, absolute)
+    0xc0, // end collection
+];
+
+/// Report button bits in order, by player input button. Face buttons are
+/// mapped by position rather than by label, so the Switch B button at the
+/// bottom becomes the Xbox A button.
+static BUTTON_MAP: &[usize] = &[
+    buttons::B,
+    buttons::A,
+    buttons::Y,
+    buttons::X,
+    buttons::L,
+    buttons::R,
+    buttons::MINUS,
+    buttons::PLUS,
+    buttons::L_STICK,
+    buttons::R_STICK,
+    buttons::HOME,
+];
+
+/// Xbox-style HID gamepads, one per slot.
+pub struct XInputGamepads {
+    gadget: Gadget,
+    devices: Vec<HidDevice>,
+}
+
+impl XInputGamepads {
+    pub fn new(conf: &ControllerConfig) -> Self {
+        let devices: Vec<HidDevice> = conf
+            .device_paths()
+            .iter()
+            .map(|path| HidDevice::new(path))
+            .collect();
+        Self {
+            gadget: Gadget {
+                name: conf.gadget_name().to_owned(),
+                vendor_id: 0x1d6b,
+                product_id: 0x0104,
+                manufacturer: "Vulcast",
+                product: "Gamepad",
+                functions: (0..devices.len())
+                    .map(|_| HidFunction {
+                        protocol: 0,
+                        subclass: 0,
+                        report_length: REPORT_LENGTH,
+                        report_desc: REPORT_DESC,
+                    })
+                    .collect(),
+            },
+            devices,
+        }
+    }
+}
+
+fn report(state: &NetworkControllerState) -> [u8; REPORT_LENGTH] {
+    let mut report = [0u8; REPORT_LENGTH];
+    let pressed = BUTTON_MAP
+        .iter()
+        .enumerate()
+        .filter(|&(_, &button)| state.is_pressed(button))
+        .fold(0u16, |bits, (bit, _)| bits | 1 << bit);
+    report[0..2].copy_from_slice(&pressed.to_le_bytes());
+    report[2] = hid::hat(state);
+    let axes = [
+        state.lh(),
+        hid::flip_axis(state.lv()),
+        state.rh(),
+        hid::flip_axis(state.rv()),
+    ];
+    for (i, &axis) in axes.iter().enumerate() {
+        let offset = 3 + 2 * i;
+        report[offset..offset + 2].copy_from_slice(&hid::signed_axis(axis).to_le_bytes());
+    }
+    let [left, right] = state.analog_triggers();
+    report[11] = (left >> 8) as u8;
+    report[12] = (right >> 8) as u8;
+    report
+}
+
+impl Controllers for XInputGamepads {
+    fn initialize(&mut self) -> Result<()> {
+        self.gadget.activate()?;
+        sleep(Duration::from_secs(1));
+        for device in &mut self.devices {
+            device.open()?;
+        }
+        Ok(())
+    }
+
+    fn deinitialize(&mut self) -> Result<()> {
+        self.gadget.deactivate()
+    }
+
+    fn slots(&self) -> usize {
+        self.devices.len()
+    }
+
+    fn write_state(&mut self, state: &NetworkControllerState) -> Result<()> {
+        self.devices[state.player_id()].write_report(&report(state))
+    }
+}
@@ -1,122 +1,424 @@
-use crate::graphql::signal_query;
+//! External media pipelines sending plain RTP, run as child processes and
+//! kept alive by a supervisor.
+
+mod adaptation;
+mod forwarder;
+mod pipelines;
+mod rtcp;
+
+pub use adaptation::Encoding;
+pub use pipelines::Pipeline;
+
+use crate::backoff::Backoff;
+use crate::config::MediaConfig;
+use crate::media::CaptureDevice;
+use adaptation::Adaptation;
+use forwarder::Forwarder;
 
 use anyhow::Result;
-use std::process::{Child, Command, Stdio};
-// use std::thread;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::process::Child;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use vulcast_rtc::types::{MediaKind, RtpParameters};
+
+const RESTART_DELAY_MIN: Duration = Duration::from_secs(1);
+const RESTART_DELAY_MAX: Duration = Duration::from_secs(30);
+/// A pipeline that ran this long before failing restarts with the shortest
+/// delay again.
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+/// How often the child is checked for having exited or stalled.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// what every pipeline sends, and what its producers are registered with
+const AUDIO_SSRC: u32 = 11111111;
+const AUDIO_PAYLOAD_TYPE: u8 = 101;
+const VIDEO_SSRC: u32 = 22222222;
+const VIDEO_PAYLOAD_TYPE: u8 = 102;
 
-pub trait Streamer {
+pub trait Streamer: Send + Sync {
+    /// Pipeline name, used in logs.
+    fn name(&self) -> &str;
+    /// Starts capturing from `capture` and sending audio to `audio` and
+    /// video to `video`.
     fn stream(
         &self,
-        audio_transport_options: signal_query::PlainTransportOptions,
-        video_transport_options: signal_query::PlainTransportOptions,
+        capture: &CaptureDevice,
+        audio: SocketAddr,
+        video: SocketAddr,
+        encoding: &Encoding,
     ) -> Result<Child>;
+    /// Whether the pipeline prints progress lines at all. Pipelines that do
+    /// not are only restarted when they exit, never for stalling.
+    fn reports_progress(&self) -> bool;
+    /// Whether an output line shows the pipeline is making progress.
+    fn is_progress(&self, line: &str) -> bool;
+    /// Whether an output line reports an error, to show as the last error.
+    fn is_error(&self, line: &str) -> bool;
+    /// The line to write to the pipeline's stdin to switch to `encoding`'s
+    /// video bitrate while it runs, or `None` if it must restart instead.
+    fn bitrate_command(&self, encoding: &Encoding) -> Option<String>;
+    /// The video codec, as a mediasoup `RtpCodecParameters` object.
+    fn video_codec(&self) -> Value;
 }
 
-pub fn _stream_persistent(
-    _streamer: &dyn Streamer,
-    _audio_transport_options: signal_query::PlainTransportOptions,
-    _video_transport_options: signal_query::PlainTransportOptions,
-) {
+/// Parameters of the RTP stream a pipeline sends for `kind`, to produce it
+/// on the relay with.
+pub fn rtp_parameters(streamer: &dyn Streamer, kind: &MediaKind) -> Result<RtpParameters> {
+    let (codec, ssrc) = match kind {
+        MediaKind::Audio => (
+            json!({
+                "mimeType": "audio/opus",
+                "payloadType": AUDIO_PAYLOAD_TYPE,
+                "clockRate": 48000,
+                "channels": 2,
+            }),
+            AUDIO_SSRC,
+        ),
+        MediaKind::Video => (streamer.video_codec(), VIDEO_SSRC),
+    };
+    Ok(serde_json::from_value(json!({
+        "codecs": [codec],
+        "encodings": [{ "ssrc": ssrc }],
+    }))?)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PipelineState {
+    /// Spawned, but no progress reported yet.
+    Starting,
+    Running,
+    /// Exited or stalled, and waiting for the backoff delay to restart.
+    WaitingToRestart,
+    /// Waiting for the capture device to be plugged in.
+    Paused,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PipelineStatus {
+    pub pipeline: String,
+    pub state: PipelineState,
+    pub pid: Option<u32>,
+    /// Failures so far; restarts to adapt the encoding do not count.
+    pub restarts: u32,
+    pub encoding: Encoding,
+    /// Why the pipeline last failed, or its last error output.
+    pub last_error: Option<String>,
 }
 
-pub struct FfmpegStreamer {}
+/// Kills the child when dropped, so a cancelled supervisor never leaves a
+/// pipeline running.
+struct ChildGuard(Child);
 
-impl FfmpegStreamer {
-    pub fn _new() -> Self {
-        Self {}
+impl Drop for ChildGuard {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
     }
 }
 
-impl Streamer for FfmpegStreamer {
-    fn stream(
-        &self,
-        audio_transport_options: signal_query::PlainTransportOptions,
-        video_transport_options: signal_query::PlainTransportOptions,
-    ) -> Result<Child> {
-        let tee_fmt = format!(
-            "[select=a:f=rtp:ssrc=11111111:payload_type=101]rtp://{}:{}|\
-             [select=v:f=rtp:ssrc=22222222:payload_type=102]rtp://{}:{}",
-            audio_transport_options.tuple.local_ip(),
-            audio_transport_options.tuple.local_port(),
-            video_transport_options.tuple.local_ip(),
-            video_transport_options.tuple.local_port()
-        );
-
-        #[rustfmt::skip]
-    let ffmpeg = Command::new("ffmpeg")
-        // .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .args(&[
-            "-fflags", "+genpts",
-            "-f", "v4l2", "-thread_queue_size", "1024", "-input_format", "mjpeg",
-            "-video_size", "640x480", "-framerate", "30", "-i", "/dev/video0",
-            "-f", "alsa", "-thread_queue_size", "1024", "-ac", "2", "-i", "hw:CARD=MS2109,DEV=0",
-            // "-re", "-stream_loop", "-1", "-i", "esker.mp4",
-            // "-c:v", "copy",
-            "-c:v", "libx264", "-preset", "ultrafast", "-maxrate", "300k", "-bufsize", "300k", "-g", "60", "-tune", "zerolatency",
-            // "-c:v", "h264_v4l2m2m", "-g", "48",
-            // "-c:v", "h264_omx", "-profile:v", "baseline", "-g", "48",
-            "-bsf:v", "h264_mp4toannexb,dump_extra",
-            "-pix_fmt", "yuv420p",
-            "-map", "0:v:0",
-            "-map", "1:a:0",
-            // "-map", "0:a:0",
-            "-c:a", "libopus", "-ab", "256k", "-ac", "2", "-ar", "48000",
-            "-f", "tee", &tee_fmt,
-        ])
-        .spawn()?;
-        Ok(ffmpeg)
+/// Sends every line read from `reader` until it closes. ffmpeg ends
+/// progress lines with `\r`, so either line ending splits lines.
+fn forward_lines(mut reader: impl Read, lines: mpsc::UnboundedSender<String>) {
+    let mut buf = [0; 4096];
+    let mut line = Vec::new();
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        for &byte in &buf[..n] {
+            if byte != b'\n' && byte != b'\r' {
+                line.push(byte);
+            } else if !line.is_empty() {
+                let _ = lines.send(String::from_utf8_lossy(&line).into_owned());
+                line.clear();
+            }
+        }
     }
 }
 
-pub struct GStreamer {}
+/// Writes each command to the pipeline's stdin, one per line, until either
+/// side closes.
+fn forward_commands(mut writer: impl Write, mut commands: mpsc::UnboundedReceiver<String>) {
+    while let Some(command) = commands.blocking_recv() {
+        if writeln!(writer, "{}", command)
+            .and_then(|_| writer.flush())
+            .is_err()
+        {
+            break;
+        }
+    }
+}
 
-impl GStreamer {
-    pub fn new() -> Self {
-        Self {}
+/// Why a run of a pipeline ended.
+enum RunEnd {
+    Failed(String),
+    /// The encoding changed in a way the running pipeline can't follow, so
+    /// it must restart with it.
+    Reconfigured,
+    /// The capture device was unplugged or replaced.
+    CaptureChanged,
+}
+
+/// Adapts the encoding to the relay's reports on the video stream, as sent
+/// through `video`.
+async fn adapt(
+    mut adaptation: Adaptation,
+    mut reports: mpsc::UnboundedReceiver<rtcp::ReceptionReport>,
+    video: Arc<Forwarder>,
+    encoding: watch::Sender<Encoding>,
+) {
+    let mut last = (Instant::now(), video.sent_bytes());
+    while let Some(report) = reports.recv().await {
+        if report.ssrc != VIDEO_SSRC {
+            continue;
+        }
+        log::trace!("{:?}", report);
+        let now = Instant::now();
+        let sent_bytes = video.sent_bytes();
+        let elapsed = now.duration_since(last.0).as_secs_f64();
+        let sent_kbps = if elapsed > 0.0 {
+            (sent_bytes - last.1) as f64 * 8.0 / 1000.0 / elapsed
+        } else {
+            0.0
+        };
+        last = (now, sent_bytes);
+        if let Some(adapted) = adaptation.on_report(report.fraction_lost, sent_kbps, now) {
+            let _ = encoding.send(adapted);
+        }
     }
 }
 
-impl Streamer for GStreamer {
-    fn stream(
-        &self,
-        audio_transport_options: signal_query::PlainTransportOptions,
-        video_transport_options: signal_query::PlainTransportOptions,
-    ) -> Result<Child> {
-        let video_ip = format!("host={}", video_transport_options.tuple.local_ip());
-        let video_port = format!("port={}", video_transport_options.tuple.local_port());
-        let audio_ip = format!("host={}", audio_transport_options.tuple.local_ip());
-        let audio_port = format!("port={}", audio_transport_options.tuple.local_port());
-        #[rustfmt::skip]
-    let gstreamer = Command::new("gst-launch-1.0")
-        .args(&[
-            "rtpbin", "name=rtpbin",
-            "v4l2src", "device=/dev/video0",
-            "!", "image/jpeg,framerate=30/1,width=720,height=480",
-            "!", "queue", "max-size-time=40000000", "leaky=upstream",
-            "!", "decodebin",
-            "!", "videoconvert",
-            "!", "vp8enc", "end-usage=cbr", "keyframe-max-dist=60", "target-bitrate=100000", "deadline=1", "cpu-used=4",
-            "!", "rtpvp8pay", "pt=102", "ssrc=22222222", "picture-id-mode=2",
-            // "!", "omxh264enc", "control-rate=constant", "target-bitrate=5000000",
-            //      "b-frames=0", "interval-intraframes=60", "inline-header=true",
-            // "!", "video/x-h264,profile=baseline",
-            // "!", "rtph264pay", "pt=102", "ssrc=22222222",
-            "!", "rtpbin.send_rtp_sink_0",
-            "rtpbin.send_rtp_src_0", "!", "udpsink", &video_ip, &video_port, "bind-port=50000",
-            "rtpbin.send_rtcp_src_0", "!", "udpsink", &video_ip, &video_port, "bind-port=50000", "sync=false", "async=false",
-            "alsasrc", "device=\"hw:CARD=MS2109,DEV=0\"",
-            "!", "queue", "max-size-time=40000000", "leaky=upstream",
-            "!", "decodebin",
-            "!", "audioresample",
-            "!", "audioconvert",
-            "!", "opusenc", "inband-fec=true",
-            "!", "rtpopuspay", "pt=101", "ssrc=11111111",
-            "!", "rtpbin.send_rtp_sink_1",
-            "rtpbin.send_rtp_src_1", "!", "udpsink", &audio_ip, &audio_port, "bind-port=50001",
-            "rtpbin.send_rtcp_src_1", "!", "udpsink", &audio_ip, &audio_port, "bind-port=50001", "sync=false", "async=false"
-        ])
-        .spawn()?;
-        Ok(gstreamer)
+/// Runs a pipeline sending to the relay at `audio` and `video`, and
+/// restarts it with backoff whenever it exits or, if it reports progress,
+/// goes `[media] pipeline_stall_timeout_ms` without doing so. Media is
+/// forwarded through the firmware, which adapts the encoding to the relay's
+/// reports if `[media] adaptive_bitrate` is set, without a restart where only
+/// the bitrate changes and the pipeline can take it on stdin. While the
+/// capture device is unplugged the pipeline is stopped, and it restarts once
+/// the device is back. The pipeline is killed when the supervisor is dropped.
+///
+/// Native mode has no supervisor: its encoder runs inside libwebrtc, which
+/// does its own congestion control.
+pub struct Supervisor {
+    status: watch::Receiver<PipelineStatus>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Supervisor {
+    pub async fn start(
+        streamer: Arc<dyn Streamer>,
+        mut capture: watch::Receiver<Option<CaptureDevice>>,
+        audio: SocketAddr,
+        video: SocketAddr,
+        conf: &MediaConfig,
+    ) -> Result<Self> {
+        let (reports_tx, reports) = mpsc::unbounded_channel();
+        let audio =
+            Arc::new(Forwarder::bind(conf.rtp_bind_port + 1, audio, reports_tx.clone()).await?);
+        let video = Arc::new(Forwarder::bind(conf.rtp_bind_port, video, reports_tx).await?);
+
+        let initial = Encoding::new(conf);
+        let (encoding_tx, mut encoding) = watch::channel(initial);
+        let mut tasks = Vec::new();
+        if conf.adaptive_bitrate {
+            let runtime_bitrate = streamer.bitrate_command(&initial).is_some();
+            let adaptation = Adaptation::new(conf, runtime_bitrate, Instant::now());
+            tasks.push(tokio::spawn(adapt(
+                adaptation,
+                reports,
+                video.clone(),
+                encoding_tx,
+            )));
+        }
+
+        let (status_tx, status) = watch::channel(PipelineStatus {
+            pipeline: streamer.name().to_owned(),
+            state: PipelineState::Starting,
+            pid: None,
+            restarts: 0,
+            encoding: initial,
+            last_error: None,
+        });
+        let stall_timeout = Duration::from_millis(conf.pipeline_stall_timeout_ms);
+        tasks.push(tokio::spawn(async move {
+            let mut backoff = Backoff::new(RESTART_DELAY_MIN, RESTART_DELAY_MAX);
+            loop {
+                let device = capture.borrow_and_update().clone();
+                let device = match device {
+                    Some(device) => device,
+                    None => {
+                        log::info!(
+                            "Pipeline {} paused until the capture device is back",
+                            streamer.name()
+                        );
+                        status_tx.send_modify(|status| {
+                            status.state = PipelineState::Paused;
+                            status.pid = None;
+                        });
+                        if capture.changed().await.is_err() {
+                            return;
+                        }
+                        continue;
+                    }
+                };
+                let started = Instant::now();
+                let end = supervise_once(
+                    streamer.as_ref(),
+                    &device,
+                    &mut capture,
+                    (audio.local_addr(), video.local_addr()),
+                    &mut encoding,
+                    stall_timeout,
+                    &status_tx,
+                )
+                .await;
+                let failure = match end {
+                    RunEnd::Reconfigured => {
+                        log::info!(
+                            "Restarting pipeline {} with {}",
+                            streamer.name(),
+                            *encoding.borrow()
+                        );
+                        continue;
+                    }
+                    RunEnd::CaptureChanged => continue,
+                    RunEnd::Failed(failure) => failure,
+                };
+                log::warn!("Pipeline {} {}, restarting", streamer.name(), failure);
+                if started.elapsed() > STABLE_AFTER {
+                    backoff.reset();
+                }
+                status_tx.send_modify(|status| {
+                    status.state = PipelineState::WaitingToRestart;
+                    status.pid = None;
+                    status.restarts += 1;
+                    status.last_error = Some(failure);
+                });
+                tokio::time::sleep(backoff.next_delay()).await;
+            }
+        }));
+        Ok(Self { status, tasks })
+    }
+
+    pub fn status(&self) -> watch::Receiver<PipelineStatus> {
+        self.status.clone()
+    }
+}
+
+impl Drop for Supervisor {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// Runs the pipeline capturing from `device` and sending to
+/// `(audio, video)` until it fails, or the capture device or the encoding
+/// changes.
+async fn supervise_once(
+    streamer: &dyn Streamer,
+    device: &CaptureDevice,
+    capture: &mut watch::Receiver<Option<CaptureDevice>>,
+    (audio, video): (SocketAddr, SocketAddr),
+    encoding: &mut watch::Receiver<Encoding>,
+    stall_timeout: Duration,
+    status: &watch::Sender<PipelineStatus>,
+) -> RunEnd {
+    let name = streamer.name();
+    let mut current = *encoding.borrow_and_update();
+    status.send_modify(|status| {
+        status.state = PipelineState::Starting;
+        status.encoding = current;
+    });
+    let mut child = match streamer.stream(device, audio, video, &current) {
+        Ok(child) => ChildGuard(child),
+        Err(e) => return RunEnd::Failed(format!("failed to start: {}", e)),
+    };
+    log::info!("Started pipeline {} with pid {}", name, child.0.id());
+    status.send_modify(|status| status.pid = Some(child.0.id()));
+    let stall_timeout = if streamer.reports_progress() {
+        Some(stall_timeout)
+    } else {
+        // with nothing to go by, running is all that can be known
+        status.send_modify(|status| status.state = PipelineState::Running);
+        None
+    };
+
+    let (lines_tx, mut lines) = mpsc::unbounded_channel();
+    if let Some(stdout) = child.0.stdout.take() {
+        let lines_tx = lines_tx.clone();
+        std::thread::spawn(move || forward_lines(stdout, lines_tx));
+    }
+    if let Some(stderr) = child.0.stderr.take() {
+        std::thread::spawn(move || forward_lines(stderr, lines_tx));
+    }
+    let commands = child.0.stdin.take().map(|stdin| {
+        let (commands_tx, commands) = mpsc::unbounded_channel();
+        std::thread::spawn(move || forward_commands(stdin, commands));
+        commands_tx
+    });
+
+    let mut last_progress = Instant::now();
+    let mut poll = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        tokio::select! {
+            Some(line) = lines.recv() => {
+                if streamer.is_progress(&line) {
+                    log::trace!("{}: {}", name, line);
+                    last_progress = Instant::now();
+                    if status.borrow().state != PipelineState::Running {
+                        log::info!("Pipeline {} is running", name);
+                        status.send_modify(|status| status.state = PipelineState::Running);
+                    }
+                } else if streamer.is_error(&line) {
+                    log::warn!("{}: {}", name, line);
+                    status.send_modify(|status| status.last_error = Some(line));
+                } else {
+                    log::debug!("{}: {}", name, line);
+                }
+            }
+            Ok(()) = encoding.changed() => {
+                let adapted = *encoding.borrow_and_update();
+                let same_picture = Encoding {
+                    video_bitrate_kbps: current.video_bitrate_kbps,
+                    ..adapted
+                } == current;
+                let sent = match (&commands, streamer.bitrate_command(&adapted)) {
+                    (Some(commands), Some(command)) if same_picture => {
+                        commands.send(command).is_ok()
+                    }
+                    _ => false,
+                };
+                if !sent {
+                    return RunEnd::Reconfigured;
+                }
+                log::info!("Pipeline {} switched to {}", name, adapted);
+                current = adapted;
+                status.send_modify(|status| status.encoding = adapted);
+            }
+            Ok(()) = capture.changed() => return RunEnd::CaptureChanged,
+            _ = poll.tick() => {
+                let failure = match child.0.try_wait() {
+                    Ok(Some(exit)) => format!("exited with {}", exit),
+                    Ok(None) => match stall_timeout {
+                        Some(timeout) if last_progress.elapsed() > timeout => {
+                            format!("made no progress for {:?}", timeout)
+                        }
+                        _ => continue,
+                    },
+                    Err(e) => format!("could not be waited on: {}", e),
+                };
+                return RunEnd::Failed(failure);
+            }
+        }
     }
 }
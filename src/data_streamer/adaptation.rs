@@ -0,0 +1,371 @@
+//! Adapts a pipeline's video encoding to the loss the relay reports. The
+//! target bitrate follows a loss-based controller like WebRTC's: it drops in
+//! proportion to loss when loss is high and creeps up while there is almost
+//! none. When the target gets too low for the resolution and frame rate,
+//! those step down, and back up once there is room again.
+//!
+//! Pipelines take their settings on the command line, so changes restart
+//! the pipeline unless it can take a new bitrate on stdin; changes are only
+//! made when they are large enough to be worth it, and not too often.
+//!
+//! Only external pipelines are adapted. In native mode the encoder runs
+//! inside libwebrtc, which has its own congestion control, and the
+//! broadcaster exposes neither its transport statistics nor its bitrate.
+
+use crate::config::MediaConfig;
+
+use serde::Serialize;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Loss above which the target is lowered.
+const HIGH_LOSS: f64 = 0.10;
+/// Loss below which the target is raised.
+const LOW_LOSS: f64 = 0.02;
+/// Growth of the target per report without loss.
+const INCREASE_FACTOR: f64 = 1.05;
+/// The target is never raised beyond this multiple of what the pipeline
+/// actually sends, so a quiet scene cannot run it up to the maximum.
+const MAX_OVERSHOOT: f64 = 1.5;
+/// Bits per pixel below which the resolution and frame rate step down.
+const MIN_BITS_PER_PIXEL: f64 = 0.04;
+/// Margin above `MIN_BITS_PER_PIXEL` needed to step back up, so the steps
+/// do not flap.
+const STEP_UP_HEADROOM: f64 = 1.5;
+/// Smallest relative bitrate change worth restarting the pipeline for.
+const MIN_CHANGE: f64 = 0.2;
+/// Smallest relative bitrate change made in a running pipeline.
+const RUNTIME_MIN_CHANGE: f64 = 0.05;
+const DECREASE_INTERVAL: Duration = Duration::from_secs(3);
+const INCREASE_INTERVAL: Duration = Duration::from_secs(15);
+/// Reports are ignored for this long after a restart, which the relay sees
+/// as loss.
+const SETTLE_TIME: Duration = Duration::from_secs(3);
+
+/// Video settings a pipeline is started with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Encoding {
+    pub video_bitrate_kbps: u32,
+    pub width: u32,
+    pub height: u32,
+    pub framerate: u32,
+}
+
+impl Encoding {
+    /// As configured, before any adaptation.
+    pub fn new(conf: &MediaConfig) -> Self {
+        Self {
+            video_bitrate_kbps: conf.video_bitrate_kbps,
+            width: conf.video_width,
+            height: conf.video_height,
+            framerate: conf.video_framerate,
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}x{}@{} at {} kbps",
+            self.width, self.height, self.framerate, self.video_bitrate_kbps
+        )
+    }
+}
+
+/// A resolution and frame rate the video can be stepped down to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Step {
+    width: u32,
+    height: u32,
+    framerate: u32,
+}
+
+impl Step {
+    fn bits_per_pixel(&self, kbps: f64) -> f64 {
+        kbps * 1000.0 / (self.width as f64 * self.height as f64 * self.framerate as f64)
+    }
+}
+
+/// Full size, three quarters and half size, then half size at half the frame
+/// rate, leaving out those below the configured minimums.
+fn steps(conf: &MediaConfig) -> Vec<Step> {
+    let scaled = |numerator: u32, denominator: u32, framerate: u32| Step {
+        // encoders want even dimensions
+        width: conf.video_width * numerator / denominator / 2 * 2,
+        height: conf.video_height * numerator / denominator / 2 * 2,
+        framerate,
+    };
+    let full = scaled(1, 1, conf.video_framerate);
+    let smaller = [
+        scaled(3, 4, conf.video_framerate),
+        scaled(1, 2, conf.video_framerate),
+        scaled(1, 2, conf.video_framerate / 2),
+    ];
+    let mut steps = vec![full];
+    steps.extend(smaller.iter().filter(|step| {
+        step.height >= conf.min_video_height && step.framerate >= conf.min_video_framerate
+    }));
+    steps
+}
+
+pub struct Adaptation {
+    min_kbps: f64,
+    max_kbps: f64,
+    steps: Vec<Step>,
+    /// Whether the pipeline takes bitrate changes without restarting.
+    runtime_bitrate: bool,
+    target_kbps: f64,
+    /// Index into `steps` of the applied resolution and frame rate.
+    step: usize,
+    applied: Encoding,
+    applied_at: Instant,
+    /// Reports before this are ignored, as the pipeline restarted.
+    settled_at: Instant,
+}
+
+impl Adaptation {
+    pub fn new(conf: &MediaConfig, runtime_bitrate: bool, now: Instant) -> Self {
+        Self {
+            min_kbps: conf.min_video_bitrate_kbps as f64,
+            max_kbps: conf.max_video_bitrate_kbps as f64,
+            steps: steps(conf),
+            runtime_bitrate,
+            target_kbps: conf.video_bitrate_kbps as f64,
+            step: 0,
+            applied: Encoding::new(conf),
+            applied_at: now,
+            settled_at: now + SETTLE_TIME,
+        }
+    }
+
+    /// Updates the target from the loss the relay reported on the video
+    /// stream, while the pipeline sends `sent_kbps`. Returns the encoding
+    /// to switch the pipeline to, if it should change.
+    pub fn on_report(
+        &mut self,
+        fraction_lost: f64,
+        sent_kbps: f64,
+        now: Instant,
+    ) -> Option<Encoding> {
+        if now < self.settled_at {
+            return None;
+        }
+        let previous = self.target_kbps;
+        if fraction_lost > HIGH_LOSS {
+            self.target_kbps *= 1.0 - 0.5 * fraction_lost;
+        } else if fraction_lost < LOW_LOSS {
+            let ceiling = self.target_kbps.max(sent_kbps * MAX_OVERSHOOT);
+            self.target_kbps = (self.target_kbps * INCREASE_FACTOR).min(ceiling);
+        }
+        self.target_kbps = self.target_kbps.clamp(self.min_kbps, self.max_kbps);
+        if (self.target_kbps - previous).abs() >= 1.0 {
+            log::info!(
+                "Video loss {:.1}% while sending {:.0} kbps, target {:.0} -> {:.0} kbps",
+                fraction_lost * 100.0,
+                sent_kbps,
+                previous,
+                self.target_kbps
+            );
+        } else {
+            log::debug!(
+                "Video loss {:.1}% while sending {:.0} kbps, holding target at {:.0} kbps",
+                fraction_lost * 100.0,
+                sent_kbps,
+                self.target_kbps
+            );
+        }
+
+        // one step at a time, as the target moves gradually
+        let mut step = self.step;
+        if step + 1 < self.steps.len()
+            && self.steps[step].bits_per_pixel(self.target_kbps) < MIN_BITS_PER_PIXEL
+        {
+            step += 1;
+        } else if step > 0
+            && self.steps[step - 1].bits_per_pixel(self.target_kbps)
+                >= MIN_BITS_PER_PIXEL * STEP_UP_HEADROOM
+        {
+            step -= 1;
+        }
+        let candidate = Encoding {
+            video_bitrate_kbps: (self.target_kbps / 10.0).round() as u32 * 10,
+            width: self.steps[step].width,
+            height: self.steps[step].height,
+            framerate: self.steps[step].framerate,
+        };
+
+        let applied_kbps = self.applied.video_bitrate_kbps as f64;
+        let change = (candidate.video_bitrate_kbps as f64 - applied_kbps).abs() / applied_kbps;
+        let restarts = step != self.step || !self.runtime_bitrate;
+        let min_change = if restarts {
+            MIN_CHANGE
+        } else {
+            RUNTIME_MIN_CHANGE
+        };
+        if step == self.step && change < min_change {
+            return None;
+        }
+        let interval = if candidate.video_bitrate_kbps < self.applied.video_bitrate_kbps {
+            DECREASE_INTERVAL
+        } else {
+            INCREASE_INTERVAL
+        };
+        if now.duration_since(self.applied_at) < interval {
+            log::debug!("Waiting to adapt video to {}", candidate);
+            return None;
+        }
+        log::info!("Adapting video from {} to {}", self.applied, candidate);
+        self.step = step;
+        self.applied = candidate;
+        self.applied_at = now;
+        if restarts {
+            self.settled_at = now + SETTLE_TIME;
+        }
+        Some(candidate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conf(video_bitrate_kbps: u32) -> MediaConfig {
+        MediaConfig {
+            video_bitrate_kbps,
+            adaptive_bitrate: true,
+            ..MediaConfig::default()
+        }
+    }
+
+    fn secs(start: Instant, secs: u64) -> Instant {
+        start + Duration::from_secs(secs)
+    }
+
+    fn encoding(video_bitrate_kbps: u32, width: u32, height: u32) -> Option<Encoding> {
+        Some(Encoding {
+            video_bitrate_kbps,
+            width,
+            height,
+            framerate: 30,
+        })
+    }
+
+    #[test]
+    fn steps_leave_out_those_below_the_minimums() {
+        let sizes = |conf: &MediaConfig| -> Vec<_> {
+            steps(conf)
+                .iter()
+                .map(|step| (step.width, step.height, step.framerate))
+                .collect()
+        };
+        assert_eq!(
+            sizes(&conf(2000)),
+            vec![
+                (1280, 720, 30),
+                (960, 540, 30),
+                (640, 360, 30),
+                (640, 360, 15)
+            ]
+        );
+        let conf = MediaConfig {
+            min_video_height: 540,
+            ..conf(2000)
+        };
+        assert_eq!(sizes(&conf), vec![(1280, 720, 30), (960, 540, 30)]);
+    }
+
+    #[test]
+    fn waits_for_the_pipeline_to_settle() {
+        let start = Instant::now();
+        let mut adaptation = Adaptation::new(&conf(2000), false, start);
+        assert_eq!(adaptation.on_report(0.5, 2000.0, secs(start, 1)), None);
+        // the ignored report did not move the target either
+        assert_eq!(
+            adaptation.on_report(0.5, 2000.0, secs(start, 3)),
+            encoding(1500, 1280, 720)
+        );
+    }
+
+    #[test]
+    fn holds_between_the_loss_thresholds() {
+        let start = Instant::now();
+        let mut adaptation = Adaptation::new(&conf(2000), false, start);
+        for at in 3..30 {
+            assert_eq!(adaptation.on_report(0.05, 5000.0, secs(start, at)), None);
+        }
+        assert_eq!(
+            adaptation.on_report(0.5, 2000.0, secs(start, 30)),
+            encoding(1500, 1280, 720)
+        );
+    }
+
+    #[test]
+    fn small_decreases_add_up_before_a_restart() {
+        let start = Instant::now();
+        let mut adaptation = Adaptation::new(&conf(2000), false, start);
+        // 1850 and 1711 kbps are within 20% of 2000
+        assert_eq!(adaptation.on_report(0.15, 2000.0, secs(start, 3)), None);
+        assert_eq!(adaptation.on_report(0.15, 2000.0, secs(start, 4)), None);
+        assert_eq!(
+            adaptation.on_report(0.15, 2000.0, secs(start, 5)),
+            encoding(1580, 1280, 720)
+        );
+    }
+
+    #[test]
+    fn increases_are_capped_and_slow() {
+        let start = Instant::now();
+        let mut quiet = Adaptation::new(&conf(1500), false, start);
+        let mut busy = Adaptation::new(&conf(1500), false, start);
+        for at in 3..15 {
+            // a pipeline sending well under its target holds it there
+            assert_eq!(quiet.on_report(0.0, 1000.0, secs(start, at)), None);
+            assert_eq!(busy.on_report(0.0, 2000.0, secs(start, at)), None);
+        }
+        assert_eq!(quiet.on_report(0.0, 1000.0, secs(start, 15)), None);
+        // 13 increases of 5% would be 2828 kbps, over the maximum
+        assert_eq!(
+            busy.on_report(0.0, 2000.0, secs(start, 15)),
+            encoding(2500, 1280, 720)
+        );
+    }
+
+    #[test]
+    fn steps_down_and_back_up() {
+        let start = Instant::now();
+        let mut adaptation = Adaptation::new(&conf(1200), false, start);
+        // 900 kbps is too little for 1280x720@30
+        assert_eq!(
+            adaptation.on_report(0.5, 1200.0, secs(start, 3)),
+            encoding(900, 960, 540)
+        );
+        // growing 5% a report, 1697 kbps after 13 is enough to step up, and
+        // 15 seconds have passed since the last change
+        for at in 6..18 {
+            assert_eq!(adaptation.on_report(0.0, 5000.0, secs(start, at)), None);
+        }
+        assert_eq!(
+            adaptation.on_report(0.0, 5000.0, secs(start, 18)),
+            encoding(1700, 1280, 720)
+        );
+    }
+
+    #[test]
+    fn runtime_bitrate_changes_are_smaller_and_keep_counting() {
+        let start = Instant::now();
+        let mut adaptation = Adaptation::new(&conf(2000), true, start);
+        assert_eq!(
+            adaptation.on_report(0.15, 2000.0, secs(start, 3)),
+            encoding(1850, 1280, 720)
+        );
+        // without a restart, reports right after a change still move the
+        // target
+        assert_eq!(adaptation.on_report(0.15, 2000.0, secs(start, 4)), None);
+        assert_eq!(adaptation.on_report(0.15, 2000.0, secs(start, 5)), None);
+        assert_eq!(
+            adaptation.on_report(0.15, 2000.0, secs(start, 6)),
+            encoding(1460, 1280, 720)
+        );
+    }
+}
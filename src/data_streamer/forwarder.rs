@@ -0,0 +1,102 @@
+//! Relays a pipeline's RTP to the relay from a socket the firmware owns, so
+//! that the relay's RTCP comes back to the firmware rather than to the
+//! pipeline.
+
+use super::rtcp::{self, ReceptionReport};
+
+use anyhow::{Context, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// Largest UDP payload.
+const MAX_DATAGRAM: usize = 65535;
+
+pub struct Forwarder {
+    local_addr: SocketAddr,
+    sent_bytes: Arc<AtomicU64>,
+    task: JoinHandle<()>,
+}
+
+impl Forwarder {
+    /// Forwards whatever arrives on a new loopback socket to `remote`,
+    /// sending from `bind_port`, and passes on the reception reports sent
+    /// back.
+    pub async fn bind(
+        bind_port: u16,
+        remote: SocketAddr,
+        reports: mpsc::UnboundedSender<ReceptionReport>,
+    ) -> Result<Self> {
+        let local = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let unspecified: IpAddr = match remote {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        let upstream = UdpSocket::bind((unspecified, bind_port))
+            .await
+            .with_context(|| format!("Couldn't bind RTP port {}", bind_port))?;
+        upstream.connect(remote).await?;
+        let local_addr = local.local_addr()?;
+        log::debug!(
+            "Forwarding RTP from {} to {} through port {}",
+            local_addr,
+            remote,
+            bind_port
+        );
+
+        let sent_bytes = Arc::new(AtomicU64::new(0));
+        let task = tokio::spawn({
+            let sent_bytes = sent_bytes.clone();
+            async move {
+                let mut outgoing = vec![0; MAX_DATAGRAM];
+                let mut incoming = vec![0; MAX_DATAGRAM];
+                loop {
+                    tokio::select! {
+                        received = local.recv(&mut outgoing) => match received {
+                            Ok(n) => match upstream.send(&outgoing[..n]).await {
+                                Ok(_) => {
+                                    sent_bytes.fetch_add(n as u64, Ordering::Relaxed);
+                                }
+                                // e.g. refused while the relay sets up
+                                Err(e) => log::trace!("Couldn't forward to {}: {:?}", remote, e),
+                            },
+                            Err(e) => log::debug!("Error receiving from the pipeline: {:?}", e),
+                        },
+                        received = upstream.recv(&mut incoming) => match received {
+                            Ok(n) => {
+                                for report in rtcp::reception_reports(&incoming[..n]) {
+                                    let _ = reports.send(report);
+                                }
+                            }
+                            Err(e) => log::trace!("Error receiving from {}: {:?}", remote, e),
+                        },
+                    }
+                }
+            }
+        });
+        Ok(Self {
+            local_addr,
+            sent_bytes,
+            task,
+        })
+    }
+
+    /// Where the pipeline should send to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Bytes forwarded so far.
+    pub fn sent_bytes(&self) -> u64 {
+        self.sent_bytes.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Forwarder {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
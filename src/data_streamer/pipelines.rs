@@ -0,0 +1,680 @@
+//! Pipeline definitions: the program to run and its arguments, with
+//! placeholders filled in from the config and the relay's transports.
+//!
+//! Besides the built-in presets, pipelines can be defined in
+//! `pipelines.conf` in the config directory, one section per pipeline.
+//! Arguments are split on whitespace, and a line ending in `\` continues on
+//! the next:
+//!
+//! ```ini
+//! [usb_h264]
+//! program = ffmpeg
+//! ; h264 or vp8, as sent to the relay
+//! codec = h264
+//! ; start of the lines the program prints while media flows, if any; without
+//! ; it the pipeline is only restarted when it exits
+//! progress = frame=
+//! ; start of the lines the program prints on errors
+//! error = Error
+//! args = -f v4l2 -input_format h264 -i {video_device} \
+//!        -f alsa -ac 2 -i {audio_device} \
+//!        -map 0:v:0 -map 1:a:0 -c:v copy -c:a libopus -ar 48000 \
+//!        -f tee [select=a:f=rtp:ssrc={audio_ssrc}:payload_type={audio_pt}]rtp://{audio_ip}:{audio_port}|[select=v:f=rtp:ssrc={video_ssrc}:payload_type={video_pt}]rtp://{video_ip}:{video_port}
+//! ```
+//!
+//! `{{` and `}}` stand for literal braces. A section named after a preset
+//! replaces it.
+//!
+//! A program that can change its video bitrate while running is sent a line
+//! on stdin for each change, set as e.g.
+//! `bitrate_command = bitrate {video_bitrate_kbps}`, where only the bitrate
+//! placeholders may be used. Other pipelines restart for every change.
+
+use super::{Encoding, Streamer, AUDIO_PAYLOAD_TYPE, AUDIO_SSRC, VIDEO_PAYLOAD_TYPE, VIDEO_SSRC};
+use crate::config::MediaConfig;
+use crate::media::CaptureDevice;
+
+use anyhow::{anyhow, Context, Result};
+use ini::Ini;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+
+/// Every placeholder a pipeline's arguments may use.
+const PLACEHOLDERS: &[&str] = &[
+    "video_device",
+    "audio_device",
+    "width",
+    "height",
+    "framerate",
+    "encode_width",
+    "encode_height",
+    "encode_framerate",
+    "video_bitrate",
+    "video_bitrate_kbps",
+    "audio_ip",
+    "audio_port",
+    "audio_ssrc",
+    "audio_pt",
+    "video_ip",
+    "video_port",
+    "video_ssrc",
+    "video_pt",
+];
+
+/// Placeholders `bitrate_command` may use.
+const BITRATE_PLACEHOLDERS: &[&str] = &["video_bitrate", "video_bitrate_kbps"];
+
+/// Built-in pipelines: name, program, codec, progress line prefix, error
+/// line prefix and arguments. The H.264 ones encode constrained baseline.
+/// Neither ffmpeg nor gst-launch can change the bitrate while running, so
+/// the presets have no `bitrate_command`.
+const PRESETS: &[(&str, &str, &str, &str, &str, &str)] = &[
+    (
+        "x264",
+        "ffmpeg",
+        "h264",
+        "frame=",
+        "Error",
+        r"
+        -fflags +genpts
+        -f v4l2 -thread_queue_size 1024 -input_format mjpeg
+        -video_size {width}x{height} -framerate {framerate} -i {video_device}
+        -f alsa -thread_queue_size 1024 -ac 2 -i {audio_device}
+        -vf scale={encode_width}:{encode_height},fps={encode_framerate}
+        -c:v libx264 -profile:v baseline -preset ultrafast -tune zerolatency
+        -b:v {video_bitrate_kbps}k -maxrate {video_bitrate_kbps}k -bufsize {video_bitrate_kbps}k -g 60
+        -bsf:v h264_mp4toannexb,dump_extra -pix_fmt yuv420p
+        -map 0:v:0 -map 1:a:0
+        -c:a libopus -ab 256k -ac 2 -ar 48000
+        -f tee [select=a:f=rtp:ssrc={audio_ssrc}:payload_type={audio_pt}]rtp://{audio_ip}:{audio_port}|[select=v:f=rtp:ssrc={video_ssrc}:payload_type={video_pt}]rtp://{video_ip}:{video_port}
+        ",
+    ),
+    (
+        "v4l2m2m",
+        "ffmpeg",
+        "h264",
+        "frame=",
+        "Error",
+        r"
+        -fflags +genpts
+        -f v4l2 -thread_queue_size 1024 -input_format mjpeg
+        -video_size {width}x{height} -framerate {framerate} -i {video_device}
+        -f alsa -thread_queue_size 1024 -ac 2 -i {audio_device}
+        -vf scale={encode_width}:{encode_height},fps={encode_framerate}
+        -c:v h264_v4l2m2m -b:v {video_bitrate_kbps}k -g 60
+        -bsf:v h264_mp4toannexb,dump_extra -pix_fmt yuv420p
+        -map 0:v:0 -map 1:a:0
+        -c:a libopus -ab 256k -ac 2 -ar 48000
+        -f tee [select=a:f=rtp:ssrc={audio_ssrc}:payload_type={audio_pt}]rtp://{audio_ip}:{audio_port}|[select=v:f=rtp:ssrc={video_ssrc}:payload_type={video_pt}]rtp://{video_ip}:{video_port}
+        ",
+    ),
+    (
+        "vp8",
+        "gst-launch-1.0",
+        "vp8",
+        "progressreport",
+        "ERROR",
+        r#"
+        rtpbin name=rtpbin
+        v4l2src device={video_device}
+        ! image/jpeg,framerate={framerate}/1,width={width},height={height}
+        ! queue max-size-time=40000000 leaky=upstream
+        ! decodebin
+        ! videoconvert
+        ! videoscale ! videorate
+        ! video/x-raw,width={encode_width},height={encode_height},framerate={encode_framerate}/1
+        ! progressreport update-freq=1 silent=false
+        ! vp8enc end-usage=cbr keyframe-max-dist=60 target-bitrate={video_bitrate} deadline=1 cpu-used=4
+        ! rtpvp8pay pt={video_pt} ssrc={video_ssrc} picture-id-mode=2
+        ! rtpbin.send_rtp_sink_0
+        rtpbin.send_rtp_src_0 ! udpsink host={video_ip} port={video_port}
+        rtpbin.send_rtcp_src_0 ! udpsink host={video_ip} port={video_port} sync=false async=false
+        alsasrc device="{audio_device}"
+        ! queue max-size-time=40000000 leaky=upstream
+        ! decodebin
+        ! audioresample
+        ! audioconvert
+        ! opusenc inband-fec=true
+        ! rtpopuspay pt={audio_pt} ssrc={audio_ssrc}
+        ! rtpbin.send_rtp_sink_1
+        rtpbin.send_rtp_src_1 ! udpsink host={audio_ip} port={audio_port}
+        rtpbin.send_rtcp_src_1 ! udpsink host={audio_ip} port={audio_port} sync=false async=false
+        "#,
+    ),
+    (
+        "omx",
+        "gst-launch-1.0",
+        "h264",
+        "progressreport",
+        "ERROR",
+        r#"
+        rtpbin name=rtpbin
+        v4l2src device={video_device}
+        ! image/jpeg,framerate={framerate}/1,width={width},height={height}
+        ! queue max-size-time=40000000 leaky=upstream
+        ! decodebin
+        ! videoconvert
+        ! videoscale ! videorate
+        ! video/x-raw,width={encode_width},height={encode_height},framerate={encode_framerate}/1
+        ! progressreport update-freq=1 silent=false
+        ! omxh264enc control-rate=constant target-bitrate={video_bitrate} b-frames=0 interval-intraframes=60 inline-header=true
+        ! video/x-h264,profile=baseline
+        ! rtph264pay pt={video_pt} ssrc={video_ssrc}
+        ! rtpbin.send_rtp_sink_0
+        rtpbin.send_rtp_src_0 ! udpsink host={video_ip} port={video_port}
+        rtpbin.send_rtcp_src_0 ! udpsink host={video_ip} port={video_port} sync=false async=false
+        alsasrc device="{audio_device}"
+        ! queue max-size-time=40000000 leaky=upstream
+        ! decodebin
+        ! audioresample
+        ! audioconvert
+        ! opusenc inband-fec=true
+        ! rtpopuspay pt={audio_pt} ssrc={audio_ssrc}
+        ! rtpbin.send_rtp_sink_1
+        rtpbin.send_rtp_src_1 ! udpsink host={audio_ip} port={audio_port}
+        rtpbin.send_rtcp_src_1 ! udpsink host={audio_ip} port={audio_port} sync=false async=false
+        "#,
+    ),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VideoCodec {
+    Vp8,
+    H264,
+}
+
+impl VideoCodec {
+    fn parse(codec: &str) -> Result<Self> {
+        match codec.trim().to_lowercase().as_str() {
+            "vp8" => Ok(VideoCodec::Vp8),
+            "h264" => Ok(VideoCodec::H264),
+            _ => Err(anyhow!("Unknown codec {:?}, expected vp8 or h264", codec)),
+        }
+    }
+}
+
+/// Replaces every `{name}` in `arg` with `value(name)`, and `{{` and `}}`
+/// with single braces. A lone `}` is kept as it is.
+fn expand(arg: &str, value: impl Fn(&str) -> Option<String>) -> Result<String> {
+    let mut expanded = String::with_capacity(arg.len());
+    let mut rest = arg;
+    while let Some(start) = rest.find(&['{', '}'][..]) {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if rest.starts_with("{{") || rest.starts_with("}}") {
+            expanded.push_str(&rest[..1]);
+            rest = &rest[2..];
+        } else if rest.starts_with('}') {
+            expanded.push('}');
+            rest = &rest[1..];
+        } else {
+            let end = rest
+                .find('}')
+                .ok_or_else(|| anyhow!("Unclosed placeholder in {:?}", arg))?;
+            let name = &rest[1..end];
+            let replacement =
+                value(name).ok_or_else(|| anyhow!("Unknown placeholder {{{}}}", name))?;
+            expanded.push_str(&replacement);
+            rest = &rest[end + 1..];
+        }
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// A pipeline as defined, before its placeholders are filled in.
+#[derive(Debug, Clone)]
+struct Template {
+    program: String,
+    codec: VideoCodec,
+    progress: String,
+    error: String,
+    args: Vec<String>,
+    bitrate_command: Option<String>,
+}
+
+impl Template {
+    fn parse(
+        program: &str,
+        codec: &str,
+        progress: &str,
+        error: &str,
+        args: &str,
+        bitrate_command: &str,
+    ) -> Result<Self> {
+        let args: Vec<String> = args.split_whitespace().map(str::to_owned).collect();
+        for arg in &args {
+            expand(arg, |name| PLACEHOLDERS.contains(&name).then(String::new))?;
+        }
+        let bitrate_command = match bitrate_command.trim() {
+            "" => None,
+            command => {
+                expand(command, |name| {
+                    BITRATE_PLACEHOLDERS.contains(&name).then(String::new)
+                })
+                .map_err(|e| anyhow!("bitrate_command: {}", e))?;
+                Some(command.to_owned())
+            }
+        };
+        if program.trim().is_empty() {
+            return Err(anyhow!("missing program"));
+        }
+        Ok(Self {
+            program: program.trim().to_owned(),
+            codec: VideoCodec::parse(codec)?,
+            progress: progress.trim().to_owned(),
+            error: error.trim().to_owned(),
+            args,
+            bitrate_command,
+        })
+    }
+}
+
+fn presets() -> BTreeMap<String, Template> {
+    PRESETS
+        .iter()
+        .map(|&(name, program, codec, progress, error, args)| {
+            let template = Template::parse(program, codec, progress, error, args, "")
+                .unwrap_or_else(|e| panic!("Invalid preset {}: {:?}", name, e));
+            (name.to_owned(), template)
+        })
+        .collect()
+}
+
+/// Reads the pipelines in `path` over the presets, reporting all mistakes at
+/// once.
+fn load_templates(path: &Path) -> Result<BTreeMap<String, Template>> {
+    let mut templates = presets();
+    if !path.exists() {
+        log::debug!("No pipelines at {}", path.display());
+        return Ok(templates);
+    }
+    let ini = Ini::load_from_file(path)
+        .with_context(|| format!("Couldn't open pipelines file: {}", path.display()))?;
+
+    let mut problems = Vec::new();
+    for (name, properties) in ini.iter() {
+        let name = match name {
+            Some(name) => name,
+            None => continue,
+        };
+        let parsed = match (properties.get("program"), properties.get("args")) {
+            (Some(program), Some(args)) => Template::parse(
+                program,
+                properties.get("codec").unwrap_or("h264"),
+                properties.get("progress").unwrap_or_default(),
+                properties.get("error").unwrap_or_default(),
+                args,
+                properties.get("bitrate_command").unwrap_or_default(),
+            ),
+            _ => Err(anyhow!("program and args must be set")),
+        };
+        match parsed {
+            Ok(template) => {
+                if templates.insert(name.to_owned(), template).is_some() {
+                    log::info!(
+                        "Pipeline {} in {} replaces the preset",
+                        name,
+                        path.display()
+                    );
+                }
+            }
+            Err(e) => problems.push(format!("[{}]: {}", name, e)),
+        }
+    }
+    if !problems.is_empty() {
+        return Err(anyhow!(
+            "Invalid pipelines in {}:\n  {}",
+            path.display(),
+            problems.join("\n  ")
+        ));
+    }
+    Ok(templates)
+}
+
+/// A pipeline with everything but the capture device, the relay's transports
+/// and the encoding filled in.
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    name: String,
+    template: Template,
+    /// Values of the placeholders taken from the config.
+    values: BTreeMap<&'static str, String>,
+}
+
+impl Pipeline {
+    /// Loads the pipeline `[media] pipeline` names.
+    pub fn load(conf: &MediaConfig, config_dir: &Path) -> Result<Self> {
+        let mut templates = load_templates(&config_dir.join("pipelines.conf"))?;
+        let template = templates.remove(&conf.pipeline).ok_or_else(|| {
+            anyhow!(
+                "No pipeline named {:?}, available pipelines: {}",
+                conf.pipeline,
+                templates.keys().cloned().collect::<Vec<_>>().join(", ")
+            )
+        })?;
+        let values = vec![
+            ("width", conf.video_width.to_string()),
+            ("height", conf.video_height.to_string()),
+            ("framerate", conf.video_framerate.to_string()),
+            ("video_ssrc", VIDEO_SSRC.to_string()),
+            ("video_pt", VIDEO_PAYLOAD_TYPE.to_string()),
+            ("audio_ssrc", AUDIO_SSRC.to_string()),
+            ("audio_pt", AUDIO_PAYLOAD_TYPE.to_string()),
+        ];
+        Ok(Self {
+            name: conf.pipeline.clone(),
+            template,
+            values: values.into_iter().collect(),
+        })
+    }
+
+    /// The program and its arguments, capturing from `capture` and sending
+    /// audio to `audio` and video to `video` encoded as `encoding` says.
+    pub fn command_line(
+        &self,
+        capture: &CaptureDevice,
+        audio: SocketAddr,
+        video: SocketAddr,
+        encoding: &Encoding,
+    ) -> Result<Vec<String>> {
+        let value = |name: &str| match name {
+            "video_device" => Some(capture.video_device.display().to_string()),
+            "audio_device" => Some(match &capture.audio_card {
+                Some(card) => format!("hw:CARD={},DEV=0", card),
+                None => "default".to_owned(),
+            }),
+            "audio_ip" => Some(audio.ip().to_string()),
+            "audio_port" => Some(audio.port().to_string()),
+            "video_ip" => Some(video.ip().to_string()),
+            "video_port" => Some(video.port().to_string()),
+            "encode_width" => Some(encoding.width.to_string()),
+            "encode_height" => Some(encoding.height.to_string()),
+            "encode_framerate" => Some(encoding.framerate.to_string()),
+            "video_bitrate" => Some((encoding.video_bitrate_kbps as u64 * 1000).to_string()),
+            "video_bitrate_kbps" => Some(encoding.video_bitrate_kbps.to_string()),
+            _ => self.values.get(name).cloned(),
+        };
+        let mut line = vec![self.template.program.clone()];
+        for arg in &self.template.args {
+            line.push(expand(arg, value)?);
+        }
+        Ok(line)
+    }
+}
+
+impl Streamer for Pipeline {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn stream(
+        &self,
+        capture: &CaptureDevice,
+        audio: SocketAddr,
+        video: SocketAddr,
+        encoding: &Encoding,
+    ) -> Result<Child> {
+        let line = self.command_line(capture, audio, video, encoding)?;
+        log::debug!("Running {:?}", line);
+        let stdin = if self.template.bitrate_command.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        };
+        let child = Command::new(&line[0])
+            .args(&line[1..])
+            .stdin(stdin)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Couldn't run {}", line[0]))?;
+        Ok(child)
+    }
+
+    fn reports_progress(&self) -> bool {
+        !self.template.progress.is_empty()
+    }
+
+    fn bitrate_command(&self, encoding: &Encoding) -> Option<String> {
+        let command = self.template.bitrate_command.as_ref()?;
+        let value = |name: &str| match name {
+            "video_bitrate" => Some((encoding.video_bitrate_kbps as u64 * 1000).to_string()),
+            "video_bitrate_kbps" => Some(encoding.video_bitrate_kbps.to_string()),
+            _ => None,
+        };
+        // checked when the template was parsed
+        expand(command, value).ok()
+    }
+
+    fn is_progress(&self, line: &str) -> bool {
+        self.reports_progress() && line.starts_with(&self.template.progress)
+    }
+
+    fn is_error(&self, line: &str) -> bool {
+        !self.template.error.is_empty() && line.starts_with(&self.template.error)
+    }
+
+    fn video_codec(&self) -> Value {
+        match self.template.codec {
+            VideoCodec::Vp8 => json!({
+                "mimeType": "video/VP8",
+                "payloadType": VIDEO_PAYLOAD_TYPE,
+                "clockRate": 90000,
+            }),
+            VideoCodec::H264 => json!({
+                "mimeType": "video/H264",
+                "payloadType": VIDEO_PAYLOAD_TYPE,
+                "clockRate": 90000,
+                "parameters": {
+                    "packetization-mode": 1,
+                    "profile-level-id": "42e01f",
+                },
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pipeline(template: Template) -> Pipeline {
+        Pipeline {
+            name: "test".to_owned(),
+            template,
+            values: BTreeMap::new(),
+        }
+    }
+
+    fn sizes(name: &str) -> Option<String> {
+        match name {
+            "width" => Some("1280".to_owned()),
+            "height" => Some("720".to_owned()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn expand_placeholders() {
+        let cases = [
+            ("", ""),
+            ("plain", "plain"),
+            ("{width}", "1280"),
+            ("{width}x{height}", "1280x720"),
+            ("-s={width}:{width}", "-s=1280:1280"),
+            ("{{width}}", "{width}"),
+            ("{{{width}}}", "{1280}"),
+            ("{{", "{"),
+            ("a}b", "a}b"),
+            ("a}}b", "a}b"),
+            ("{{}", "{}"),
+        ];
+        for (arg, expected) in cases.iter() {
+            assert_eq!(expand(arg, sizes).unwrap(), *expected, "{:?}", arg);
+        }
+    }
+
+    #[test]
+    fn expand_bad_placeholders() {
+        let cases = [
+            ("{depth}", "Unknown placeholder {depth}"),
+            ("{}", "Unknown placeholder {}"),
+            ("{Width}", "Unknown placeholder {Width}"),
+            ("{width", "Unclosed placeholder in \"{width\""),
+            ("{width}x{", "Unclosed placeholder in \"{width}x{\""),
+            ("{{{width", "Unclosed placeholder in \"{{{width\""),
+        ];
+        for (arg, error) in cases.iter() {
+            let e = expand(arg, sizes).unwrap_err().to_string();
+            assert_eq!(e, *error, "{:?}", arg);
+        }
+        let e = Template::parse("ffmpeg", "h264", "", "", "-i {video_device} -s {size}", "")
+            .unwrap_err()
+            .to_string();
+        assert_eq!(e, "Unknown placeholder {size}");
+    }
+
+    #[test]
+    fn preset_command_line() {
+        let conf = MediaConfig {
+            pipeline: "x264".to_owned(),
+            ..MediaConfig::default()
+        };
+        let pipeline = Pipeline::load(&conf, Path::new("/nonexistent")).unwrap();
+        let capture = CaptureDevice {
+            video_device: "/dev/video2".into(),
+            audio_card: Some("MS2109".to_owned()),
+            usb_id: None,
+            name: "USB Video".to_owned(),
+        };
+        let encoding = Encoding {
+            video_bitrate_kbps: 2500,
+            width: 960,
+            height: 540,
+            framerate: 30,
+        };
+        let line = pipeline
+            .command_line(
+                &capture,
+                "10.0.0.1:5000".parse().unwrap(),
+                "10.0.0.1:5002".parse().unwrap(),
+                &encoding,
+            )
+            .unwrap();
+        let expected = format!(
+            "ffmpeg -fflags +genpts \
+             -f v4l2 -thread_queue_size 1024 -input_format mjpeg \
+             -video_size 1280x720 -framerate 30 -i /dev/video2 \
+             -f alsa -thread_queue_size 1024 -ac 2 -i hw:CARD=MS2109,DEV=0 \
+             -vf scale=960:540,fps=30 \
+             -c:v libx264 -profile:v baseline -preset ultrafast -tune zerolatency \
+             -b:v 2500k -maxrate 2500k -bufsize 2500k -g 60 \
+             -bsf:v h264_mp4toannexb,dump_extra -pix_fmt yuv420p \
+             -map 0:v:0 -map 1:a:0 \
+             -c:a libopus -ab 256k -ac 2 -ar 48000 \
+             -f tee [select=a:f=rtp:ssrc={audio_ssrc}:payload_type={audio_pt}]rtp://10.0.0.1:5000|\
+             [select=v:f=rtp:ssrc={video_ssrc}:payload_type={video_pt}]rtp://10.0.0.1:5002",
+            audio_ssrc = AUDIO_SSRC,
+            audio_pt = AUDIO_PAYLOAD_TYPE,
+            video_ssrc = VIDEO_SSRC,
+            video_pt = VIDEO_PAYLOAD_TYPE,
+        );
+        assert_eq!(line.join(" "), expected);
+    }
+
+    #[test]
+    fn bitrate_commands() {
+        let encoding = Encoding {
+            video_bitrate_kbps: 1200,
+            width: 1280,
+            height: 720,
+            framerate: 30,
+        };
+        let template = Template::parse(
+            "encoder",
+            "h264",
+            "",
+            "",
+            "--to {video_ip}:{video_port}",
+            " set bitrate={video_bitrate_kbps}k ({video_bitrate}) ",
+        )
+        .unwrap();
+        assert_eq!(
+            pipeline(template).bitrate_command(&encoding).as_deref(),
+            Some("set bitrate=1200k (1200000)")
+        );
+
+        let e = Template::parse("encoder", "h264", "", "", "", "size {width}")
+            .unwrap_err()
+            .to_string();
+        assert_eq!(e, "bitrate_command: Unknown placeholder {width}");
+        for (name, template) in presets() {
+            assert!(
+                pipeline(template).bitrate_command(&encoding).is_none(),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn presets_match_lines_by_prefix() {
+        let mut presets = presets();
+        let ffmpeg = pipeline(presets.remove("x264").unwrap());
+        assert!(ffmpeg.is_progress("frame=  120 fps= 30 q=-1.0 size=N/A"));
+        assert!(ffmpeg.is_error("Error opening input files: No such file or directory"));
+        // mentions of errors elsewhere in a line are not errors
+        assert!(!ffmpeg.is_error("  -err_detect  error detection flags"));
+        assert!(
+            !ffmpeg.is_error("[rtp @ 0x5581] max delay reached. need to consume packet, failed")
+        );
+
+        let gstreamer = pipeline(presets.remove("vp8").unwrap());
+        assert!(gstreamer.is_progress("progressreport0 (00:00:05): 5 seconds"));
+        assert!(
+            gstreamer.is_error("ERROR: from element /GstPipeline:pipeline0/GstV4l2Src:v4l2src0")
+        );
+        assert!(!gstreamer.is_error("Setting pipeline to PLAYING, error-after unset"));
+    }
+
+    #[test]
+    fn empty_prefixes_match_nothing() {
+        let template = Template::parse("true", "vp8", "", " ", "", "").unwrap();
+        let pipeline = pipeline(template);
+        assert!(!pipeline.reports_progress());
+        assert!(!pipeline.is_progress("frame=1"));
+        assert!(!pipeline.is_error("Error"));
+    }
+
+    #[test]
+    fn load_pipeline_without_progress() {
+        let dir = std::env::temp_dir().join(format!("vulcast-pipelines-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("pipelines.conf");
+        std::fs::write(
+            &path,
+            "[quiet]\nprogram = quiet-streamer\ncodec = vp8\n\
+             args = --video {video_device} --to {video_ip}:{video_port}\n\
+             bitrate_command = bitrate {video_bitrate_kbps}\n",
+        )
+        .unwrap();
+        let templates = load_templates(&path);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mut templates = templates.unwrap();
+        let quiet = pipeline(templates.remove("quiet").unwrap());
+        assert!(!quiet.reports_progress());
+        assert!(!quiet.is_progress("frame=1"));
+        assert_eq!(quiet.template.codec, VideoCodec::Vp8);
+        assert_eq!(
+            quiet.template.bitrate_command.as_deref(),
+            Some("bitrate {video_bitrate_kbps}")
+        );
+        // the presets are still there
+        assert!(templates.contains_key("x264"));
+    }
+}
@@ -0,0 +1,156 @@
+//! Just enough RTCP parsing to read the reception reports the relay sends
+//! about the streams it receives (RFC 3550 section 6.4).
+
+const SENDER_REPORT: u8 = 200;
+const RECEIVER_REPORT: u8 = 201;
+const SENDER_REPORT_HEADER_LENGTH: usize = 28;
+const RECEIVER_REPORT_HEADER_LENGTH: usize = 8;
+const REPORT_BLOCK_LENGTH: usize = 24;
+
+/// Reception statistics of one stream, as seen by the relay.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReceptionReport {
+    pub ssrc: u32,
+    /// Fraction of packets lost since the previous report, 0.0 to 1.0.
+    pub fraction_lost: f64,
+    /// Interarrival jitter, in RTP timestamp units.
+    pub jitter: u32,
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Reads the report blocks of every sender and receiver report in a
+/// compound RTCP packet, stopping at anything malformed.
+pub fn reception_reports(mut packet: &[u8]) -> Vec<ReceptionReport> {
+    let mut reports = Vec::new();
+    while packet.len() >= 4 {
+        let version = packet[0] >> 6;
+        let count = (packet[0] & 0x1f) as usize;
+        let length = (u16::from_be_bytes([packet[2], packet[3]]) as usize + 1) * 4;
+        if version != 2 || packet.len() < length {
+            break;
+        }
+        let header_length = match packet[1] {
+            SENDER_REPORT => Some(SENDER_REPORT_HEADER_LENGTH),
+            RECEIVER_REPORT => Some(RECEIVER_REPORT_HEADER_LENGTH),
+            _ => None,
+        };
+        if let Some(header_length) = header_length {
+            let blocks = &packet[header_length.min(length)..length];
+            for block in blocks.chunks_exact(REPORT_BLOCK_LENGTH).take(count) {
+                reports.push(ReceptionReport {
+                    ssrc: be_u32(&block[0..4]),
+                    fraction_lost: block[4] as f64 / 256.0,
+                    jitter: be_u32(&block[12..16]),
+                });
+            }
+        }
+        packet = &packet[length..];
+    }
+    reports
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE_DESCRIPTION: u8 = 202;
+
+    fn packet(count: usize, packet_type: u8, body: &[u8]) -> Vec<u8> {
+        let words = (4 + body.len()) / 4 - 1;
+        let mut packet = vec![0x80 | count as u8, packet_type];
+        packet.extend_from_slice(&(words as u16).to_be_bytes());
+        packet.extend_from_slice(body);
+        packet
+    }
+
+    fn block(ssrc: u32, fraction_lost: u8, jitter: u32) -> Vec<u8> {
+        let mut block = ssrc.to_be_bytes().to_vec();
+        block.extend_from_slice(&[fraction_lost, 0, 0, 7]);
+        block.extend_from_slice(&1000u32.to_be_bytes());
+        block.extend_from_slice(&jitter.to_be_bytes());
+        block.extend_from_slice(&[0; 8]);
+        block
+    }
+
+    fn receiver_report(blocks: &[Vec<u8>]) -> Vec<u8> {
+        let mut body = 1u32.to_be_bytes().to_vec();
+        body.extend(blocks.iter().flatten());
+        packet(blocks.len(), RECEIVER_REPORT, &body)
+    }
+
+    fn sender_report(blocks: &[Vec<u8>]) -> Vec<u8> {
+        let mut body = 1u32.to_be_bytes().to_vec();
+        // NTP and RTP timestamps, packet and octet counts
+        body.extend_from_slice(&[0xab; 20]);
+        body.extend(blocks.iter().flatten());
+        packet(blocks.len(), SENDER_REPORT, &body)
+    }
+
+    fn source_description() -> Vec<u8> {
+        let mut body = 1u32.to_be_bytes().to_vec();
+        body.extend_from_slice(&[1, 4, b'r', b'e', b'l', b'y', 0, 0]);
+        packet(1, SOURCE_DESCRIPTION, &body)
+    }
+
+    fn report(ssrc: u32, fraction_lost: u8, jitter: u32) -> ReceptionReport {
+        ReceptionReport {
+            ssrc,
+            fraction_lost: fraction_lost as f64 / 256.0,
+            jitter,
+        }
+    }
+
+    #[test]
+    fn single_reports() {
+        let sender = sender_report(&[block(22222222, 64, 90)]);
+        assert_eq!(
+            sender.len(),
+            SENDER_REPORT_HEADER_LENGTH + REPORT_BLOCK_LENGTH
+        );
+        assert_eq!(reception_reports(&sender), vec![report(22222222, 64, 90)]);
+
+        let receiver = receiver_report(&[block(11111111, 0, 3), block(22222222, 255, 0)]);
+        assert_eq!(
+            reception_reports(&receiver),
+            vec![report(11111111, 0, 3), report(22222222, 255, 0)]
+        );
+        assert_eq!(reception_reports(&receiver)[1].fraction_lost, 255.0 / 256.0);
+        assert!(reception_reports(&receiver_report(&[])).is_empty());
+    }
+
+    #[test]
+    fn compound_packets() {
+        let mut compound = sender_report(&[block(1, 10, 1)]);
+        compound.extend(source_description());
+        compound.extend(receiver_report(&[block(2, 20, 2), block(3, 30, 3)]));
+        let expected = vec![report(1, 10, 1), report(2, 20, 2), report(3, 30, 3)];
+        assert_eq!(reception_reports(&compound), expected);
+
+        // a packet cut short is dropped with everything after it
+        let cut = &compound[..compound.len() - 4];
+        assert_eq!(reception_reports(cut), expected[..1]);
+        assert!(reception_reports(&compound[..3]).is_empty());
+        assert!(reception_reports(&[]).is_empty());
+    }
+
+    #[test]
+    fn malformed_packets() {
+        // more blocks counted than there is room for
+        let mut overcounted = receiver_report(&[block(1, 10, 1)]);
+        overcounted[0] = 0x80 | 2;
+        assert_eq!(reception_reports(&overcounted), vec![report(1, 10, 1)]);
+
+        // a sender report too short for its own header
+        let short = packet(1, SENDER_REPORT, &[0; 8]);
+        assert!(reception_reports(&short).is_empty());
+
+        // not RTCP version 2, which also ends the compound packet
+        let mut wrong_version = receiver_report(&[block(1, 10, 1)]);
+        wrong_version[0] = 0x40 | 1;
+        wrong_version.extend(receiver_report(&[block(2, 20, 2)]));
+        assert!(reception_reports(&wrong_version).is_empty());
+    }
+}
@@ -0,0 +1,265 @@
+//! Per data producer input limits, so a buggy or hostile client cannot
+//! flood the HID writes: a token bucket on message rate, counters of bad
+//! messages, and a threshold of bad messages after which the client is
+//! disconnected.
+
+use std::time::{Duration, Instant};
+
+use crate::config::LimitsConfig;
+use crate::protocol::DecodeError;
+
+use serde::Serialize;
+
+/// Period over which `LimitsConfig::abuse_threshold` is counted.
+const ABUSE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Allows `rate` events per second on average, in bursts of up to `burst`.
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32, burst: u32, now: Instant) -> Self {
+        Self {
+            rate: rate as f64,
+            burst: burst as f64,
+            tokens: burst as f64,
+            last: now,
+        }
+    }
+
+    /// Takes a token if one is available.
+    fn take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        // an earlier `now` must not credit the same time twice
+        self.last = self.last.max(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Counts of what happened to one data producer's messages.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct ProducerStats {
+    pub received: u64,
+    /// Dropped for arriving faster than `input_rate_hz` allows.
+    pub rate_limited: u64,
+    pub undersized: u64,
+    pub oversized: u64,
+    pub malformed: u64,
+    /// Sent for a slot the producer may not use, e.g. one another producer
+    /// owns.
+    pub rejected: u64,
+    /// Could not be applied for reasons not held against the producer, such
+    /// as an unknown macro or a failed device write.
+    pub failed: u64,
+}
+
+/// What to do with a message that passed or failed the limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+    Drop,
+    /// Too many bad messages; stop consuming from the producer.
+    Disconnect,
+}
+
+/// Applies the input limits to one data producer.
+#[derive(Debug, Clone)]
+pub struct InputLimiter {
+    bucket: TokenBucket,
+    stats: ProducerStats,
+    abuse_threshold: u32,
+    /// Bad messages since `window_start`.
+    violations: u32,
+    window_start: Instant,
+}
+
+impl InputLimiter {
+    pub fn new(conf: &LimitsConfig, now: Instant) -> Self {
+        Self {
+            bucket: TokenBucket::new(conf.input_rate_hz, conf.input_burst, now),
+            stats: ProducerStats::default(),
+            abuse_threshold: conf.abuse_threshold,
+            violations: 0,
+            window_start: now,
+        }
+    }
+
+    pub fn stats(&self) -> ProducerStats {
+        self.stats
+    }
+
+    /// Counts a newly received message and decides whether to process it.
+    pub fn admit(&mut self, now: Instant) -> Verdict {
+        self.stats.received += 1;
+        if self.bucket.take(now) {
+            Verdict::Accept
+        } else {
+            self.stats.rate_limited += 1;
+            self.violation(now)
+        }
+    }
+
+    /// Counts a message that could not be decoded.
+    pub fn malformed(&mut self, error: &DecodeError, now: Instant) -> Verdict {
+        match error {
+            DecodeError::Undersized(_) => self.stats.undersized += 1,
+            DecodeError::Oversized(_) => self.stats.oversized += 1,
+            DecodeError::Malformed(_) => self.stats.malformed += 1,
+        }
+        self.violation(now)
+    }
+
+    /// Counts a message for a controller the producer may not use.
+    pub fn rejected(&mut self, now: Instant) -> Verdict {
+        self.stats.rejected += 1;
+        self.violation(now)
+    }
+
+    /// Counts a message that was accepted but could not be applied.
+    pub fn failed(&mut self) {
+        self.stats.failed += 1;
+    }
+
+    fn violation(&mut self, now: Instant) -> Verdict {
+        if now.saturating_duration_since(self.window_start) > ABUSE_WINDOW {
+            self.window_start = now;
+            self.violations = 0;
+        }
+        self.violations += 1;
+        if self.abuse_threshold > 0 && self.violations >= self.abuse_threshold {
+            Verdict::Disconnect
+        } else {
+            Verdict::Drop
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis(start: Instant, millis: u64) -> Instant {
+        start + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(4, 3, start);
+        // a full burst, then nothing until a token has refilled
+        assert!((0..3).all(|_| bucket.take(start)));
+        assert!(!bucket.take(start));
+        assert!(!bucket.take(millis(start, 125)));
+        assert!(bucket.take(millis(start, 250)));
+        assert!(!bucket.take(millis(start, 250)));
+        // time going backwards adds nothing
+        assert!(!bucket.take(start));
+        assert!(!bucket.take(millis(start, 400)));
+        assert!(bucket.take(millis(start, 500)));
+    }
+
+    #[test]
+    fn bucket_holds_at_most_a_burst() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(4, 3, start);
+        assert!(bucket.take(start));
+        let later = start + Duration::from_secs(3600);
+        assert!((0..3).all(|_| bucket.take(later)));
+        assert!(!bucket.take(later));
+    }
+
+    #[test]
+    fn rate_limited_messages_are_violations() {
+        let conf = LimitsConfig {
+            input_rate_hz: 4,
+            input_burst: 1,
+            abuse_threshold: 2,
+        };
+        let start = Instant::now();
+        let mut limiter = InputLimiter::new(&conf, start);
+        assert_eq!(limiter.admit(start), Verdict::Accept);
+        assert_eq!(limiter.admit(start), Verdict::Drop);
+        assert_eq!(limiter.admit(millis(start, 100)), Verdict::Disconnect);
+
+        let stats = limiter.stats();
+        assert_eq!(stats.received, 3);
+        assert_eq!(stats.rate_limited, 2);
+    }
+
+    #[test]
+    fn violations_are_counted_per_window() {
+        let conf = LimitsConfig {
+            abuse_threshold: 3,
+            ..LimitsConfig::default()
+        };
+        let start = Instant::now();
+        let cases = [
+            // violation times in milliseconds, verdict on the last
+            (&[0, 5_000, 10_000][..], Verdict::Disconnect),
+            (&[0, 5_000, 10_001][..], Verdict::Drop),
+            (&[0, 5_000, 10_001, 12_000, 15_000][..], Verdict::Disconnect),
+            (&[0, 10_001, 20_002][..], Verdict::Drop),
+        ];
+        for (times, expected) in cases.iter() {
+            let mut limiter = InputLimiter::new(&conf, start);
+            let verdicts: Vec<_> = times
+                .iter()
+                .map(|&at| limiter.rejected(millis(start, at)))
+                .collect();
+            assert_eq!(verdicts.last(), Some(expected), "{:?}", times);
+            assert!(
+                verdicts[..verdicts.len() - 1]
+                    .iter()
+                    .all(|&verdict| verdict == Verdict::Drop),
+                "{:?}",
+                times
+            );
+        }
+    }
+
+    #[test]
+    fn no_threshold_never_disconnects() {
+        let conf = LimitsConfig {
+            abuse_threshold: 0,
+            ..LimitsConfig::default()
+        };
+        let start = Instant::now();
+        let mut limiter = InputLimiter::new(&conf, start);
+        assert!((0..1000).all(|_| limiter.rejected(start) == Verdict::Drop));
+    }
+
+    #[test]
+    fn only_violations_count_towards_disconnecting() {
+        let conf = LimitsConfig {
+            abuse_threshold: 3,
+            ..LimitsConfig::default()
+        };
+        let now = Instant::now();
+        let mut limiter = InputLimiter::new(&conf, now);
+        for _ in 0..10 {
+            limiter.failed();
+        }
+        assert_eq!(limiter.rejected(now), Verdict::Drop);
+        assert_eq!(
+            limiter.malformed(&DecodeError::Undersized(String::new()), now),
+            Verdict::Drop
+        );
+        assert_eq!(limiter.rejected(now), Verdict::Disconnect);
+
+        let stats = limiter.stats();
+        assert_eq!(stats.failed, 10);
+        assert_eq!(stats.rejected, 2);
+        assert_eq!(stats.undersized, 1);
+    }
+}
@@ -1,165 +1,202 @@
+use std::path::Path;
 use std::sync::{Arc, Mutex};
-use tokio::io::AsyncReadExt;
-
-use graphql::backend_query;
-use graphql::signal_query;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Result};
-use atty::Stream;
-use backend_query::assign_vulcast_to_relay::AssignVulcastToRelayAssignVulcastToRelay::{
-    AuthenticationError, RelayAssignment, VulcastAssignedToRelayError,
-};
-use backend_query::log_in_as_vulcast::LogInAsVulcastLogInAsVulcast::{
-    AuthenticationError as LoginAuthenticationError, VulcastAuthentication,
-};
+use backoff::Backoff;
 use clap::Parser;
-use controllers::Controllers;
-use controllers::NsProcons;
-use futures::StreamExt;
-use graphql_client::{GraphQLQuery, Response};
-use graphql_ws::GraphQLWebSocket;
-use http::Uri;
-use ini::Ini;
-use serde::Serialize;
-use std::convert::TryInto;
-use tokio::net::TcpStream;
-use tokio_tungstenite::Connector;
-use vulcast_rtc::broadcaster::Broadcaster;
-use vulcast_rtc::types::*;
-
-use crate::graphql_signaller::GraphQLSignaller;
+use config::Config;
+use control::{Control, ControlCommand, SessionState};
+use controllers::{ControllerSlots, MACRO_TICK};
+use data_streamer::{Encoding, Pipeline};
+use media::MediaMode;
+use recording::Recorder;
+use session::{MediaSource, SessionEnd};
+use tokio::signal::unix::{signal, Signal, SignalKind};
 
+mod backend;
+mod backoff;
 mod cmdline;
+mod config;
+mod control;
 mod controllers;
 mod data_streamer;
 mod graphql;
 mod graphql_signaller;
+mod limits;
+mod media;
+mod protocol;
+mod recording;
+mod session;
+mod tls;
 
-use cmdline::Opts;
-
-#[derive(Serialize)]
-struct SessionToken {
-    token: String,
-}
+use cmdline::{Command, ConfigCommand, Opts, PipelineCommand};
 
-async fn login(conf: &Ini, client: &reqwest::Client) -> Result<String> {
-    log::info!("Logging in");
+const RECONNECT_DELAY_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(60);
+/// A session that stayed up this long before the relay dropped it reconnects
+/// with the shortest delay again.
+const SESSION_STABLE_AFTER: Duration = Duration::from_secs(60);
+/// Upper bound on releasing the controllers and deactivating the gadget once
+/// asked to exit; systemd's stop timeout must be longer than this.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often slots are checked for stale input.
+const WATCHDOG_INTERVAL: Duration = Duration::from_millis(100);
 
-    let guid = conf
-        .get_from(Some("auth"), "guid")
-        .expect("GUID missing from config")
-        .to_owned();
-    let secret = conf
-        .get_from(Some("auth"), "secret")
-        .expect("Secret missing from config")
-        .to_owned();
-    let uri = conf
-        .get_from(Some("network"), "backend_addr")
-        .expect("No backend address specified")
-        .to_owned()
-        + "/graphql";
+/// Resolves with the name of the first termination signal received.
+async fn shutdown_signal(mut sigterm: Signal, mut sigint: Signal) -> &'static str {
+    tokio::select! {
+        _ = sigterm.recv() => "SIGTERM",
+        _ = sigint.recv() => "SIGINT",
+    }
+}
 
-    let login_query =
-        backend_query::LogInAsVulcast::build_query(backend_query::log_in_as_vulcast::Variables {
-            vulcast_id: guid,
-            secret,
-        });
-    let auth = client.post(&uri).json(&login_query).send().await?;
-    let response_body: Response<backend_query::log_in_as_vulcast::ResponseData> =
-        auth.json().await?;
-    if let Some(errors) = response_body.errors {
-        errors.iter().for_each(|error| log::error!("{:?}", error))
+/// Leaves every controller in a neutral state and deactivates the gadget,
+/// giving up after `SHUTDOWN_TIMEOUT` so a wedged HID write cannot stop the
+/// process from exiting.
+async fn release_controllers(controllers: Arc<Mutex<ControllerSlots>>) {
+    log::info!("Releasing controllers...");
+    let cleanup = tokio::task::spawn_blocking(move || controllers.lock().unwrap().deinitialize());
+    match tokio::time::timeout(SHUTDOWN_TIMEOUT, cleanup).await {
+        Ok(Ok(Ok(()))) => (),
+        Ok(Ok(Err(e))) => log::warn!("Error releasing controllers: {:?}", e),
+        Ok(Err(e)) => log::warn!("Error releasing controllers: {:?}", e),
+        Err(_) => {
+            log::error!("Timed out releasing controllers, exiting anyway");
+            // the blocking task would otherwise keep the runtime alive
+            std::process::exit(1);
+        }
     }
-    let response_data: backend_query::log_in_as_vulcast::ResponseData = response_body
-        .data
-        .ok_or_else(|| anyhow!("Request returned no data"))?;
-    match response_data.log_in_as_vulcast {
-        VulcastAuthentication(auth) => Ok(auth.vulcast_access_token),
-        LoginAuthenticationError(error) => Err(anyhow!("Authentication error: {}", error.message)),
+}
+
+/// Resets slots to neutral once they have gone `timeout` without input, so a
+/// player whose data channel goes quiet cannot leave a button held down.
+async fn watch_inputs(controllers: Arc<Mutex<ControllerSlots>>, timeout: Duration) {
+    let mut interval = tokio::time::interval(WATCHDOG_INTERVAL.min(timeout));
+    loop {
+        interval.tick().await;
+        if let Err(e) = controllers.lock().unwrap().release_idle(timeout) {
+            log::warn!("Error releasing idle controllers: {:?}", e);
+        }
     }
 }
 
-fn write_relay_assignment(hostname: &str, token: &str, opts: &Opts) -> Result<()> {
-    log::info!("Writing relay assignment...");
-    let mut assigned = Ini::new();
-    assigned
-        .with_section(Some("relay"))
-        .set("hostname", hostname)
-        .set("token", token);
-    assigned.write_to_file(opts.config_dir.clone() + "/assigned_relay")?;
-    Ok(())
+/// Advances turbo and macro playback, which change input between messages.
+async fn tick_macros(controllers: Arc<Mutex<ControllerSlots>>) {
+    let mut interval = tokio::time::interval(MACRO_TICK);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        interval.tick().await;
+        if let Err(e) = controllers.lock().unwrap().tick(Instant::now()) {
+            log::warn!("Error advancing macros: {:?}", e);
+        }
+    }
 }
 
-fn read_relay_assignment(opts: &Opts) -> Result<(String, String)> {
-    log::info!("Reading relay assignment...");
-    let relay_file = Ini::load_from_file(opts.config_dir.clone() + "/assigned_relay")?;
-    let host = relay_file
-        .get_from(Some("relay"), "hostname")
-        .ok_or_else(|| anyhow!("Could not load relay hostname from file"))?;
-    let token = relay_file
-        .get_from(Some("relay"), "token")
-        .ok_or_else(|| anyhow!("Could not load relay token from file"))?;
-    Ok((host.to_owned(), token.to_owned()))
+/// Joins a command line, quoting words the shell would split or expand.
+fn shell_words(line: &[String]) -> String {
+    line.iter()
+        .map(|word| {
+            let plain = !word.is_empty()
+                && word
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_./:=,+@%".contains(c));
+            if plain {
+                word.clone()
+            } else {
+                format!("'{}'", word.replace('\'', "'\\''"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
 }
 
-async fn assign_relay(
-    conf: &Ini,
-    opts: &Opts,
-    client: &reqwest::Client,
-    auth_token: &str,
-) -> Result<(String, String)> {
-    log::info!("Requesting relay assignment");
+fn main() -> Result<()> {
+    env_logger::init_from_env(env_logger::Env::default());
+
+    let opts: Opts = Opts::parse();
+    let conf = Config::load(&opts)?;
+
+    // changes the environment, so it must happen before the runtime starts
+    // any threads
+    let alsa = match (&opts.command, conf.media.mode) {
+        (None, MediaMode::Native) => Some(media::AlsaDefault::install(
+            conf.control_socket(&opts).with_file_name("asound.conf"),
+        )?),
+        _ => None,
+    };
 
-    let uri = conf
-        .get_from(Some("network"), "backend_addr")
-        .expect("No backend address specified")
-        .to_owned()
-        + "/graphql";
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(opts, conf, alsa))
+}
 
-    let register_query = backend_query::AssignVulcastToRelay::build_query(
-        backend_query::assign_vulcast_to_relay::Variables {},
+async fn run(opts: Opts, conf: Config, alsa: Option<media::AlsaDefault>) -> Result<()> {
+    let shutdown = shutdown_signal(
+        signal(SignalKind::terminate())?,
+        signal(SignalKind::interrupt())?,
     );
-    let res = client
-        .post(&uri)
-        .bearer_auth("vulcast_".to_owned() + auth_token)
-        .json(&register_query)
-        .send()
-        .await?;
+    tokio::pin!(shutdown);
 
-    let response_body: Response<backend_query::assign_vulcast_to_relay::ResponseData> =
-        res.json().await?;
-    if let Some(errors) = response_body.errors {
-        errors.iter().for_each(|error| log::error!("{:?}", error))
-    }
-    let response_data: backend_query::assign_vulcast_to_relay::ResponseData = response_body
-        .data
-        .ok_or_else(|| anyhow!("Request returned no data"))?;
-    match response_data.assign_vulcast_to_relay {
-        RelayAssignment(assignment) => {
-            let _ = write_relay_assignment(
-                &assignment.relay.host_name,
-                &assignment.relay_access_token,
-                &opts,
-            );
-            Ok((assignment.relay.host_name, assignment.relay_access_token))
+    let replay = match &opts.command {
+        Some(Command::Config(ConfigCommand::Check)) => {
+            print!("{}", conf);
+            return Ok(());
         }
-        AuthenticationError(error) => Err(anyhow!("Authentication error: {}", error.message)),
-        VulcastAssignedToRelayError(error) => {
-            Err(anyhow!("Vulcast already assigned error: {}", error.message))
+        Some(Command::Control { method, params }) => {
+            return control::request(&conf.control_socket(&opts), method, params.as_deref()).await;
         }
-    }
-}
-
-#[tokio::main]
-async fn main() -> Result<()> {
-    env_logger::init_from_env(env_logger::Env::default());
+        Some(Command::Pipeline(PipelineCommand::DryRun {
+            name,
+            audio_addr,
+            video_addr,
+        })) => {
+            let mut media = conf.media.clone();
+            if let Some(name) = name {
+                media.pipeline = name.clone();
+            }
+            let capture = media::find_capture_device(&media).map_err(|reason| {
+                anyhow!(
+                    "Capture device not found ({}), available devices: {:?}",
+                    reason,
+                    media::list_devices()
+                )
+            })?;
+            let pipeline = Pipeline::load(&media, Path::new(&opts.config_dir))?;
+            let line = pipeline.command_line(
+                &capture,
+                *audio_addr,
+                *video_addr,
+                &Encoding::new(&media),
+            )?;
+            println!("{}", shell_words(&line));
+            return Ok(());
+        }
+        Some(Command::Replay { file, speed }) => {
+            if speed.is_nan() || *speed <= 0.0 {
+                return Err(anyhow!("Replay speed must be positive"));
+            }
+            Some((recording::read(Path::new(file))?, *speed))
+        }
+        None => None,
+    };
 
-    let opts: Opts = Opts::parse();
+    // bound before touching the gadget, so a second instance stops here
+    let control_socket = conf.control_socket(&opts);
+    let control_listener = match replay {
+        Some(_) => None,
+        None => Some(control::listen(&control_socket).await?),
+    };
 
     let controllers = {
-        if !opts.no_controller {
+        if !opts.no_controller && conf.controller.enabled {
             log::info!("Setting up controller emulator...");
-            let mut controllers = NsProcons::new("procons");
+            let mut controllers = ControllerSlots::new(
+                controllers::create(&conf.controller)?,
+                &conf,
+                Path::new(&opts.config_dir),
+            )?;
             controllers.initialize()?;
             Some(Arc::new(Mutex::new(controllers)))
         } else {
@@ -167,114 +204,122 @@ async fn main() -> Result<()> {
         }
     };
 
-    log::info!("Loading config from {}", opts.config_dir);
-    let conf = Ini::load_from_file(opts.config_dir.clone() + "/vulcast.conf").expect(&format!(
-        "Couldn't open config file: {}/vulcast.conf",
-        &opts.config_dir
-    ));
-    let client = reqwest::Client::new();
-
-    let access_token = login(&conf, &client).await?;
-    let (relay_host, relay_token) = assign_relay(&conf, &opts, &client, &access_token)
-        .await
-        .or_else(|_| read_relay_assignment(&opts))?;
-
-    log::info!("Assigned to relay {:?}", relay_host);
-
-    let port: u16 = conf
-        .get_from(Some("network"), "signal_port")
-        .expect("Signal port not specified")
-        .parse()
-        .expect("Signal port could not be parsed as an int");
-    let relay_uri: Uri = format!("wss://{}:{}", relay_host, port).parse().unwrap();
-
-    log::info!("Connecting to relay at {:?}", relay_uri);
-
-    let stream = TcpStream::connect((relay_host.clone(), port)).await?;
-    let req = http::Request::builder()
-        .uri(relay_uri)
-        .header("Sec-WebSocket-Protocol", "graphql-ws")
-        .body(())?;
+    if let Some(controllers) = &controllers {
+        tokio::spawn(tick_macros(controllers.clone()));
+        if conf.controller.input_timeout_ms > 0 {
+            tokio::spawn(watch_inputs(
+                controllers.clone(),
+                Duration::from_millis(conf.controller.input_timeout_ms),
+            ));
+        }
+    }
 
-    struct PromiscuousServerVerifier;
-    impl rustls::client::ServerCertVerifier for PromiscuousServerVerifier {
-        fn verify_server_cert(
-            &self,
-            _end_entity: &rustls::Certificate,
-            _intermediates: &[rustls::Certificate],
-            _server_name: &rustls::ServerName,
-            _scts: &mut dyn Iterator<Item = &[u8]>,
-            _ocsp_response: &[u8],
-            _now: std::time::SystemTime,
-        ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
-            // here be dragons
-            Ok(rustls::client::ServerCertVerified::assertion())
+    if let Some((records, speed)) = replay {
+        let controllers =
+            controllers.ok_or_else(|| anyhow!("Replaying needs controller emulation enabled"))?;
+        log::info!("Replaying {} messages at {}x speed", records.len(), speed);
+        tokio::select! {
+            _ = recording::replay(&records, &controllers, speed) => log::info!("Replay finished"),
+            signal = &mut shutdown => log::info!("Received {}, stopping replay", signal),
+        }
+        let events = controllers.lock().unwrap().recorded_events();
+        release_controllers(controllers).await;
+        if let Some(events) = events {
+            println!("{}", serde_json::to_string_pretty(&events)?);
         }
+        return Ok(());
     }
-    let client_config = rustls::ClientConfig::builder()
-        .with_safe_defaults()
-        .with_custom_certificate_verifier(Arc::new(PromiscuousServerVerifier))
-        .with_no_client_auth();
-    let (socket, _response) = tokio_tungstenite::client_async_tls_with_config(
-        req,
-        stream,
-        None,
-        Some(Connector::Rustls(Arc::new(client_config))),
-    )
-    .await?;
 
-    log::info!("Strarting graphql client");
-    let ws_client = GraphQLWebSocket::new(
-        socket,
-        Some(serde_json::to_value(SessionToken { token: relay_token })?),
-    );
+    let recorder = match &conf.controller.record_dir {
+        Some(dir) => Some(Arc::new(Mutex::new(Recorder::create(Path::new(dir))?))),
+        None => None,
+    };
 
-    let signaller = Arc::new(GraphQLSignaller::new(ws_client.clone()));
-    let broadcaster = Broadcaster::new(signaller.clone()).await;
+    let capture = media::watch_capture_device(&conf.media);
+    let device = capture.borrow().clone();
+    if let Some(device) = device {
+        log::info!("Capturing from {}", device);
+        media::check_capture_modes(&conf.media, &device.video_device)?;
+    }
+    let media = MediaSource {
+        capture: capture.clone(),
+        pipeline: match conf.media.mode {
+            MediaMode::Native => None,
+            MediaMode::Plain => Some(Arc::new(Pipeline::load(
+                &conf.media,
+                Path::new(&opts.config_dir),
+            )?)),
+        },
+        alsa,
+    };
 
-    let data_producer_available = ws_client.subscribe::<signal_query::DataProducerAvailable>(
-        signal_query::data_producer_available::Variables,
-    );
-    let mut data_producer_available_stream = data_producer_available.execute();
-    println!("Press Enter to end session...");
+    let client = backend::client(&conf)?;
+
+    let control = Control::new(controllers.clone());
+    control.set_capture(capture);
+    if let Some(listener) = control_listener {
+        let control = control.clone();
+        tokio::spawn(async move {
+            if let Err(e) = control.serve(listener).await {
+                log::error!("Control socket failed: {:?}", e);
+            }
+        });
+    }
 
-    let _vcm_capturer = broadcaster
-        .produce_video_from_vcm_capturer(Some(-1), 1280, 720, 30)
-        .await;
-    let _alsa_capturer = broadcaster.produce_audio_from_default_alsa().await;
-    let mut shutdown = signaller.shutdown();
-    let mut stdin = tokio::io::stdin();
-    let mut _buf = [0];
+    // The controller gadget stays initialized across sessions so the console
+    // never sees the controllers disconnect while we re-establish the relay.
+    let mut backoff = Backoff::new(RECONNECT_DELAY_MIN, RECONNECT_DELAY_MAX);
     loop {
-        tokio::select! {
-            Some(Ok(response)) = data_producer_available_stream.next() => {
-                let data_producer_id = response.data.unwrap().data_producer_available;
-                log::trace!("data producer available: {:?}", &data_producer_id);
-                let mut data_consumer = broadcaster.consume_data(data_producer_id.clone()).await.unwrap();
-                let cont_mutex = controllers.clone();
-                tokio::spawn(async move {
-                    while let Some(message) = data_consumer.next().await {
-                        log::debug!("{:?}", message);
+        control.set_session_state(SessionState::Connecting);
+        let started = Instant::now();
+        // Cancelling the session on a signal drops the producers, broadcaster
+        // and relay connection in reverse order of creation.
+        let result = tokio::select! {
+            result = session::run(
+                &conf,
+                &opts,
+                &client,
+                &media,
+                controllers.clone(),
+                recorder.clone(),
+                &control,
+            ) => result,
+            signal = &mut shutdown => {
+                log::info!("Received {}, shutting down", signal);
+                break;
+            }
+        };
+        match result {
+            Ok(SessionEnd::Requested) => break,
+            Ok(SessionEnd::Disconnected) => {
+                if started.elapsed() > SESSION_STABLE_AFTER {
+                    backoff.reset();
+                }
+            }
+            Err(e) => log::error!("Session failed: {:?}", e),
+        }
 
-                        if let Some(cont_mutex) = &cont_mutex {
-                           if  message.len() == 13 {
-                            let mut conts = cont_mutex.lock().unwrap();
-                            let res = conts.set_state(controllers::NetworkControllerState(message.try_into().unwrap()));
-                            match &res {
-                                Err(e) => log::warn!("Error writing input: {:?}", e),
-                                Ok(_) => (),
-                            };
-                           }
-                        }
-                    }
-                    log::debug!("data producer {:?} is gone", data_producer_id);
-                });
+        control.set_session_state(SessionState::WaitingToReconnect);
+        let mut commands = control.commands();
+        let delay = backoff.next_delay();
+        log::info!("Reconnecting in {:?}", delay);
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => (),
+            Ok(command) = commands.recv() => if command == ControlCommand::EndSession {
+                break;
             },
-            _ = stdin.read(&mut _buf), if atty::is(Stream::Stdin) => {break}
-            _ = shutdown.recv() => {break},
-            else => {break}
+            signal = &mut shutdown => {
+                log::info!("Received {}, shutting down", signal);
+                break;
+            }
         }
     }
+    control.set_session_state(SessionState::Stopped);
+
+    if let Some(controllers) = controllers {
+        release_controllers(controllers).await;
+    }
+    let _ = std::fs::remove_file(&control_socket);
 
     Ok(())
 }
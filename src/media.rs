@@ -0,0 +1,370 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::config::MediaConfig;
+
+mod devices;
+
+pub use devices::{find_capture_device, list_devices, watch_capture_device, CaptureDevice};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize, Serializer};
+use v4l::capability::Flags;
+use v4l::fraction::Fraction;
+use v4l::frameinterval::FrameIntervalEnum;
+use v4l::framesize::FrameSizeEnum;
+use v4l::video::Capture;
+use v4l::Device;
+
+/// How audio and video reach the relay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaMode {
+    /// Captured and encoded in process, and sent over WebRTC.
+    Native,
+    /// Captured and encoded by an external pipeline, and sent as plain RTP.
+    Plain,
+}
+
+/// Video capture device, selected either by its index among capture devices
+/// (-1 for the first one) or by device node path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VideoDevice {
+    Index(i32),
+    Path(PathBuf),
+}
+
+impl FromStr for VideoDevice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with('/') {
+            return Ok(VideoDevice::Path(PathBuf::from(s)));
+        }
+        match s.parse() {
+            Ok(index) if index >= -1 => Ok(VideoDevice::Index(index)),
+            _ => Err(format!(
+                "expected a device index (-1 for the first) or a path, got {:?}",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for VideoDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VideoDevice::Index(index) => write!(f, "{}", index),
+            VideoDevice::Path(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+impl Serialize for VideoDevice {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// One pixel format and frame size a device can capture, with the frame
+/// rates it supports at that size.
+struct CaptureMode {
+    fourcc: String,
+    width: u32,
+    height: u32,
+    framerates: Vec<f64>,
+}
+
+impl fmt::Display for CaptureMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}x{} @", self.fourcc, self.width, self.height)?;
+        for (i, fps) in self.framerates.iter().enumerate() {
+            write!(f, "{}{}", if i == 0 { " " } else { "/" }, fps)?;
+        }
+        Ok(())
+    }
+}
+
+/// Lists video capture device nodes in the order the capturer enumerates
+/// them, so that a position in this list is a capturer device index.
+fn capture_devices() -> Vec<PathBuf> {
+    let mut nodes: Vec<(usize, PathBuf)> = v4l::context::enum_devices()
+        .into_iter()
+        .map(|node| (node.index(), node.path().to_owned()))
+        .collect();
+    nodes.sort();
+    nodes
+        .into_iter()
+        .map(|(_, path)| path)
+        .filter(|path| {
+            Device::with_path(path)
+                .and_then(|device| device.query_caps())
+                .map(|caps| caps.capabilities.contains(Flags::VIDEO_CAPTURE))
+                .unwrap_or(false)
+        })
+        .collect()
+}
+
+fn fps(interval: Fraction) -> Option<f64> {
+    if interval.numerator == 0 {
+        return None;
+    }
+    Some(interval.denominator as f64 / interval.numerator as f64)
+}
+
+/// Enumerates capture modes. Stepwise frame size ranges are reported by their
+/// bounds, plus the configured size if it falls within the range.
+fn capture_modes(device: &Device, conf: &MediaConfig) -> Result<Vec<CaptureMode>> {
+    let mut modes = Vec::new();
+    for format in device.enum_formats()? {
+        let fourcc = format.fourcc.to_string();
+        for size in device.enum_framesizes(format.fourcc)? {
+            let sizes = match size.size {
+                FrameSizeEnum::Discrete(size) => vec![(size.width, size.height)],
+                FrameSizeEnum::Stepwise(range) => {
+                    let mut sizes = vec![
+                        (range.min_width, range.min_height),
+                        (range.max_width, range.max_height),
+                    ];
+                    let in_range = |value: u32, min: u32, max: u32, step: u32| {
+                        (min..=max).contains(&value) && (value - min).is_multiple_of(step.max(1))
+                    };
+                    if in_range(
+                        conf.video_width,
+                        range.min_width,
+                        range.max_width,
+                        range.step_width,
+                    ) && in_range(
+                        conf.video_height,
+                        range.min_height,
+                        range.max_height,
+                        range.step_height,
+                    ) {
+                        sizes.push((conf.video_width, conf.video_height));
+                    }
+                    sizes
+                }
+            };
+            for (width, height) in sizes {
+                let framerates = device
+                    .enum_frameintervals(format.fourcc, width, height)?
+                    .into_iter()
+                    .filter_map(|interval| match interval.interval {
+                        FrameIntervalEnum::Discrete(interval) => fps(interval),
+                        FrameIntervalEnum::Stepwise(range) => {
+                            // report only the configured rate if it is in range
+                            let wanted = conf.video_framerate as f64;
+                            match (fps(range.max), fps(range.min)) {
+                                (Some(slowest), Some(fastest))
+                                    if (slowest..=fastest).contains(&wanted) =>
+                                {
+                                    Some(wanted)
+                                }
+                                _ => None,
+                            }
+                        }
+                    })
+                    .collect();
+                modes.push(CaptureMode {
+                    fourcc: fourcc.clone(),
+                    width,
+                    height,
+                    framerates,
+                });
+            }
+        }
+    }
+    Ok(modes)
+}
+
+fn supports(modes: &[CaptureMode], width: u32, height: u32, framerate: u32) -> bool {
+    modes.iter().any(|mode| {
+        mode.width == width
+            && mode.height == height
+            && mode
+                .framerates
+                .iter()
+                .any(|&fps| (fps - framerate as f64).abs() < 0.5)
+    })
+}
+
+/// Checks that the video device at `path` can capture at the configured size
+/// and frame rate, listing the modes it supports otherwise.
+pub fn check_capture_modes(conf: &MediaConfig, path: &Path) -> Result<()> {
+    let device = Device::with_path(path)
+        .with_context(|| format!("Couldn't open video device {}", path.display()))?;
+    let modes = match capture_modes(&device, conf) {
+        Ok(modes) if !modes.is_empty() => modes,
+        Ok(_) | Err(_) => {
+            log::warn!(
+                "Couldn't enumerate capture modes of {}, not validating {}x{}@{}",
+                path.display(),
+                conf.video_width,
+                conf.video_height,
+                conf.video_framerate
+            );
+            return Ok(());
+        }
+    };
+
+    log::info!("Capture modes supported by {}:", path.display());
+    for mode in &modes {
+        log::info!("  {}", mode);
+    }
+    if !supports(
+        &modes,
+        conf.video_width,
+        conf.video_height,
+        conf.video_framerate,
+    ) {
+        return Err(anyhow!(
+            "{} cannot capture {}x{}@{}, supported modes:\n  {}",
+            path.display(),
+            conf.video_width,
+            conf.video_height,
+            conf.video_framerate,
+            modes
+                .iter()
+                .map(CaptureMode::to_string)
+                .collect::<Vec<_>>()
+                .join("\n  ")
+        ));
+    }
+    Ok(())
+}
+
+/// Capturer device index of the video device at `path`, if it is still
+/// there.
+pub fn capturer_index(path: &Path) -> Option<i32> {
+    let path = fs::canonicalize(path).ok()?;
+    capture_devices()
+        .iter()
+        .position(|device| fs::canonicalize(device).ok().as_ref() == Some(&path))
+        .map(|index| index as i32)
+}
+
+/// ALSA card id or index in `[media] audio_card`, which may also be given as
+/// an ALSA device on the card, like `hw:1` or `plughw:CARD=MS2109,DEV=0`.
+pub fn alsa_card(value: &str) -> Result<&str, String> {
+    let card = match ["hw:", "plughw:", "sysdefault:"]
+        .iter()
+        .find_map(|prefix| value.strip_prefix(prefix))
+    {
+        Some(device) => {
+            let mut parts = device.split(',');
+            let card = parts.next().unwrap_or_default();
+            match (parts.next(), parts.next()) {
+                (None, _) => (),
+                (Some(dev), None) if dev.strip_prefix("DEV=").unwrap_or(dev) == "0" => (),
+                _ => {
+                    return Err(format!(
+                        "only device 0 of a card is captured, got {:?}",
+                        value
+                    ))
+                }
+            }
+            card.strip_prefix("CARD=").unwrap_or(card)
+        }
+        None => value,
+    };
+    if card.is_empty() || !card.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(format!(
+            "expected a card id or index, like MS2109, 1 or hw:CARD=MS2109, got {:?}",
+            value
+        ));
+    }
+    Ok(card)
+}
+
+/// The broadcaster captures audio from ALSA's default device, so in native
+/// mode a config file making the capture device's card the default is added
+/// to alsa-lib's own, and rewritten whenever the card changes. alsa-lib
+/// reloads its config when the file changes, the next time a device is
+/// opened.
+pub struct AlsaDefault {
+    path: PathBuf,
+}
+
+impl AlsaDefault {
+    /// Points alsa-lib at the config written to `path`, after its own.
+    /// Changes the environment, so it must happen before other threads start.
+    pub fn install(path: PathBuf) -> Result<Self> {
+        let alsa = AlsaDefault { path };
+        alsa.select(None)?;
+        // alsa-lib reads this list once, on first use
+        let base = std::env::var("ALSA_CONFIG_PATH")
+            .unwrap_or_else(|_| "/usr/share/alsa/alsa.conf".to_owned());
+        std::env::set_var(
+            "ALSA_CONFIG_PATH",
+            format!("{}:{}", base, alsa.path.display()),
+        );
+        Ok(alsa)
+    }
+
+    /// Makes `card`, or the system's default card if `None`, the default one.
+    pub fn select(&self, card: Option<&str>) -> Result<()> {
+        // replaced in one go, as alsa-lib may be reading it
+        let partial = self.path.with_extension("tmp");
+        fs::write(&partial, alsa_config(card))
+            .and_then(|()| fs::rename(&partial, &self.path))
+            .with_context(|| format!("Couldn't write {}", self.path.display()))
+    }
+}
+
+fn alsa_config(card: Option<&str>) -> String {
+    match card {
+        Some(card) => format!(
+            "defaults.pcm.!card \"{0}\"\ndefaults.ctl.!card \"{0}\"\n",
+            card
+        ),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alsa_cards() {
+        let cases = [
+            ("MS2109", Ok("MS2109")),
+            ("1", Ok("1")),
+            ("hw:1", Ok("1")),
+            ("hw:1,0", Ok("1")),
+            ("hw:CARD=MS2109", Ok("MS2109")),
+            ("plughw:CARD=MS2109,DEV=0", Ok("MS2109")),
+            ("sysdefault:CARD=USB_Audio", Ok("USB_Audio")),
+            ("hw:CARD=MS2109,DEV=1", Err("only device 0")),
+            ("hw:1,0,0", Err("only device 0")),
+            ("hw:", Err("expected a card id")),
+            ("dsnoop:1", Err("expected a card id")),
+            ("", Err("expected a card id")),
+            ("MS 2109", Err("expected a card id")),
+        ];
+        for (value, expected) in cases.iter() {
+            match (alsa_card(value), expected) {
+                (Ok(card), Ok(expected)) => assert_eq!(card, *expected, "{:?}", value),
+                (Err(e), Err(expected)) => assert!(e.starts_with(expected), "{:?}: {}", value, e),
+                (result, _) => panic!("{:?}: {:?}", value, result),
+            }
+        }
+    }
+
+    #[test]
+    fn alsa_default_is_replaced() {
+        let path = std::env::temp_dir().join(format!("vulcast-asound-{}.conf", std::process::id()));
+        let alsa = AlsaDefault { path: path.clone() };
+        alsa.select(Some("MS2109")).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "defaults.pcm.!card \"MS2109\"\ndefaults.ctl.!card \"MS2109\"\n"
+        );
+        alsa.select(None).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+        assert!(!path.with_extension("tmp").exists());
+        fs::remove_file(&path).unwrap();
+    }
+}
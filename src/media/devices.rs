@@ -0,0 +1,564 @@
+//! Finding the capture device through sysfs, and noticing when it is
+//! unplugged and plugged back in.
+//!
+//! A USB capture dongle shows up as a V4L2 capture node and, usually, an
+//! ALSA card under the same USB device. With `[media] capture_usb_id` or
+//! `capture_name` set, both are taken from the first USB device that
+//! matches; otherwise `video_device` and `audio_card` are used as given.
+
+use super::{alsa_card, capture_devices, VideoDevice};
+use crate::config::MediaConfig;
+
+use serde::Serialize;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::watch;
+
+const VIDEO4LINUX_CLASS: &str = "/sys/class/video4linux";
+const SOUND_CLASS: &str = "/sys/class/sound";
+/// How often sysfs is checked for devices coming and going.
+const HOTPLUG_POLL: Duration = Duration::from_secs(1);
+
+/// Where video and audio are captured from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CaptureDevice {
+    pub video_device: PathBuf,
+    /// ALSA card id, or `None` for the default card.
+    pub audio_card: Option<String>,
+    /// `vendor:product` of the USB device, if it is one.
+    pub usb_id: Option<String>,
+    /// USB product or V4L2 device name.
+    pub name: String,
+}
+
+impl fmt::Display for CaptureDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}", self.name, self.video_device.display())?;
+        if let Some(usb_id) = &self.usb_id {
+            write!(f, " ({})", usb_id)?;
+        }
+        match &self.audio_card {
+            Some(card) => write!(f, " with ALSA card {}", card),
+            None => write!(f, " with the default ALSA card"),
+        }
+    }
+}
+
+fn read_attribute(path: &Path) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|value| value.trim().to_owned())
+}
+
+#[derive(Debug, Clone)]
+struct UsbDevice {
+    /// sysfs directory, the same for every interface of the device.
+    path: PathBuf,
+    id: String,
+    product: Option<String>,
+}
+
+/// The USB device a sysfs class device belongs to, if any.
+fn usb_device(class_device: &Path) -> Option<UsbDevice> {
+    let device = fs::canonicalize(class_device.join("device")).ok()?;
+    let path = device
+        .ancestors()
+        .find(|dir| dir.join("idVendor").exists())?;
+    let id = format!(
+        "{}:{}",
+        read_attribute(&path.join("idVendor"))?,
+        read_attribute(&path.join("idProduct"))?
+    );
+    Some(UsbDevice {
+        path: path.to_owned(),
+        id,
+        product: read_attribute(&path.join("product")),
+    })
+}
+
+#[derive(Debug, Clone)]
+struct VideoNode {
+    path: PathBuf,
+    name: String,
+    usb: Option<UsbDevice>,
+}
+
+fn video_nodes() -> Vec<VideoNode> {
+    capture_devices()
+        .into_iter()
+        .map(|path| {
+            let class_device = match path.file_name() {
+                Some(node) => Path::new(VIDEO4LINUX_CLASS).join(node),
+                None => PathBuf::new(),
+            };
+            VideoNode {
+                name: read_attribute(&class_device.join("name")).unwrap_or_default(),
+                usb: usb_device(&class_device),
+                path,
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+struct SoundCard {
+    index: u32,
+    id: String,
+    usb: Option<UsbDevice>,
+}
+
+/// ALSA cards, with the USB device each belongs to.
+fn sound_cards() -> Vec<SoundCard> {
+    let entries = match fs::read_dir(SOUND_CLASS) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut paths: Vec<(u32, PathBuf)> = entries
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let index = path
+                .file_name()?
+                .to_str()?
+                .strip_prefix("card")?
+                .parse()
+                .ok()?;
+            Some((index, path))
+        })
+        .collect();
+    paths.sort();
+    paths
+        .into_iter()
+        .filter_map(|(index, path)| {
+            Some(SoundCard {
+                index,
+                id: read_attribute(&path.join("id"))?,
+                usb: usb_device(&path),
+            })
+        })
+        .collect()
+}
+
+/// Matches `text` against a pattern where `*` stands for any run of
+/// characters and `?` for any one, ignoring case.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // pattern position after the last `*`, and the text position it matched to
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                backtrack = Some((p, t));
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((after_star, matched)) => {
+                    p = after_star;
+                    t = matched + 1;
+                    backtrack = Some((after_star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Finds the capture device the config selects, or says why it can't.
+pub fn find_capture_device(conf: &MediaConfig) -> Result<CaptureDevice, String> {
+    select_device(conf, video_nodes(), &sound_cards())
+}
+
+fn select_device(
+    conf: &MediaConfig,
+    nodes: Vec<VideoNode>,
+    cards: &[SoundCard],
+) -> Result<CaptureDevice, String> {
+    if conf.capture_usb_id.is_none() && conf.capture_name.is_none() {
+        return select_configured(conf, nodes, cards);
+    }
+    nodes
+        .into_iter()
+        .find_map(|node| {
+            let usb_id = node.usb.as_ref().map(|usb| usb.id.as_str());
+            let product = node.usb.as_ref().and_then(|usb| usb.product.as_deref());
+            let id_matches = match &conf.capture_usb_id {
+                Some(wanted) => usb_id.is_some_and(|id| id.eq_ignore_ascii_case(wanted)),
+                None => true,
+            };
+            let name_matches = match &conf.capture_name {
+                Some(pattern) => {
+                    glob_match(pattern, &node.name)
+                        || product.is_some_and(|p| glob_match(pattern, p))
+                }
+                None => true,
+            };
+            if !id_matches || !name_matches {
+                return None;
+            }
+            let audio_card = cards
+                .iter()
+                .find(|card| match (&card.usb, &node.usb) {
+                    (Some(card), Some(video)) => card.path == video.path,
+                    _ => false,
+                })
+                .map(|card| card.id.clone());
+            Some(CaptureDevice {
+                name: product.unwrap_or(&node.name).to_owned(),
+                usb_id: usb_id.map(str::to_owned),
+                audio_card,
+                video_device: node.path,
+            })
+        })
+        .ok_or_else(|| {
+            let mut wanted = Vec::new();
+            if let Some(usb_id) = &conf.capture_usb_id {
+                wanted.push(format!("USB id {}", usb_id));
+            }
+            if let Some(pattern) = &conf.capture_name {
+                wanted.push(format!("name matching {:?}", pattern));
+            }
+            format!("no video capture device with {}", wanted.join(" and "))
+        })
+}
+
+/// Finds `[media] video_device` and `audio_card`, if both are present.
+fn select_configured(
+    conf: &MediaConfig,
+    nodes: Vec<VideoNode>,
+    cards: &[SoundCard],
+) -> Result<CaptureDevice, String> {
+    let missing = || format!("no video capture device {}", conf.video_device);
+    let node = match &conf.video_device {
+        VideoDevice::Index(-1) => nodes.into_iter().next().ok_or_else(missing)?,
+        VideoDevice::Index(index) => nodes.into_iter().nth(*index as usize).ok_or_else(missing)?,
+        VideoDevice::Path(path) => {
+            // kept as configured, as /dev/v4l/by-id links survive replugging
+            let target = fs::canonicalize(path).map_err(|_| missing())?;
+            let node = nodes
+                .into_iter()
+                .find(|node| fs::canonicalize(&node.path).ok().as_ref() == Some(&target))
+                .ok_or_else(missing)?;
+            VideoNode {
+                path: path.clone(),
+                ..node
+            }
+        }
+    };
+    let audio_card = match &conf.audio_card {
+        Some(wanted) => {
+            // an index is resolved to the card's id, which stays the same
+            // when cards are renumbered
+            let wanted = alsa_card(wanted)?;
+            let card = cards
+                .iter()
+                .find(|card| card.id == wanted || card.index.to_string() == wanted)
+                .ok_or_else(|| {
+                    format!(
+                        "no ALSA card {}, the cards are: {}",
+                        wanted,
+                        cards
+                            .iter()
+                            .map(|card| format!("{} ({})", card.id, card.index))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )
+                })?;
+            Some(card.id.clone())
+        }
+        None => None,
+    };
+    Ok(CaptureDevice {
+        name: node
+            .usb
+            .as_ref()
+            .and_then(|usb| usb.product.clone())
+            .unwrap_or(node.name),
+        usb_id: node.usb.map(|usb| usb.id),
+        audio_card,
+        video_device: node.path,
+    })
+}
+
+/// Every video capture device and sound card, for picking a selector.
+pub fn list_devices() -> Vec<String> {
+    let cards = sound_cards();
+    let mut devices: Vec<String> = video_nodes()
+        .into_iter()
+        .map(|node| match &node.usb {
+            Some(usb) => format!(
+                "{} {:?} ({})",
+                node.path.display(),
+                usb.product.as_deref().unwrap_or(&node.name),
+                usb.id
+            ),
+            None => format!("{} {:?}", node.path.display(), node.name),
+        })
+        .collect();
+    devices.extend(cards.into_iter().map(|card| match card.usb {
+        Some(usb) => format!("ALSA card {} ({}, {})", card.id, card.index, usb.id),
+        None => format!("ALSA card {} ({})", card.id, card.index),
+    }));
+    devices
+}
+
+/// Names in the sysfs classes, which change whenever a device comes or goes.
+fn class_listing() -> Vec<PathBuf> {
+    let mut listing: Vec<PathBuf> = [VIDEO4LINUX_CLASS, SOUND_CLASS]
+        .iter()
+        .filter_map(|class| fs::read_dir(class).ok())
+        .flatten()
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .collect();
+    listing.sort();
+    listing
+}
+
+/// Finds the capture device now and again whenever devices come and go,
+/// logging when it is unplugged or plugged in.
+pub fn watch_capture_device(conf: &MediaConfig) -> watch::Receiver<Option<CaptureDevice>> {
+    let conf = conf.clone();
+    let found = find_capture_device(&conf);
+    if let Err(reason) = &found {
+        log::warn!(
+            "Capture device not found ({}), waiting for it to be plugged in; available devices: {:?}",
+            reason,
+            list_devices()
+        );
+    }
+    let (device_tx, device) = watch::channel(found.ok());
+    tokio::spawn(async move {
+        let mut listing = class_listing();
+        let mut interval = tokio::time::interval(HOTPLUG_POLL);
+        loop {
+            interval.tick().await;
+            let current = class_listing();
+            // a device just plugged in may not be openable yet, so keep
+            // looking while there is none
+            if current == listing && device_tx.borrow().is_some() {
+                continue;
+            }
+            listing = current;
+            let found = find_capture_device(&conf);
+            if found.as_ref().ok() == device_tx.borrow().as_ref() {
+                continue;
+            }
+            match &found {
+                Ok(found) => {
+                    log::info!("Capture device {} plugged in", found);
+                    if let Err(e) = super::check_capture_modes(&conf, &found.video_device) {
+                        log::warn!("{:?}", e);
+                    }
+                }
+                Err(reason) => log::warn!("Capture device unplugged: {}", reason),
+            }
+            device_tx.send_replace(found.ok());
+        }
+    });
+    device
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_matches() {
+        let cases = [
+            ("MS2109", "MS2109", true),
+            ("ms2109", "MS2109", true),
+            ("*MS2109*", "USB Video MS2109 Capture", true),
+            ("*MS2109*", "MS2109", true),
+            ("*MS2109*", "MS210", false),
+            ("MS210?", "MS2109", true),
+            ("MS210?", "MS210", false),
+            ("MS210?", "MS21099", false),
+            ("?*", "", false),
+            ("*", "", true),
+            ("*", "anything", true),
+            ("", "", true),
+            ("", "MS2109", false),
+            ("*a*b", "xaxxab", true),
+            ("*a*b", "aab", true),
+            ("*a*b", "abba", false),
+            ("*a*b", "ba", false),
+            ("a*b*c", "abxbc", true),
+            ("a*b*c", "acb", false),
+            ("**", "x", true),
+        ];
+        for case in cases.iter() {
+            let (pattern, text, matches) = *case;
+            assert_eq!(glob_match(pattern, text), matches, "{:?}", case);
+        }
+    }
+
+    fn usb(path: &str, id: &str, product: Option<&str>) -> Option<UsbDevice> {
+        Some(UsbDevice {
+            path: PathBuf::from(path),
+            id: id.to_owned(),
+            product: product.map(str::to_owned),
+        })
+    }
+
+    fn devices() -> (Vec<VideoNode>, Vec<SoundCard>) {
+        let nodes = vec![
+            VideoNode {
+                path: "/dev/video0".into(),
+                name: "Integrated Camera".to_owned(),
+                usb: usb("/sys/devices/usb1/1-1", "04f2:b6dd", None),
+            },
+            VideoNode {
+                path: "/dev/video2".into(),
+                name: "USB Video: USB Video".to_owned(),
+                usb: usb("/sys/devices/usb1/1-2", "534d:2109", Some("MS2109")),
+            },
+            VideoNode {
+                path: "/dev/video4".into(),
+                name: "bcm2835-codec".to_owned(),
+                usb: None,
+            },
+        ];
+        let cards = vec![
+            SoundCard {
+                index: 0,
+                id: "Headphones".to_owned(),
+                usb: None,
+            },
+            SoundCard {
+                index: 1,
+                id: "MS2109".to_owned(),
+                usb: usb("/sys/devices/usb1/1-2", "534d:2109", Some("MS2109")),
+            },
+        ];
+        (nodes, cards)
+    }
+
+    #[test]
+    fn selects_usb_devices() {
+        let cases = [
+            (Some("534d:2109"), None, Ok(("/dev/video2", Some("MS2109")))),
+            (Some("534D:2109"), None, Ok(("/dev/video2", Some("MS2109")))),
+            (None, Some("*ms2109*"), Ok(("/dev/video2", Some("MS2109")))),
+            // the V4L2 name counts too, and the camera has no sound card
+            (None, Some("Integrated*"), Ok(("/dev/video0", None))),
+            (None, Some("*"), Ok(("/dev/video0", None))),
+            (
+                Some("534d:2109"),
+                Some("*Camera"),
+                Err("no video capture device with USB id 534d:2109 and name matching \"*Camera\""),
+            ),
+            (
+                Some("1234:5678"),
+                None,
+                Err("no video capture device with USB id 1234:5678"),
+            ),
+        ];
+        for case in cases.iter() {
+            let (usb_id, name, expected) = case;
+            let conf = MediaConfig {
+                capture_usb_id: usb_id.map(str::to_owned),
+                capture_name: name.map(str::to_owned),
+                // ignored when selecting by USB id or name
+                audio_card: Some("Headphones".to_owned()),
+                ..MediaConfig::default()
+            };
+            let (nodes, cards) = devices();
+            let found = select_device(&conf, nodes, &cards);
+            let found = found
+                .as_ref()
+                .map(|device| {
+                    (
+                        device.video_device.to_str().unwrap(),
+                        device.audio_card.as_deref(),
+                    )
+                })
+                .map_err(String::as_str);
+            assert_eq!(&found, expected, "{:?}", case);
+        }
+    }
+
+    #[test]
+    fn selects_configured_devices() {
+        let cases = [
+            (-1, None, Ok(("/dev/video0", None))),
+            (1, Some("hw:1"), Ok(("/dev/video2", Some("MS2109")))),
+            (
+                2,
+                Some("Headphones"),
+                Ok(("/dev/video4", Some("Headphones"))),
+            ),
+            (
+                0,
+                Some("plughw:CARD=MS2109,DEV=0"),
+                Ok(("/dev/video0", Some("MS2109"))),
+            ),
+            (3, None, Err("no video capture device 3")),
+            (
+                0,
+                Some("hw:2"),
+                Err("no ALSA card 2, the cards are: Headphones (0), MS2109 (1)"),
+            ),
+            (
+                0,
+                Some("hw:1,1"),
+                Err("only device 0 of a card is captured, got \"hw:1,1\""),
+            ),
+        ];
+        for case in cases.iter() {
+            let (index, card, expected) = case;
+            let conf = MediaConfig {
+                video_device: VideoDevice::Index(*index),
+                audio_card: card.map(str::to_owned),
+                ..MediaConfig::default()
+            };
+            let (nodes, cards) = devices();
+            let found = select_device(&conf, nodes, &cards);
+            let found = found
+                .as_ref()
+                .map(|device| {
+                    (
+                        device.video_device.to_str().unwrap(),
+                        device.audio_card.as_deref(),
+                    )
+                })
+                .map_err(String::as_str);
+            assert_eq!(&found, expected, "{:?}", case);
+        }
+    }
+
+    #[test]
+    fn configured_paths_are_kept() {
+        let dir = std::env::temp_dir().join(format!("vulcast-devices-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let node = dir.join("video2");
+        let link = dir.join("usb-MS2109-video-index0");
+        fs::write(&node, "").unwrap();
+        let _ = fs::remove_file(&link);
+        std::os::unix::fs::symlink(&node, &link).unwrap();
+
+        let (mut nodes, cards) = devices();
+        nodes[1].path = node;
+        let conf = MediaConfig {
+            video_device: VideoDevice::Path(link.clone()),
+            ..MediaConfig::default()
+        };
+        let device = select_device(&conf, nodes.clone(), &cards).unwrap();
+        assert_eq!(device.video_device, link);
+        assert_eq!(device.name, "MS2109");
+        assert_eq!(device.usb_id.as_deref(), Some("534d:2109"));
+
+        let conf = MediaConfig {
+            video_device: VideoDevice::Path(dir.join("missing")),
+            ..MediaConfig::default()
+        };
+        assert!(select_device(&conf, nodes, &cards).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
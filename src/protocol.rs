@@ -0,0 +1,402 @@
+//! Wire format of the messages players send over their data channels.
+//!
+//! Legacy (version 0) messages are exactly 13 bytes, multi-byte values big
+//! endian:
+//!
+//! | offset | size | field                                         |
+//! |--------|------|-----------------------------------------------|
+//! | 0      | 1    | player id (0-3)                               |
+//! | 1      | 1    | sequence number                               |
+//! | 2      | 3    | buttons 0-16, least significant bit first     |
+//! | 5      | 8    | sticks: lh, lv, rh, rv as u16                 |
+//!
+//! Versioned messages start with a header byte `1vvv tttt` holding the
+//! protocol version `v` (1-7) and message type `t`. Legacy player ids never
+//! have the high bit set, so the two cannot be confused. A version 1 input
+//! message (type 0, header `0x90`) is:
+//!
+//! | offset | size | field                                         |
+//! |--------|------|-----------------------------------------------|
+//! | 0      | 1    | header                                        |
+//! | 1      | 1    | player id                                     |
+//! | 2      | 1    | sequence number                               |
+//! | 3      | 4    | buttons 0-31, least significant bit first     |
+//! | 7      | 8    | sticks: lh, lv, rh, rv as u16                 |
+//! | 15     | 4    | analog triggers: left, right as u16           |
+//! | 19     | 1    | flags; bit 0: motion data follows             |
+//! | 20     | 12   | accel x, y, z then gyro x, y, z as i16        |
+//!
+//! Trailing bytes are ignored, so fields can be appended within a version
+//! without breaking older firmware. Buttons 20-23 are paddles, which only
+//! have an effect once remapped.
+//!
+//! A player selects a remapping profile from `profiles.conf` with a type 3
+//! message (header `0x93`): the player id, then the profile name in UTF-8,
+//! or nothing to go back to the default layout.
+//!
+//! A type 4 message (header `0x94`) followed by the player id asks for the
+//! player's stick centers to be calibrated from their next inputs.
+//!
+//! Type 5 (header `0x95`) turns turbo on or off: the player id, the button
+//! index, then 1 for on or 0 for off. Type 6 (header `0x96`) plays a macro
+//! from `macros.conf`: the player id, then the macro name in UTF-8, or
+//! nothing to stop the macro playing.
+//!
+//! Types 1 and 2 are reserved for rumble and player LEDs sent back to the
+//! players.
+
+use crate::controllers::{Motion, NetworkControllerState, LEGACY_BUTTONS};
+
+use std::fmt;
+
+const LEGACY_LENGTH: usize = 13;
+/// Longest message accepted, well above any valid one.
+pub const MAX_MESSAGE_LENGTH: usize = 256;
+const VERSIONED: u8 = 0x80;
+
+const TYPE_INPUT: u8 = 0;
+const TYPE_SELECT_PROFILE: u8 = 3;
+const TYPE_CALIBRATE_STICKS: u8 = 4;
+const TYPE_SET_TURBO: u8 = 5;
+const TYPE_PLAY_MACRO: u8 = 6;
+
+const V1_INPUT_LENGTH: usize = 20;
+const V1_MOTION_LENGTH: usize = 12;
+const V1_FLAG_MOTION: u8 = 1;
+
+/// Why a message could not be decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Shorter than its type requires.
+    Undersized(String),
+    /// Longer than its type or `MAX_MESSAGE_LENGTH` allows.
+    Oversized(String),
+    /// Unsupported version or type, or invalid contents.
+    Malformed(String),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Undersized(reason)
+            | DecodeError::Oversized(reason)
+            | DecodeError::Malformed(reason) => f.write_str(reason),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+type Result<T> = std::result::Result<T, DecodeError>;
+
+/// A decoded data channel message.
+#[derive(Debug, Clone)]
+pub enum Message {
+    Input(NetworkControllerState),
+    SelectProfile {
+        player_id: u8,
+        /// `None` for the default layout.
+        profile: Option<String>,
+    },
+    CalibrateSticks {
+        player_id: u8,
+    },
+    SetTurbo {
+        player_id: u8,
+        button: u8,
+        enabled: bool,
+    },
+    PlayMacro {
+        player_id: u8,
+        /// `None` to stop playback.
+        name: Option<String>,
+    },
+}
+
+impl Message {
+    pub fn player_id(&self) -> u8 {
+        match self {
+            Message::Input(state) => state.player_id,
+            Message::SelectProfile { player_id, .. }
+            | Message::CalibrateSticks { player_id }
+            | Message::SetTurbo { player_id, .. }
+            | Message::PlayMacro { player_id, .. } => *player_id,
+        }
+    }
+
+    pub fn set_player_id(&mut self, id: u8) {
+        match self {
+            Message::Input(state) => state.player_id = id,
+            Message::SelectProfile { player_id, .. }
+            | Message::CalibrateSticks { player_id }
+            | Message::SetTurbo { player_id, .. }
+            | Message::PlayMacro { player_id, .. } => *player_id = id,
+        }
+    }
+}
+
+fn be_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn be_axes(bytes: &[u8], offset: usize) -> [u16; 4] {
+    [0, 1, 2, 3].map(|i| be_u16(bytes, offset + 2 * i))
+}
+
+/// Decodes a message in any supported version of the wire format.
+pub fn decode(message: &[u8]) -> Result<Message> {
+    let header = *message
+        .first()
+        .ok_or_else(|| DecodeError::Undersized("Empty message".to_owned()))?;
+    if message.len() > MAX_MESSAGE_LENGTH {
+        return Err(DecodeError::Oversized(format!(
+            "Message must be at most {} bytes, got {}",
+            MAX_MESSAGE_LENGTH,
+            message.len()
+        )));
+    }
+    if header & VERSIONED == 0 {
+        return decode_legacy(message).map(Message::Input);
+    }
+
+    let version = (header & 0x70) >> 4;
+    let message_type = header & 0x0f;
+    match (version, message_type) {
+        (1, TYPE_INPUT) => decode_v1_input(message).map(Message::Input),
+        (1, TYPE_SELECT_PROFILE) => {
+            let (player_id, profile) = decode_v1_name(message)?;
+            Ok(Message::SelectProfile { player_id, profile })
+        }
+        (1, TYPE_CALIBRATE_STICKS) => match message.get(1) {
+            Some(&player_id) => Ok(Message::CalibrateSticks { player_id }),
+            None => Err(DecodeError::Undersized(
+                "Calibration request is missing the player id".to_owned(),
+            )),
+        },
+        (1, TYPE_SET_TURBO) => match *message {
+            [_, player_id, button, enabled, ..] => Ok(Message::SetTurbo {
+                player_id,
+                button,
+                enabled: enabled != 0,
+            }),
+            _ => Err(DecodeError::Undersized(
+                "Turbo message must be at least 4 bytes".to_owned(),
+            )),
+        },
+        (1, TYPE_PLAY_MACRO) => {
+            let (player_id, name) = decode_v1_name(message)?;
+            Ok(Message::PlayMacro { player_id, name })
+        }
+        (1, _) => Err(DecodeError::Malformed(format!(
+            "Unknown message type {}",
+            message_type
+        ))),
+        _ => Err(DecodeError::Malformed(format!(
+            "Unsupported protocol version {}",
+            version
+        ))),
+    }
+}
+
+fn decode_legacy(message: &[u8]) -> Result<NetworkControllerState> {
+    if message.len() != LEGACY_LENGTH {
+        let reason = format!(
+            "Legacy input message must be {} bytes, got {}",
+            LEGACY_LENGTH,
+            message.len()
+        );
+        return Err(if message.len() < LEGACY_LENGTH {
+            DecodeError::Undersized(reason)
+        } else {
+            DecodeError::Oversized(reason)
+        });
+    }
+    let buttons = u32::from_le_bytes([message[2], message[3], message[4], 0]);
+    Ok(NetworkControllerState {
+        player_id: message[0],
+        sequence_no: message[1],
+        legacy: true,
+        // the unused high bits now carry buttons legacy clients don't know
+        buttons: buttons & ((1 << LEGACY_BUTTONS) - 1),
+        axes: be_axes(message, 5),
+        triggers: None,
+        motion: None,
+    })
+}
+
+fn decode_v1_input(message: &[u8]) -> Result<NetworkControllerState> {
+    if message.len() < V1_INPUT_LENGTH {
+        return Err(DecodeError::Undersized(format!(
+            "Input message must be at least {} bytes, got {}",
+            V1_INPUT_LENGTH,
+            message.len()
+        )));
+    }
+    let motion = if message[19] & V1_FLAG_MOTION != 0 {
+        let motion = message
+            .get(V1_INPUT_LENGTH..V1_INPUT_LENGTH + V1_MOTION_LENGTH)
+            .ok_or_else(|| {
+                DecodeError::Undersized(
+                    "Input message is flagged with motion data but too short".to_owned(),
+                )
+            })?;
+        let value = |i: usize| be_u16(motion, 2 * i) as i16;
+        Some(Motion {
+            accel: [value(0), value(1), value(2)],
+            gyro: [value(3), value(4), value(5)],
+        })
+    } else {
+        None
+    };
+    Ok(NetworkControllerState {
+        player_id: message[1],
+        sequence_no: message[2],
+        legacy: false,
+        buttons: u32::from_le_bytes([message[3], message[4], message[5], message[6]]),
+        axes: be_axes(message, 7),
+        triggers: Some([be_u16(message, 15), be_u16(message, 17)]),
+        motion,
+    })
+}
+
+/// Decodes a player id followed by an optional UTF-8 name.
+fn decode_v1_name(message: &[u8]) -> Result<(u8, Option<String>)> {
+    let player_id = *message
+        .get(1)
+        .ok_or_else(|| DecodeError::Undersized("Message is missing the player id".to_owned()))?;
+    let name = std::str::from_utf8(&message[2..])
+        .map_err(|e| DecodeError::Malformed(format!("Name is not UTF-8: {}", e)))?;
+    Ok((
+        player_id,
+        Some(name.to_owned()).filter(|name| !name.is_empty()),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(message: &[u8]) -> NetworkControllerState {
+        match decode(message) {
+            Ok(Message::Input(state)) => state,
+            other => panic!("expected input, got {:?}", other),
+        }
+    }
+
+    fn v1_input(flags: u8) -> Vec<u8> {
+        let mut message = vec![0x90, 2, 42, 0x01, 0x00, 0x10, 0x00];
+        message.extend_from_slice(&[0x12, 0x34, 0x80, 0x00, 0x00, 0x00, 0xff, 0xff]);
+        message.extend_from_slice(&[0x00, 0x10, 0xff, 0xff]);
+        message.push(flags);
+        message
+    }
+
+    #[test]
+    fn legacy_input() {
+        let state = input(&[
+            3, 9, 0x05, 0x00, 0xff, 0x12, 0x34, 0x80, 0x00, 0x00, 0x00, 0xff, 0xff,
+        ]);
+        assert_eq!(state.player_id(), 3);
+        assert_eq!(state.sequence_no(), 9);
+        assert!(state.legacy);
+        // only the 17 legacy buttons are taken from the three button bytes
+        assert_eq!(state.buttons, 0x0001_0005);
+        assert_eq!(state.axes, [0x1234, 0x8000, 0x0000, 0xffff]);
+        assert_eq!(state.triggers, None);
+        assert_eq!(state.motion(), None);
+    }
+
+    #[test]
+    fn legacy_input_must_be_exact_length() {
+        assert!(matches!(decode(&[0; 12]), Err(DecodeError::Undersized(_))));
+        assert!(matches!(decode(&[0; 14]), Err(DecodeError::Oversized(_))));
+    }
+
+    #[test]
+    fn v1_input_without_motion() {
+        let state = input(&v1_input(0));
+        assert_eq!(state.player_id(), 2);
+        assert_eq!(state.sequence_no(), 42);
+        assert!(!state.legacy);
+        assert_eq!(state.buttons, 0x0010_0001);
+        assert_eq!(state.axes, [0x1234, 0x8000, 0x0000, 0xffff]);
+        assert_eq!(state.triggers, Some([0x0010, 0xffff]));
+        assert_eq!(state.motion(), None);
+    }
+
+    #[test]
+    fn v1_input_with_motion() {
+        let mut message = v1_input(V1_FLAG_MOTION);
+        message.extend_from_slice(&[0, 1, 0xff, 0xff, 0x80, 0x00, 0x7f, 0xff, 0, 0, 0, 2]);
+        // trailing bytes are ignored
+        message.push(0xaa);
+        let state = input(&message);
+        assert_eq!(
+            state.motion(),
+            Some(Motion {
+                accel: [1, -1, i16::MIN],
+                gyro: [i16::MAX, 0, 2],
+            })
+        );
+    }
+
+    #[test]
+    fn v1_input_undersized() {
+        let message = v1_input(0);
+        assert!(matches!(
+            decode(&message[..V1_INPUT_LENGTH - 1]),
+            Err(DecodeError::Undersized(_))
+        ));
+        let mut message = v1_input(V1_FLAG_MOTION);
+        message.extend_from_slice(&[0; V1_MOTION_LENGTH - 1]);
+        assert!(matches!(decode(&message), Err(DecodeError::Undersized(_))));
+    }
+
+    #[test]
+    fn oversized_message() {
+        let mut message = v1_input(0);
+        message.resize(MAX_MESSAGE_LENGTH, 0);
+        assert!(decode(&message).is_ok());
+        message.push(0);
+        assert!(matches!(decode(&message), Err(DecodeError::Oversized(_))));
+    }
+
+    #[test]
+    fn empty_message() {
+        assert!(matches!(decode(&[]), Err(DecodeError::Undersized(_))));
+    }
+
+    #[test]
+    fn unknown_version() {
+        assert!(matches!(
+            decode(&[0xa0, 0, 0, 0]),
+            Err(DecodeError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn unknown_message_type() {
+        assert!(matches!(
+            decode(&[0x9f, 0, 0, 0]),
+            Err(DecodeError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn named_messages() {
+        match decode(b"\x93\x01fighting") {
+            Ok(Message::SelectProfile { player_id, profile }) => {
+                assert_eq!(player_id, 1);
+                assert_eq!(profile.as_deref(), Some("fighting"));
+            }
+            other => panic!("expected a profile selection, got {:?}", other),
+        }
+        match decode(&[0x96, 0]) {
+            Ok(Message::PlayMacro { player_id, name }) => {
+                assert_eq!(player_id, 0);
+                assert_eq!(name, None);
+            }
+            other => panic!("expected a macro, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,241 @@
+//! Recording of every data channel message the firmware receives, and
+//! replaying recordings into the controllers.
+//!
+//! A recording is the magic bytes `VCREC002`, followed by one record per
+//! message, multi-byte values little endian:
+//!
+//! | size | field                                                       |
+//! |------|-------------------------------------------------------------|
+//! | 8    | microseconds since the recording started, as u64            |
+//! | 2    | data producer, numbered in order of first message, as u16   |
+//! | 1    | controller the message went to, or `0xff` if it was dropped |
+//! | 2    | message length as u16                                       |
+//! | n    | the message as received                                     |
+//!
+//! Recordings made before the data producer was recorded start with
+//! `VCREC001`, have no producer field, and hold the player id the message
+//! carried instead of the controller it went to.
+
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::controllers::ControllerSlots;
+use crate::protocol;
+use crate::session;
+
+use anyhow::{anyhow, Context, Result};
+use vulcast_rtc::types::DataProducerId;
+
+const MAGIC: &[u8; 8] = b"VCREC002";
+const RECORD_HEADER_LENGTH: usize = 13;
+const MAGIC_V1: &[u8; 8] = b"VCREC001";
+const RECORD_HEADER_LENGTH_V1: usize = 11;
+const UNKNOWN_PLAYER: u8 = 0xff;
+
+/// Appends messages to a recording file.
+pub struct Recorder {
+    /// `None` once writing has failed, so a full disk is only reported once.
+    file: Option<File>,
+    started: Instant,
+    /// Data producers seen so far, numbered by position.
+    producers: Vec<DataProducerId>,
+}
+
+impl Recorder {
+    /// Starts a new recording in `dir`, named after the current time.
+    pub fn create(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Couldn't create recording directory {}", dir.display()))?;
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let path = dir.join(format!("input-{}.rec", timestamp));
+        let mut file = File::create(&path)
+            .with_context(|| format!("Couldn't create recording {}", path.display()))?;
+        file.write_all(MAGIC)?;
+        log::info!("Recording data channel messages to {}", path.display());
+        Ok(Self {
+            file: Some(file),
+            started: Instant::now(),
+            producers: Vec::new(),
+        })
+    }
+
+    /// Records a message from `producer`, with the controller it was
+    /// applied to, if any.
+    pub fn record(&mut self, producer: &DataProducerId, message: &[u8], slot: Option<usize>) {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => return,
+        };
+        let number = match self.producers.iter().position(|seen| seen == producer) {
+            Some(number) => number,
+            None => {
+                log::info!(
+                    "Recording data producer {:?} as {}",
+                    producer,
+                    self.producers.len()
+                );
+                self.producers.push(producer.clone());
+                self.producers.len() - 1
+            }
+        };
+        let at = self.started.elapsed().as_micros() as u64;
+        let length = message.len().min(u16::MAX as usize);
+        let mut record = Vec::with_capacity(RECORD_HEADER_LENGTH + length);
+        record.extend_from_slice(&at.to_le_bytes());
+        record.extend_from_slice(&(number as u16).to_le_bytes());
+        record.push(slot.map_or(UNKNOWN_PLAYER, |slot| slot as u8));
+        record.extend_from_slice(&(length as u16).to_le_bytes());
+        record.extend_from_slice(&message[..length]);
+        // one write per record, so a crash leaves at most one torn record
+        if let Err(e) = file.write_all(&record) {
+            log::error!("Stopped recording after failing to write: {:?}", e);
+            self.file = None;
+        }
+    }
+}
+
+/// A recorded message.
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub at: Duration,
+    /// Number of the data producer that sent it, if recorded.
+    pub producer: Option<u16>,
+    /// Controller the message was applied to, or in old recordings the
+    /// player id it carried.
+    pub player_id: Option<u8>,
+    pub message: Vec<u8>,
+}
+
+/// Reads every complete record in a recording.
+pub fn read(path: &Path) -> Result<Vec<Record>> {
+    let data =
+        fs::read(path).with_context(|| format!("Couldn't read recording {}", path.display()))?;
+    let (mut rest, header_length) = if let Some(rest) = data.strip_prefix(MAGIC) {
+        (rest, RECORD_HEADER_LENGTH)
+    } else if let Some(rest) = data.strip_prefix(MAGIC_V1) {
+        (rest, RECORD_HEADER_LENGTH_V1)
+    } else {
+        return Err(anyhow!("{} is not an input recording", path.display()));
+    };
+
+    let mut records = Vec::new();
+    while rest.len() >= header_length {
+        let at = u64::from_le_bytes(rest[..8].try_into()?);
+        let (producer, header) = match header_length {
+            RECORD_HEADER_LENGTH => (Some(u16::from_le_bytes([rest[8], rest[9]])), &rest[10..]),
+            _ => (None, &rest[8..]),
+        };
+        let player_id = Some(header[0]).filter(|&id| id != UNKNOWN_PLAYER);
+        let length = u16::from_le_bytes([header[1], header[2]]) as usize;
+        let end = header_length + length;
+        if rest.len() < end {
+            break;
+        }
+        records.push(Record {
+            at: Duration::from_micros(at),
+            producer,
+            player_id,
+            message: rest[header_length..end].to_vec(),
+        });
+        rest = &rest[end..];
+    }
+    if !rest.is_empty() {
+        log::warn!("Ignoring a truncated record at the end of the recording");
+    }
+    Ok(records)
+}
+
+/// Feeds recorded messages to the controllers with their original spacing
+/// divided by `speed`, as if they had arrived on a data channel.
+pub async fn replay(records: &[Record], controllers: &Arc<Mutex<ControllerSlots>>, speed: f64) {
+    let started = tokio::time::Instant::now();
+    for record in records {
+        tokio::time::sleep_until(started + record.at.div_f64(speed)).await;
+        log::debug!(
+            "{:?} producer {:?} player {:?}: {:?}",
+            record.at,
+            record.producer,
+            record.player_id,
+            record.message
+        );
+        let mut message = match protocol::decode(&record.message) {
+            Ok(message) => message,
+            Err(e) => {
+                log::warn!("Invalid message at {:?}: {:?}", record.at, e);
+                continue;
+            }
+        };
+        // replayed on the controller it went to, after any remapping
+        match record.player_id {
+            Some(slot) => message.set_player_id(slot),
+            None => {
+                log::debug!("Skipping message at {:?}, dropped when recorded", record.at);
+                continue;
+            }
+        }
+        let mut controllers = controllers.lock().unwrap();
+        if let Err(e) = session::apply_message(&mut controllers, message) {
+            log::warn!("Error applying message at {:?}: {:?}", record.at, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_round_trip() {
+        let dir = std::env::temp_dir().join(format!("vulcast-recording-{}", std::process::id()));
+        let (a, b): (DataProducerId, DataProducerId) = (
+            serde_json::from_str("\"a\"").unwrap(),
+            serde_json::from_str("\"b\"").unwrap(),
+        );
+        let mut recorder = Recorder::create(&dir).unwrap();
+        recorder.record(&a, &[0x94, 0], Some(2));
+        recorder.record(&b, &[0x94, 0], None);
+        recorder.record(&a, &[1, 2, 3], Some(2));
+        drop(recorder);
+
+        let path = fs::read_dir(&dir).unwrap().next().unwrap().unwrap().path();
+        let records = read(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let fields: Vec<_> = records
+            .iter()
+            .map(|record| (record.producer, record.player_id, record.message.clone()))
+            .collect();
+        assert_eq!(
+            fields,
+            vec![
+                (Some(0), Some(2), vec![0x94, 0]),
+                (Some(1), None, vec![0x94, 0]),
+                (Some(0), Some(2), vec![1, 2, 3]),
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_version_1_recordings() {
+        let dir = std::env::temp_dir().join(format!("vulcast-recording-v1-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("input-0.rec");
+        let mut data = MAGIC_V1.to_vec();
+        data.extend_from_slice(&1500u64.to_le_bytes());
+        data.extend_from_slice(&[3, 2, 0, 0x94, 3]);
+        // torn record
+        data.extend_from_slice(&[0; 5]);
+        fs::write(&path, data).unwrap();
+
+        let records = read(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].at, Duration::from_micros(1500));
+        assert_eq!(records[0].producer, None);
+        assert_eq!(records[0].player_id, Some(3));
+        assert_eq!(records[0].message, vec![0x94, 3]);
+    }
+}
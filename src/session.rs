@@ -0,0 +1,328 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::backend;
+use crate::cmdline::Opts;
+use crate::config::Config;
+use crate::control::{Control, ControlCommand};
+use crate::controllers::ControllerSlots;
+use crate::data_streamer::{self, Pipeline, Supervisor};
+use crate::graphql::signal_query;
+use crate::graphql_signaller::GraphQLSignaller;
+use crate::limits::{InputLimiter, Verdict};
+use crate::media::{self, AlsaDefault, CaptureDevice};
+use crate::protocol::{self, Message};
+use crate::recording::Recorder;
+use crate::tls;
+
+use anyhow::Result;
+use futures::StreamExt;
+use graphql_ws::GraphQLWebSocket;
+use http::Uri;
+use serde::Serialize;
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio_tungstenite::Connector;
+use vulcast_rtc::broadcaster::Broadcaster;
+use vulcast_rtc::types::MediaKind;
+
+#[derive(Serialize)]
+struct SessionToken {
+    token: String,
+}
+
+/// Why a relay session stopped running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEnd {
+    /// The operator asked for the session to end; do not reconnect.
+    Requested,
+    /// The relay connection was lost after being established.
+    Disconnected,
+}
+
+/// Where a session's audio and video come from.
+pub struct MediaSource {
+    /// The capture device, `None` while it is unplugged.
+    pub capture: watch::Receiver<Option<CaptureDevice>>,
+    /// External pipeline sending plain RTP, or `None` to capture with the
+    /// in-process capturers.
+    pub pipeline: Option<Arc<Pipeline>>,
+    /// Selects the ALSA card the in-process capturers record from.
+    pub alsa: Option<AlsaDefault>,
+}
+
+/// Applies a decoded data channel message to the controllers.
+pub fn apply_message(controllers: &mut ControllerSlots, message: Message) -> Result<()> {
+    match message {
+        Message::Input(state) => controllers.set_state(state),
+        Message::SelectProfile { player_id, profile } => {
+            controllers.select_profile(player_id as usize, profile.as_deref())
+        }
+        Message::CalibrateSticks { player_id } => controllers.calibrate_sticks(player_id as usize),
+        Message::SetTurbo {
+            player_id,
+            button,
+            enabled,
+        } => controllers.set_turbo(player_id as usize, button as usize, enabled),
+        Message::PlayMacro { player_id, name } => {
+            controllers.play_macro(player_id as usize, name.as_deref())
+        }
+    }
+}
+
+/// Creates plain transports on the relay, produces the audio and video
+/// `pipeline` sends to them, and starts it capturing from `capture`.
+async fn produce_plain(
+    ws_client: &GraphQLWebSocket,
+    conf: &Config,
+    pipeline: &Arc<Pipeline>,
+    capture: watch::Receiver<Option<CaptureDevice>>,
+) -> Result<Supervisor> {
+    let audio_transport = ws_client
+        .query_unchecked::<signal_query::CreatePlainTransport>(
+            signal_query::create_plain_transport::Variables,
+        )
+        .await
+        .create_plain_transport;
+    let video_transport = ws_client
+        .query_unchecked::<signal_query::CreatePlainTransport>(
+            signal_query::create_plain_transport::Variables,
+        )
+        .await
+        .create_plain_transport;
+
+    let transports = vec![
+        (MediaKind::Audio, &audio_transport),
+        (MediaKind::Video, &video_transport),
+    ];
+    for (kind, transport) in transports {
+        let rtp_parameters = data_streamer::rtp_parameters(pipeline.as_ref(), &kind)?;
+        log::info!("Producing {:?} on plain transport {:?}", kind, transport);
+        let producer = ws_client
+            .query_unchecked::<signal_query::ProducePlain>(signal_query::produce_plain::Variables {
+                transport_id: transport.id.clone(),
+                kind,
+                rtp_parameters,
+            })
+            .await
+            .produce_plain;
+        log::debug!("Plain producer {:?}", producer);
+    }
+
+    let address = |transport: &signal_query::PlainTransportOptions| {
+        SocketAddr::new(transport.tuple.local_ip(), transport.tuple.local_port())
+    };
+    Supervisor::start(
+        pipeline.clone(),
+        capture,
+        address(&audio_transport),
+        address(&video_transport),
+        &conf.media,
+    )
+    .await
+}
+
+/// Logs in, acquires a relay assignment and runs a single broadcasting
+/// session until it ends, sending media from `media` while the capture device
+/// is plugged in. Errors are returned for anything that fails before the
+/// session is established.
+pub async fn run(
+    conf: &Config,
+    opts: &Opts,
+    client: &reqwest::Client,
+    media: &MediaSource,
+    controllers: Option<Arc<Mutex<ControllerSlots>>>,
+    recorder: Option<Arc<Mutex<Recorder>>>,
+    control: &Arc<Control>,
+) -> Result<SessionEnd> {
+    let mut commands = control.commands();
+    let access_token = backend::login(conf, client).await?;
+    let (relay_host, relay_token) = backend::assign_relay(conf, opts, client, &access_token)
+        .await
+        .or_else(|_| backend::read_relay_assignment(opts))?;
+
+    log::info!("Assigned to relay {:?}", relay_host);
+
+    let port = conf.network.signal_port;
+    let relay_uri: Uri = format!("wss://{}:{}", relay_host, port).parse()?;
+
+    log::info!("Connecting to relay at {:?}", relay_uri);
+
+    let stream = TcpStream::connect((relay_host.clone(), port)).await?;
+    let req = http::Request::builder()
+        .uri(relay_uri)
+        .header("Sec-WebSocket-Protocol", "graphql-ws")
+        .body(())?;
+
+    let client_config = tls::relay_client_config(conf, opts, &relay_host)?;
+    let (socket, _response) = tokio_tungstenite::client_async_tls_with_config(
+        req,
+        stream,
+        None,
+        Some(Connector::Rustls(Arc::new(client_config))),
+    )
+    .await?;
+
+    log::info!("Strarting graphql client");
+    let ws_client = GraphQLWebSocket::new(
+        socket,
+        Some(serde_json::to_value(SessionToken { token: relay_token })?),
+    );
+
+    let signaller = Arc::new(GraphQLSignaller::new(ws_client.clone()));
+    let broadcaster = Broadcaster::new(signaller.clone()).await;
+
+    let data_producer_available = ws_client.subscribe::<signal_query::DataProducerAvailable>(
+        signal_query::data_producer_available::Variables,
+    );
+    let mut data_producer_available_stream = data_producer_available.execute();
+
+    // the supervisor follows the capture device itself; the in-process
+    // capturers are produced here while it is plugged in
+    let mut capture = media.capture.clone();
+    let mut capturers = None;
+    let _pipeline = match &media.pipeline {
+        Some(pipeline) => {
+            let pipeline = produce_plain(&ws_client, conf, pipeline, capture.clone()).await?;
+            control.set_pipeline(pipeline.status());
+            Some(pipeline)
+        }
+        None => None,
+    };
+    control.set_connected(&relay_host);
+    let mut shutdown = signaller.shutdown();
+    loop {
+        if media.pipeline.is_none() {
+            let device = capture.borrow_and_update().clone();
+            let producing = capturers.as_ref().map(|(producing, _, _)| producing);
+            if producing != device.as_ref() {
+                // dropping the producers closes them on the relay
+                if let Some((previous, _, _)) = capturers.take() {
+                    log::info!("Stopped producing audio and video from {}", previous);
+                }
+                match device {
+                    Some(device) => match media::capturer_index(&device.video_device) {
+                        Some(index) => {
+                            if let Some(alsa) = &media.alsa {
+                                if let Err(e) = alsa.select(device.audio_card.as_deref()) {
+                                    log::warn!("{:?}, audio comes from the default card", e);
+                                }
+                            }
+                            let vcm_capturer = broadcaster
+                                .produce_video_from_vcm_capturer(
+                                    Some(index),
+                                    conf.media.video_width,
+                                    conf.media.video_height,
+                                    conf.media.video_framerate,
+                                )
+                                .await;
+                            let alsa_capturer = broadcaster.produce_audio_from_default_alsa().await;
+                            log::info!("Producing audio and video from {}", device);
+                            capturers = Some((device, vcm_capturer, alsa_capturer));
+                        }
+                        None => log::warn!("Capture device {} is gone already", device),
+                    },
+                    None => log::warn!(
+                        "No capture device, pausing audio and video until one is plugged in"
+                    ),
+                }
+            }
+        }
+        tokio::select! {
+            Ok(()) = capture.changed(), if media.pipeline.is_none() => (),
+            Some(Ok(response)) = data_producer_available_stream.next() => {
+                let data_producer_id = match response.data {
+                    Some(data) => data.data_producer_available,
+                    None => {
+                        log::warn!("Data producer announcement without data: {:?}", response.errors);
+                        continue;
+                    }
+                };
+                log::trace!("data producer available: {:?}", &data_producer_id);
+                let mut data_consumer = match broadcaster.consume_data(data_producer_id.clone()).await {
+                    Ok(data_consumer) => data_consumer,
+                    Err(e) => {
+                        log::warn!("Could not consume data producer {:?}: {:?}", data_producer_id, e);
+                        continue;
+                    }
+                };
+                control.add_data_producer(&data_producer_id);
+                let cont_mutex = controllers.clone();
+                let recorder = recorder.clone();
+                let limits = conf.limits.clone();
+                let control = control.clone();
+                tokio::spawn(async move {
+                    let mut limiter = InputLimiter::new(&limits, Instant::now());
+                    while let Some(message) = data_consumer.next().await {
+                        log::trace!("{:?}", message);
+                        let now = Instant::now();
+
+                        let decoded = protocol::decode(&message);
+                        // the controller the message went to, for the recording
+                        let mut applied_to = None;
+                        let verdict = match (limiter.admit(now), decoded, &cont_mutex) {
+                            (Verdict::Accept, Ok(mut decoded), Some(cont_mutex)) => {
+                                let mut conts = cont_mutex.lock().unwrap();
+                                // only input for a slot the producer may not have
+                                // counts against it; the rest are our problem
+                                match conts.claim(&data_producer_id, decoded.player_id()) {
+                                    Ok(slot) => {
+                                        applied_to = Some(slot);
+                                        decoded.set_player_id(slot as u8);
+                                        if let Err(e) = apply_message(&mut conts, decoded) {
+                                            log::warn!("Couldn't apply message from {:?}: {:?}", data_producer_id, e);
+                                            limiter.failed();
+                                        }
+                                        Verdict::Accept
+                                    }
+                                    Err(e) => {
+                                        log::debug!("Message from {:?} rejected: {:?}", data_producer_id, e);
+                                        limiter.rejected(now)
+                                    }
+                                }
+                            }
+                            (Verdict::Accept, Ok(_), None) => Verdict::Accept,
+                            (Verdict::Accept, Err(e), _) => {
+                                log::debug!("Invalid message from {:?}: {}", data_producer_id, e);
+                                limiter.malformed(&e, now)
+                            }
+                            (verdict, _, _) => verdict,
+                        };
+                        if let Some(recorder) = &recorder {
+                            recorder.lock().unwrap().record(&data_producer_id, &message, applied_to);
+                        }
+                        control.set_producer_stats(&data_producer_id, limiter.stats());
+                        if verdict == Verdict::Disconnect {
+                            log::warn!(
+                                "Disconnecting data producer {:?} after too many bad messages: {:?}",
+                                data_producer_id,
+                                limiter.stats()
+                            );
+                            break;
+                        }
+                    }
+                    log::debug!("data producer {:?} is gone", data_producer_id);
+                    control.remove_data_producer(&data_producer_id);
+                    if let Some(cont_mutex) = &cont_mutex {
+                        if let Err(e) = cont_mutex.lock().unwrap().disown(&data_producer_id) {
+                            log::warn!("Error releasing controllers of {:?}: {:?}", data_producer_id, e);
+                        }
+                    }
+                });
+            },
+            Ok(command) = commands.recv() => match command {
+                ControlCommand::EndSession => return Ok(SessionEnd::Requested),
+                ControlCommand::Reconnect => {
+                    log::info!("Reconnect requested, leaving relay {:?}", relay_host);
+                    return Ok(SessionEnd::Disconnected);
+                }
+            },
+            _ = shutdown.recv() => {break},
+            else => {break}
+        }
+    }
+
+    log::warn!("Lost connection to relay {:?}", relay_host);
+    Ok(SessionEnd::Disconnected)
+}
@@ -0,0 +1,347 @@
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::cmdline::Opts;
+use crate::config::Config;
+
+use anyhow::{anyhow, Context, Result};
+use rustls::client::WantsClientCert;
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{
+    Certificate, ClientConfig, ConfigBuilder, OwnedTrustAnchor, PrivateKey, RootCertStore,
+    ServerName,
+};
+use rustls_pemfile::Item;
+use sha2::{Digest, Sha256};
+
+/// Accepts any certificate the relay presents. Only used with
+/// `--insecure-relay-tls`.
+struct PromiscuousServerVerifier;
+
+impl ServerCertVerifier for PromiscuousServerVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        // here be dragons
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Verifies the chain against the trusted roots, then additionally requires
+/// the end-entity public key to match one of the configured SPKI pins.
+struct PinnedServerVerifier {
+    inner: WebPkiVerifier,
+    pins: Vec<[u8; 32]>,
+}
+
+impl ServerCertVerifier for PinnedServerVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+        let spki_hash = spki_sha256(end_entity)
+            .map_err(|e| rustls::Error::InvalidCertificateData(e.to_string()))?;
+        if !self.pins.contains(&spki_hash) {
+            return Err(rustls::Error::General(format!(
+                "Relay public key sha256/{} does not match any configured pin",
+                base64::encode(spki_hash)
+            )));
+        }
+        Ok(verified)
+    }
+}
+
+fn spki_sha256(cert: &Certificate) -> Result<[u8; 32]> {
+    let (_, parsed) = x509_parser::parse_x509_certificate(&cert.0)
+        .map_err(|e| anyhow!("Could not parse certificate: {}", e))?;
+    Ok(Sha256::digest(parsed.public_key().raw).into())
+}
+
+/// Builds the root store from the bundled webpki roots, plus any
+/// certificates in `[network] relay_ca_file`.
+fn root_store(conf: &Config) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+
+    if let Some(ca_file) = &conf.network.relay_ca_file {
+        log::info!("Loading relay CA bundle from {}", ca_file);
+        let mut reader = BufReader::new(
+            File::open(ca_file).with_context(|| format!("Couldn't open CA bundle {}", ca_file))?,
+        );
+        let certs = rustls_pemfile::certs(&mut reader)
+            .with_context(|| format!("Couldn't read CA bundle {}", ca_file))?;
+        if certs.is_empty() {
+            return Err(anyhow!("CA bundle {} contains no certificates", ca_file));
+        }
+        for cert in certs {
+            roots.add(&Certificate(cert))?;
+        }
+    }
+    Ok(roots)
+}
+
+/// Parses the SPKI pins configured for `host` in the `[relay_pins]` section,
+/// given as a comma-separated list of base64 SHA-256 digests, optionally
+/// prefixed with `sha256/`.
+pub fn parse_pins(host: &str, pins: &str) -> Result<Vec<[u8; 32]>> {
+    pins.split(',')
+        .map(str::trim)
+        .filter(|pin| !pin.is_empty())
+        .map(|pin| {
+            let digest = base64::decode(pin.trim_start_matches("sha256/"))
+                .with_context(|| format!("Invalid pin for {}: {}", host, pin))?;
+            digest
+                .as_slice()
+                .try_into()
+                .map_err(|_| anyhow!("Pin for {} is not a SHA-256 digest: {}", host, pin))
+        })
+        .collect()
+}
+
+/// Device certificate chain and private key presented to the backend and
+/// relay, loaded from `[auth] client_cert` and `[auth] client_key`.
+pub struct ClientIdentity {
+    certs: Vec<Certificate>,
+    key: PrivateKey,
+    pem: Vec<u8>,
+}
+
+impl ClientIdentity {
+    /// Concatenated certificate chain and key, as expected by
+    /// `reqwest::Identity::from_pem`.
+    pub fn pem(&self) -> &[u8] {
+        &self.pem
+    }
+}
+
+/// Reads the private key in `key_file`, returning it with the file's
+/// contents. reqwest's rustls backend only takes PKCS#8 and RSA keys, so
+/// SEC1 EC keys are refused here rather than when the client is built.
+pub fn read_client_key(key_file: &str) -> Result<(PrivateKey, Vec<u8>)> {
+    let key_pem =
+        fs::read(key_file).with_context(|| format!("Couldn't read client key {}", key_file))?;
+    for item in rustls_pemfile::read_all(&mut key_pem.as_slice())
+        .with_context(|| format!("Couldn't parse client key {}", key_file))?
+    {
+        match item {
+            Item::PKCS8Key(der) | Item::RSAKey(der) => return Ok((PrivateKey(der), key_pem)),
+            Item::ECKey(_) => {
+                return Err(anyhow!(
+                    "{} is a SEC1 EC key, which is not supported; convert it to PKCS#8 with \
+                     `openssl pkcs8 -topk8 -nocrypt -in {} -out <new key>`",
+                    key_file,
+                    key_file
+                ))
+            }
+            _ => (),
+        }
+    }
+    Err(anyhow!("{} contains no private key", key_file))
+}
+
+/// Loads the device identity, if one is configured.
+pub fn client_identity(conf: &Config) -> Result<Option<ClientIdentity>> {
+    let (cert_file, key_file) = match (&conf.auth.client_cert, &conf.auth.client_key) {
+        (Some(cert_file), Some(key_file)) => (cert_file, key_file),
+        (None, None) => return Ok(None),
+        _ => {
+            return Err(anyhow!(
+                "Both client_cert and client_key must be set to use a device certificate"
+            ))
+        }
+    };
+
+    let cert_pem = fs::read(cert_file)
+        .with_context(|| format!("Couldn't read client certificate {}", cert_file))?;
+
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut cert_pem.as_slice())
+        .with_context(|| format!("Couldn't parse client certificate {}", cert_file))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(anyhow!("{} contains no certificates", cert_file));
+    }
+
+    let (key, key_pem) = read_client_key(key_file)?;
+
+    let mut pem = cert_pem;
+    pem.push(b'\n');
+    pem.extend_from_slice(&key_pem);
+
+    Ok(Some(ClientIdentity { certs, key, pem }))
+}
+
+fn with_client_auth(
+    builder: ConfigBuilder<ClientConfig, WantsClientCert>,
+    identity: Option<&ClientIdentity>,
+) -> Result<ClientConfig> {
+    match identity {
+        Some(identity) => {
+            Ok(builder.with_single_cert(identity.certs.clone(), identity.key.clone())?)
+        }
+        None => Ok(builder.with_no_client_auth()),
+    }
+}
+
+/// Creates the TLS configuration used to connect to the relay at `host`,
+/// presenting the device certificate if one is configured.
+pub fn relay_client_config(conf: &Config, opts: &Opts, host: &str) -> Result<ClientConfig> {
+    let builder = ClientConfig::builder().with_safe_defaults();
+    let identity = client_identity(conf)?;
+
+    if opts.insecure_relay_tls {
+        log::warn!("!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!");
+        log::warn!("Relay certificate verification is DISABLED (--insecure-relay-tls).");
+        log::warn!("Anyone on the network path can impersonate the relay.");
+        log::warn!("!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!");
+        return with_client_auth(
+            builder.with_custom_certificate_verifier(Arc::new(PromiscuousServerVerifier)),
+            identity.as_ref(),
+        );
+    }
+
+    let roots = root_store(conf)?;
+    let pins = match conf.relay_pins.get(host) {
+        Some(pins) => parse_pins(host, pins)?,
+        None => Vec::new(),
+    };
+    if pins.is_empty() {
+        return with_client_auth(
+            builder.with_custom_certificate_verifier(Arc::new(WebPkiVerifier::new(roots, None))),
+            identity.as_ref(),
+        );
+    }
+
+    log::info!("Pinning relay {} to {} public key(s)", host, pins.len());
+    with_client_auth(
+        builder.with_custom_certificate_verifier(Arc::new(PinnedServerVerifier {
+            inner: WebPkiVerifier::new(roots, None),
+            pins,
+        })),
+        identity.as_ref(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::convert::TryFrom;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    /// SPKI pin of `testdata/tls/relay.pem`, which `ca.pem` signed for
+    /// `relay.test`.
+    const RELAY_PIN: &str = "X8dcMivxX/+d9fM3Ww7n2VFQVYM7TurWAlJb4IfvKHk=";
+
+    fn fixture(name: &str) -> String {
+        format!("{}/testdata/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn certificate(name: &str) -> Certificate {
+        let pem = fs::read(fixture(name)).unwrap();
+        Certificate(
+            rustls_pemfile::certs(&mut pem.as_slice())
+                .unwrap()
+                .remove(0),
+        )
+    }
+
+    fn verify(pins: &str) -> Result<ServerCertVerified, rustls::Error> {
+        let mut roots = RootCertStore::empty();
+        roots.add(&certificate("ca.pem")).unwrap();
+        let verifier = PinnedServerVerifier {
+            inner: WebPkiVerifier::new(roots, None),
+            pins: parse_pins("relay.test", pins).unwrap(),
+        };
+        // within the fixtures' validity
+        let now = UNIX_EPOCH + Duration::from_secs(1_798_761_600);
+        verifier.verify_server_cert(
+            &certificate("relay.pem"),
+            &[],
+            &ServerName::try_from("relay.test").unwrap(),
+            &mut std::iter::empty(),
+            &[],
+            now,
+        )
+    }
+
+    #[test]
+    fn pins_with_and_without_prefix() {
+        let pins = parse_pins(
+            "relay.test",
+            &format!(" sha256/{}, ,{} ", RELAY_PIN, RELAY_PIN),
+        )
+        .unwrap();
+        assert_eq!(pins.len(), 2);
+        assert_eq!(pins[0], pins[1]);
+        assert_eq!(pins[0].to_vec(), base64::decode(RELAY_PIN).unwrap());
+    }
+
+    #[test]
+    fn bad_pins() {
+        let bad_base64 = parse_pins("relay.test", "sha256/not*base64").unwrap_err();
+        assert!(bad_base64
+            .to_string()
+            .starts_with("Invalid pin for relay.test"));
+        // an MD5-sized digest
+        let short = parse_pins("relay.test", "sha256/AAECAwQFBgcICQoLDA0ODw==").unwrap_err();
+        assert!(short.to_string().contains("is not a SHA-256 digest"));
+    }
+
+    #[test]
+    fn client_keys() {
+        assert!(read_client_key(&fixture("relay.key")).is_ok());
+        let sec1 = read_client_key(&fixture("relay-sec1.key")).unwrap_err();
+        assert!(sec1.to_string().contains("is a SEC1 EC key"));
+        let no_key = read_client_key(&fixture("ca.pem")).unwrap_err();
+        assert!(no_key.to_string().contains("contains no private key"));
+    }
+
+    #[test]
+    fn spki_digest_matches_pin() {
+        let digest = spki_sha256(&certificate("relay.pem")).unwrap();
+        assert_eq!(base64::encode(digest), RELAY_PIN);
+    }
+
+    #[test]
+    fn pinned_relay_key() {
+        assert!(verify(RELAY_PIN).is_ok());
+        let other = base64::encode([7u8; 32]);
+        assert!(verify(&format!("{}, sha256/{}", other, RELAY_PIN)).is_ok());
+        match verify(&other) {
+            Err(rustls::Error::General(message)) => {
+                assert!(message.contains(RELAY_PIN), "{}", message)
+            }
+            other => panic!("expected a pin mismatch, got {:?}", other.map(|_| ())),
+        }
+    }
+}